@@ -0,0 +1,501 @@
+//! Convert BF IR back to plain BF source code.
+//!
+//! This lets us use our peephole optimisations as a BF-to-BF
+//! minifier: the output runs on any BF implementation, not just
+//! bfc.
+
+use std::num::Wrapping;
+
+use itertools::Itertools;
+
+use crate::bfir::AstNode;
+use crate::bfir::Cell;
+use crate::peephole::KnownCells;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+#[cfg(test)]
+use quickcheck::{quickcheck, TestResult};
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[cfg(test)]
+use crate::bfir::{parse, Position};
+#[cfg(test)]
+use crate::peephole::{bf_passes, PassManager};
+
+/// Wrap the generated source at this many characters per line.
+const LINE_WIDTH: usize = 80;
+
+/// Return BF source code that is behaviourally equivalent to
+/// `instrs`, which must only have been optimised with `bf_passes`.
+pub fn bf_from_instructions(instrs: &[AstNode]) -> String {
+    let mut src = String::new();
+    // Cells in BF are initialised to zero.
+    let mut known = KnownCells::zeroed();
+    // Any pointer movement after the last instruction has no effect,
+    // so we don't emit it.
+    add_instrs_to_bf(instrs, &mut src, 0, &mut known, false);
+
+    let chars: Vec<char> = src.chars().collect();
+    chars
+        .chunks(LINE_WIDTH)
+        .map(|line| line.iter().collect::<String>() + "\n")
+        .collect()
+}
+
+/// Move the tape head from offset `from` to offset `to`.
+fn add_ptr_movement(src: &mut String, from: isize, to: isize) {
+    let c = if to > from { '>' } else { '<' };
+    for _ in 0..(to - from).abs() {
+        src.push(c);
+    }
+}
+
+/// Add `amount` to the cell under the tape head, taking the shortest
+/// route under wrapping arithmetic.
+fn add_cell_change(src: &mut String, amount: Cell) {
+    let c = if amount.0 > 0 { '+' } else { '-' };
+    for _ in 0..(amount.0 as i16).abs() {
+        src.push(c);
+    }
+}
+
+/// Append a loop that tests the cell at `offset`. The loop body uses
+/// the same cell pointer as the code around it, so the tape head
+/// finishes on `offset`.
+fn add_loop_to_bf(
+    body: &[AstNode],
+    offset: isize,
+    src: &mut String,
+    head: isize,
+    known: &mut KnownCells,
+) {
+    add_ptr_movement(src, head, offset);
+    src.push('[');
+    let body_head = add_instrs_to_bf(body, src, offset, &mut KnownCells::unknown(), true);
+    // Every iteration must start on the same cell.
+    add_ptr_movement(src, body_head, offset);
+    src.push(']');
+
+    // The loop body may have modified any cell, but we know the
+    // loop only terminates on a zero cell.
+    *known = KnownCells::unknown();
+    known.set(offset, Some(Wrapping(0)));
+}
+
+/// Append the BF for `instrs` to `src`. Rather than moving the tape
+/// head back and forth for every offset, we only move it when an
+/// instruction needs it. `head` is the offset of the tape head from
+/// the IR's cell pointer, and we return its final value.
+///
+/// If `head_at_end` is false, we're at the end of the program so
+/// the tape head may finish anywhere.
+fn add_instrs_to_bf(
+    instrs: &[AstNode],
+    src: &mut String,
+    mut head: isize,
+    known: &mut KnownCells,
+    head_at_end: bool,
+) -> isize {
+    let mut index = 0;
+    while index < instrs.len() {
+        // Increments and sets on different cells can be applied in
+        // any order, so find the longest run of them.
+        let run_length = instrs[index..]
+            .iter()
+            .take_while(|instr| {
+                matches!(
+                    instr,
+                    AstNode::Increment { .. }
+                        | AstNode::Set { .. }
+                        | AstNode::PointerIncrement { .. }
+                )
+            })
+            .count();
+        if run_length > 0 {
+            let run = &instrs[index..index + run_length];
+            index += run_length;
+
+            let at_end = index == instrs.len() && !head_at_end;
+            head = add_cell_changes_to_bf(run, src, head, known, at_end);
+            continue;
+        }
+
+        match instrs[index] {
+            AstNode::Read { offset, .. } => {
+                add_ptr_movement(src, head, offset);
+                head = offset;
+                src.push(',');
+                known.set(offset, None);
+            }
+            AstNode::Write { offset, .. } => {
+                add_ptr_movement(src, head, offset);
+                head = offset;
+                src.push('.');
+            }
+            AstNode::Loop {
+                ref body, offset, ..
+            } => {
+                add_loop_to_bf(body, offset, src, head, known);
+                head = offset;
+            }
+            // An if is just a loop whose body always finishes on a
+            // zero cell.
+            AstNode::If { ref body, .. } => {
+                add_loop_to_bf(body, 0, src, head, known);
+                head = 0;
+            }
+            AstNode::MultiplyMove { ref changes, .. } => {
+                add_ptr_movement(src, head, 0);
+                head = 0;
+
+                let mut targets: Vec<_> = changes.keys().cloned().collect();
+                targets.sort_unstable();
+
+                src.push_str("[-");
+                for target in targets {
+                    let factor = changes[&target];
+                    if factor != Wrapping(0) {
+                        add_ptr_movement(src, head, target);
+                        head = target;
+                        add_cell_change(src, factor);
+                        known.set(target, None);
+                    }
+                }
+                add_ptr_movement(src, head, 0);
+                head = 0;
+                src.push(']');
+                known.set(0, Some(Wrapping(0)));
+            }
+            // We'd need a spare cell to implement these in BF, so
+            // `bf_passes` never produces them.
+            AstNode::MultiplyAdd { .. } | AstNode::WriteConst { .. } => unreachable!(),
+            AstNode::Scan { stride, .. } => {
+                add_ptr_movement(src, head, 0);
+                src.push('[');
+                add_ptr_movement(src, 0, stride);
+                src.push(']');
+                head = 0;
+
+                // We don't know where we've moved to, except that
+                // the current cell is zero.
+                *known = KnownCells::unknown();
+                known.set(0, Some(Wrapping(0)));
+            }
+            AstNode::Increment { .. } | AstNode::Set { .. } | AstNode::PointerIncrement { .. } => {
+                unreachable!()
+            }
+        }
+        index += 1;
+    }
+    head
+}
+
+/// Append the BF for a sequence of increments, sets and pointer
+/// increments. We visit the cells in whichever direction requires
+/// the least tape head movement, finishing on the cell pointer
+/// (unless `at_end` is true). Returns the final tape head offset.
+fn add_cell_changes_to_bf(
+    run: &[AstNode],
+    src: &mut String,
+    head: isize,
+    known: &mut KnownCells,
+    at_end: bool,
+) -> isize {
+    // Group the changes by cell, relative to the cell pointer at the
+    // start of the run. Changes to the same cell stay in order.
+    let mut changes: Vec<(isize, &AstNode)> = vec![];
+    let mut ptr = 0;
+    for instr in run {
+        match *instr {
+            AstNode::Increment { offset, .. } | AstNode::Set { offset, .. } => {
+                changes.push((ptr + offset, instr));
+            }
+            AstNode::PointerIncrement { amount, .. } => {
+                ptr += amount;
+            }
+            _ => unreachable!(),
+        }
+    }
+    changes.sort_by_key(|&(offset, _)| offset);
+
+    if let (Some(&(lowest, _)), Some(&(highest, _))) = (changes.first(), changes.last()) {
+        let finish_cost = |last: isize| if at_end { 0 } else { (last - ptr).abs() };
+        let ascending_cost = (head - lowest).abs() + finish_cost(highest);
+        let descending_cost = (head - highest).abs() + finish_cost(lowest);
+        if descending_cost < ascending_cost {
+            // Reverse the cell order, but keep changes to each cell
+            // in their original order.
+            changes.reverse();
+            changes = changes
+                .into_iter()
+                .group_by(|&(offset, _)| offset)
+                .into_iter()
+                .flat_map(|(_, group)| {
+                    let mut group: Vec<_> = group.collect();
+                    group.reverse();
+                    group
+                })
+                .collect();
+        }
+    }
+
+    let mut head = head;
+    for (offset, instr) in changes {
+        add_ptr_movement(src, head, offset);
+        head = offset;
+        match *instr {
+            AstNode::Increment { amount, .. } => {
+                add_cell_change(src, amount);
+                let value = known.get(offset).map(|value| value + amount);
+                known.set(offset, value);
+            }
+            AstNode::Set { amount, .. } => {
+                match known.get(offset) {
+                    // Increment from the current value if that's
+                    // shorter than zeroing the cell first.
+                    Some(value)
+                        if ((amount - value).0 as i16).abs() <= (amount.0 as i16).abs() + 3 =>
+                    {
+                        add_cell_change(src, amount - value);
+                    }
+                    _ => {
+                        src.push_str("[-]");
+                        add_cell_change(src, amount);
+                    }
+                }
+                known.set(offset, Some(amount));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // The tape head stays where it is, so make it relative to the
+    // new cell pointer.
+    known.shift(ptr);
+    head - ptr
+}
+
+#[test]
+fn emit_unoptimised_program() {
+    let instrs = parse("+>-<,[.]").unwrap();
+    assert_eq!(bf_from_instructions(&instrs), "+>-<,[.]\n");
+}
+
+#[test]
+fn emit_ignores_trailing_movement() {
+    let instrs = parse("+>>>").unwrap();
+    assert_eq!(bf_from_instructions(&instrs), "+\n");
+}
+
+#[test]
+fn emit_set() {
+    let instrs = [
+        AstNode::Read {
+            offset: 0,
+            position: None,
+        },
+        AstNode::Set {
+            amount: Wrapping(-3),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(bf_from_instructions(&instrs), ",[-]---\n");
+}
+
+#[test]
+fn emit_set_known_value() {
+    // Cells start at zero, so we don't need to zero them.
+    let instrs = [AstNode::Set {
+        amount: Wrapping(2),
+        offset: 1,
+        position: None,
+    }];
+    assert_eq!(bf_from_instructions(&instrs), ">++\n");
+
+    // Cells are zero after a loop.
+    let instrs = parse(",[>]+").unwrap();
+    let mut optimised = instrs.clone();
+    optimised[2] = AstNode::Set {
+        amount: Wrapping(1),
+        offset: 0,
+        position: None,
+    };
+    assert_eq!(bf_from_instructions(&optimised), ",[>]+\n");
+}
+
+#[test]
+fn emit_offsets() {
+    let instrs = [
+        AstNode::Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: None,
+        },
+        AstNode::Increment {
+            amount: Wrapping(2),
+            offset: 2,
+            position: None,
+        },
+        AstNode::PointerIncrement {
+            amount: 3,
+            position: None,
+        },
+        AstNode::Write {
+            offset: 0,
+            position: None,
+        },
+    ];
+    assert_eq!(bf_from_instructions(&instrs), ">+>++>.\n");
+}
+
+#[test]
+fn emit_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(2, Wrapping(3));
+    changes.insert(-1, Wrapping(-1));
+    let instrs = [AstNode::MultiplyMove {
+        changes,
+        position: None,
+    }];
+    assert_eq!(bf_from_instructions(&instrs), "[-<->>>+++<<]\n");
+}
+
+#[test]
+fn emit_loop_with_offset() {
+    let instrs = [AstNode::Loop {
+        body: vec![
+            AstNode::Increment {
+                amount: Wrapping(-1),
+                offset: 2,
+                position: None,
+            },
+            AstNode::Increment {
+                amount: Wrapping(1),
+                offset: 3,
+                position: None,
+            },
+        ],
+        offset: 2,
+        position: None,
+    }];
+    assert_eq!(bf_from_instructions(&instrs), ">>[->+<]\n");
+}
+
+#[test]
+fn emit_write_with_offset() {
+    let instrs = [
+        AstNode::Write {
+            offset: 2,
+            position: None,
+        },
+        AstNode::Write {
+            offset: 1,
+            position: None,
+        },
+    ];
+    assert_eq!(bf_from_instructions(&instrs), ">>.<.\n");
+}
+
+#[test]
+fn emit_loop_balances_body() {
+    let instrs = parse("[>]").unwrap();
+    assert_eq!(bf_from_instructions(&instrs), "[>]\n");
+
+    let instrs = [AstNode::Loop {
+        body: vec![AstNode::Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: None,
+        }],
+        offset: 0,
+        position: None,
+    }];
+    assert_eq!(bf_from_instructions(&instrs), "[>+<]\n");
+}
+
+#[test]
+fn emit_wraps_long_lines() {
+    let src = "+".repeat(100) + ".";
+    let instrs = parse(&src).unwrap();
+    let emitted = bf_from_instructions(&instrs);
+    assert_eq!(emitted.lines().count(), 2);
+    assert_eq!(parse(&emitted).unwrap().len(), 101);
+}
+
+#[test]
+fn emit_never_grows_sample_programs() {
+    fn bf_len(src: &str) -> usize {
+        src.chars().filter(|c| "+-<>,.[]".contains(*c)).count()
+    }
+
+    for name in &[
+        "bangbang",
+        "bottles",
+        "factor",
+        "hello_world",
+        "life",
+        "mandelbrot",
+    ] {
+        let path = format!("sample_programs/{}.bf", name);
+        let src = std::fs::read_to_string(&path).unwrap();
+        let instrs = parse(&src).unwrap();
+        let (optimised, _) = PassManager::new(&Some(bf_passes())).run_with_report(instrs);
+
+        let emitted = bf_from_instructions(&optimised);
+        assert!(
+            bf_len(&emitted) <= bf_len(&src),
+            "{} grew from {} to {} commands",
+            path,
+            bf_len(&src),
+            bf_len(&emitted)
+        );
+    }
+}
+
+#[test]
+fn quickcheck_emitted_bf_is_equivalent() {
+    use crate::execution::Outcome::*;
+    use crate::execution::{execute_with_state, ExecutionState};
+
+    fn contains_non_bf(instrs: &[AstNode]) -> bool {
+        instrs.iter().any(|instr| match *instr {
+            AstNode::MultiplyAdd { .. } | AstNode::WriteConst { .. } => true,
+            AstNode::Loop { ref body, .. } | AstNode::If { ref body, .. } => contains_non_bf(body),
+            _ => false,
+        })
+    }
+
+    fn is_equivalent(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
+        // We never generate BF from programs with MultiplyAdd or
+        // WriteConst.
+        if contains_non_bf(&instrs) {
+            return TestResult::discard();
+        }
+
+        let mut state = ExecutionState::initial(&instrs[..]);
+        let outcome = execute_with_state(&instrs[..], &mut state, 1000, read_value);
+        match outcome {
+            RuntimeError(_) | OutOfSteps | InfiniteLoop(_) => return TestResult::discard(),
+            _ => (),
+        }
+
+        let emitted = parse(&bf_from_instructions(&instrs)).unwrap();
+        let mut emitted_state = ExecutionState::initial(&instrs[..]);
+        // Sets and multiplies become loops, so we allow extra steps.
+        let emitted_outcome =
+            execute_with_state(&emitted[..], &mut emitted_state, 1_000_000, read_value);
+
+        match (outcome, emitted_outcome) {
+            (Completed(_), Completed(_)) | (ReachedRuntimeValue, ReachedRuntimeValue) => {}
+            _ => return TestResult::failed(),
+        }
+
+        TestResult::from_bool(
+            state.outputs == emitted_state.outputs && state.cells == emitted_state.cells,
+        )
+    }
+    quickcheck(is_equivalent as fn(Vec<AstNode>, Option<i8>) -> TestResult);
+}
@@ -5,8 +5,10 @@
 //! It also provides functions for generating ASTs from source code,
 //! producing good error messages on malformed inputs.
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
 
 #[cfg(test)]
@@ -20,7 +22,7 @@ use self::AstNode::*;
 pub type Cell = Wrapping<i8>;
 
 /// An inclusive range used for tracking positions in source code.
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Hash)]
 pub struct Position {
     pub start: usize,
     pub end: usize,
@@ -79,13 +81,19 @@ pub enum AstNode {
         position: Option<Position>,
     },
     Read {
+        offset: isize,
         position: Option<Position>,
     },
     Write {
+        offset: isize,
         position: Option<Position>,
     },
+    /// Execute `body` while the cell at `offset` is non-zero. When
+    /// `offset` is non-zero, the body has no net pointer movement and
+    /// its instructions are relative to the same cell as the loop.
     Loop {
         body: Vec<AstNode>,
+        offset: isize,
         position: Option<Position>,
     },
     // These instruction have no direct equivalent in BF, but we
@@ -99,6 +107,70 @@ pub enum AstNode {
         changes: HashMap<isize, Cell>,
         position: Option<Position>,
     },
+    /// Add multiples of products of the current cell with other
+    /// cells. Each key is (target offset, operand offset), so
+    /// `{(2, 1): 3}` adds 3 * current cell * cell #1 to cell
+    /// #2. Unlike MultiplyMove, the current cell is unchanged.
+    MultiplyAdd {
+        changes: HashMap<(isize, isize), Cell>,
+        position: Option<Position>,
+    },
+    /// Move the pointer by `stride` until we reach a zero cell,
+    /// e.g. `[>]` or `[<<]`.
+    Scan {
+        stride: isize,
+        position: Option<Position>,
+    },
+    /// Execute `body` once if the current cell is non-zero. The
+    /// body always finishes on a zero cell, so this is equivalent
+    /// to a loop that runs at most once.
+    If {
+        body: Vec<AstNode>,
+        position: Option<Position>,
+    },
+    /// Write bytes that we know at compile time.
+    WriteConst {
+        bytes: Vec<u8>,
+        position: Option<Position>,
+    },
+}
+
+/// Hash the entries of `map` in any order, as `HashMap` doesn't
+/// implement `Hash`.
+fn hash_changes<K: Hash, H: Hasher>(map: &HashMap<K, Cell>, state: &mut H) {
+    let combined = map
+        .iter()
+        .map(|entry| {
+            let mut hasher = DefaultHasher::new();
+            entry.hash(&mut hasher);
+            hasher.finish()
+        })
+        .fold(0u64, u64::wrapping_add);
+    state.write_u64(combined);
+}
+
+impl Hash for AstNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        get_position(self).hash(state);
+        match self {
+            Increment { amount, offset, .. } | Set { amount, offset, .. } => {
+                amount.hash(state);
+                offset.hash(state);
+            }
+            PointerIncrement { amount, .. } => amount.hash(state),
+            Read { offset, .. } | Write { offset, .. } => offset.hash(state),
+            Loop { body, offset, .. } => {
+                body.hash(state);
+                offset.hash(state);
+            }
+            MultiplyMove { changes, .. } => hash_changes(changes, state),
+            MultiplyAdd { changes, .. } => hash_changes(changes, state),
+            Scan { stride, .. } => stride.hash(state),
+            If { body, .. } => body.hash(state),
+            WriteConst { bytes, .. } => bytes.hash(state),
+        }
+    }
 }
 
 fn fmt_with_indent(instr: &AstNode, indent: i32, f: &mut fmt::Formatter) {
@@ -109,16 +181,79 @@ fn fmt_with_indent(instr: &AstNode, indent: i32, f: &mut fmt::Formatter) {
     match instr {
         &Loop {
             body: ref loop_body,
+            offset,
             position,
-            ..
         } => {
-            let _ = write!(f, "Loop position: {:?}", position);
+            if offset == 0 {
+                let _ = write!(f, "Loop position: {:?}", position);
+            } else {
+                let _ = write!(f, "Loop offset: {} position: {:?}", offset, position);
+            }
 
             for loop_instr in loop_body {
                 let _ = writeln!(f);
                 fmt_with_indent(loop_instr, indent + 1, f);
             }
         }
+        &If { ref body, position } => {
+            let _ = write!(f, "If position: {:?}", position);
+
+            for body_instr in body {
+                let _ = writeln!(f);
+                fmt_with_indent(body_instr, indent + 1, f);
+            }
+        }
+        &WriteConst {
+            ref bytes,
+            position,
+        } => {
+            let text: String = bytes
+                .iter()
+                .flat_map(|b| b.escape_ascii())
+                .map(char::from)
+                .collect();
+            let _ = write!(
+                f,
+                "WriteConst {{ bytes: \"{}\", position: {:?} }}",
+                text, position
+            );
+        }
+        // Sort the changes, so the output doesn't depend on HashMap
+        // ordering.
+        &MultiplyMove {
+            ref changes,
+            position,
+        } => {
+            let mut changes: Vec<_> = changes.iter().collect();
+            changes.sort();
+            let changes: Vec<_> = changes
+                .iter()
+                .map(|(target, factor)| format!("{}: {}", target, factor))
+                .collect();
+            let _ = write!(
+                f,
+                "MultiplyMove {{ changes: {{{}}}, position: {:?} }}",
+                changes.join(", "),
+                position
+            );
+        }
+        &MultiplyAdd {
+            ref changes,
+            position,
+        } => {
+            let mut changes: Vec<_> = changes.iter().collect();
+            changes.sort();
+            let changes: Vec<_> = changes
+                .iter()
+                .map(|(cells, factor)| format!("{:?}: {}", cells, factor))
+                .collect();
+            let _ = write!(
+                f,
+                "MultiplyAdd {{ changes: {{{}}}, position: {:?} }}",
+                changes.join(", "),
+                position
+            );
+        }
         instr => {
             let _ = write!(f, "{:?}", instr);
         }
@@ -136,11 +271,15 @@ pub fn get_position(instr: &AstNode) -> Option<Position> {
     match *instr {
         Increment { position, .. } => position,
         PointerIncrement { position, .. } => position,
-        Read { position } => position,
-        Write { position } => position,
+        Read { position, .. } => position,
+        Write { position, .. } => position,
         Loop { position, .. } => position,
         Set { position, .. } => position,
         MultiplyMove { position, .. } => position,
+        MultiplyAdd { position, .. } => position,
+        Scan { position, .. } => position,
+        If { position, .. } => position,
+        WriteConst { position, .. } => position,
     }
 }
 
@@ -193,12 +332,14 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
                 }),
             }),
             ',' => instructions.push(Read {
+                offset: 0,
                 position: Some(Position {
                     start: index,
                     end: index,
                 }),
             }),
             '.' => instructions.push(Write {
+                offset: 0,
                 position: Some(Position {
                     start: index,
                     end: index,
@@ -212,6 +353,7 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
                 if let Some((mut parent_instr, open_index)) = stack.pop() {
                     parent_instr.push(Loop {
                         body: instructions,
+                        offset: 0,
                         position: Some(Position {
                             start: open_index,
                             end: index,
@@ -312,6 +454,7 @@ fn parse_read() {
     assert_eq!(
         parse(",").unwrap(),
         [Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 })
         }]
     );
@@ -322,6 +465,7 @@ fn parse_write() {
     assert_eq!(
         parse(".").unwrap(),
         [Write {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 })
         }]
     );
@@ -331,6 +475,7 @@ fn parse_write() {
 fn parse_empty_loop() {
     let expected = [Loop {
         body: vec![],
+        offset: 0,
         position: Some(Position { start: 0, end: 1 }),
     }];
     assert_eq!(parse("[]").unwrap(), expected);
@@ -345,6 +490,7 @@ fn parse_simple_loop() {
     }];
     let expected = [Loop {
         body: loop_body,
+        offset: 0,
         position: Some(Position { start: 0, end: 2 }),
     }];
     assert_eq!(parse("[+]").unwrap(), expected);
@@ -354,6 +500,7 @@ fn parse_simple_loop() {
 fn parse_complex_loop() {
     let loop_body = vec![
         Read {
+            offset: 0,
             position: Some(Position { start: 2, end: 2 }),
         },
         Increment {
@@ -364,10 +511,12 @@ fn parse_complex_loop() {
     ];
     let expected = [
         Write {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Loop {
             body: loop_body,
+            offset: 0,
             position: Some(Position { start: 1, end: 4 }),
         },
         Increment {
@@ -423,3 +572,19 @@ fn test_combine_pos_overlap() {
 
     assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 3 }));
 }
+
+#[test]
+fn display_multiply_move_sorted() {
+    let mut changes = HashMap::new();
+    for target in (1..10).rev() {
+        changes.insert(target, Wrapping(target as i8));
+    }
+    let instr = MultiplyMove {
+        changes,
+        position: None,
+    };
+    assert_eq!(
+        format!("{}", instr),
+        "MultiplyMove { changes: {1: 1, 2: 2, 3: 3, 4: 4, 5: 5, 6: 6, 7: 7, 8: 8, 9: 9}, position: None }"
+    );
+}
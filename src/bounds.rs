@@ -110,7 +110,10 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 )
             }
         }
-        Increment { offset, .. } | Set { offset, .. } => (
+        Increment { offset, .. }
+        | Set { offset, .. }
+        | Read { offset, .. }
+        | Write { offset, .. } => (
             SaturatingInt::Number(offset as i64),
             SaturatingInt::Number(0),
         ),
@@ -126,8 +129,21 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 SaturatingInt::Number(0),
             )
         }
-        Loop { ref body, .. } => {
+        MultiplyAdd { ref changes, .. } => {
+            let highest_affected = changes
+                .keys()
+                .map(|&(target, operand)| max(target, operand))
+                .fold(0, max);
+            (
+                SaturatingInt::Number(highest_affected as i64),
+                SaturatingInt::Number(0),
+            )
+        }
+        Loop {
+            ref body, offset, ..
+        } => {
             let (max_in_body, net_in_body) = overall_movement(body);
+            let max_in_body = max(max_in_body, SaturatingInt::Number(offset as i64));
 
             match net_in_body {
                 SaturatingInt::Number(net_loop_movement) => {
@@ -150,7 +166,24 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 }
             }
         }
-        Read { .. } | Write { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
+        If { ref body, .. } => {
+            let (max_in_body, net_in_body) = overall_movement(body);
+
+            // The body runs at most once, so unlike loops we can
+            // use its movement directly. We may also skip the body,
+            // so we can't end up lower than the current cell.
+            (max_in_body, max(net_in_body, SaturatingInt::Number(0)))
+        }
+        WriteConst { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
+        Scan { stride, .. } => {
+            if stride > 0 {
+                // We can't know how far we'll scan.
+                (SaturatingInt::Max, SaturatingInt::Max)
+            } else {
+                // As with loops, conservatively assume we don't move.
+                (SaturatingInt::Number(0), SaturatingInt::Number(0))
+            }
+        }
     }
 }
 
@@ -246,6 +279,18 @@ fn multiply_move_backwards_bounds() {
     assert_eq!(highest_cell_index(&instrs), 1);
 }
 
+#[test]
+fn multiply_add_bounds() {
+    let mut changes = HashMap::new();
+    changes.insert((1, 3), Wrapping(1));
+    let instrs = vec![MultiplyAdd {
+        changes,
+        position: None,
+    }];
+
+    assert_eq!(highest_cell_index(&instrs), 3);
+}
+
 #[test]
 fn unbounded_movement() {
     let instrs = parse("[>]").unwrap();
@@ -255,6 +300,59 @@ fn unbounded_movement() {
     assert_eq!(highest_cell_index(&instrs), 1);
 }
 
+#[test]
+fn scan_bounds() {
+    let instrs = vec![Scan {
+        stride: 2,
+        position: None,
+    }];
+    assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
+
+    let instrs = vec![
+        PointerIncrement {
+            amount: 3,
+            position: None,
+        },
+        Scan {
+            stride: -1,
+            position: None,
+        },
+    ];
+    assert_eq!(highest_cell_index(&instrs), 3);
+}
+
+#[test]
+fn if_bounds() {
+    // Unlike a loop, an if with net movement only runs once.
+    let instrs = vec![
+        If {
+            body: parse(">>[-]").unwrap(),
+            position: None,
+        },
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+    ];
+    assert_eq!(highest_cell_index(&instrs), 3);
+
+    let instrs = vec![
+        PointerIncrement {
+            amount: 2,
+            position: None,
+        },
+        If {
+            body: parse("<[-]").unwrap(),
+            position: None,
+        },
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+    ];
+    assert_eq!(highest_cell_index(&instrs), 3);
+}
+
 #[test]
 fn excessive_bounds_truncated() {
     // TODO: we should generate a warning in this situation.
@@ -265,6 +363,16 @@ fn excessive_bounds_truncated() {
     assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
 }
 
+#[test]
+fn loop_offset_bounds() {
+    let instrs = vec![Loop {
+        body: vec![],
+        offset: 4,
+        position: None,
+    }];
+    assert_eq!(highest_cell_index(&instrs), 4);
+}
+
 #[test]
 fn loop_with_no_net_movement() {
     // Max cell index 1, final cell position 0.
@@ -299,6 +407,21 @@ fn increment_offset_bounds() {
     assert_eq!(highest_cell_index(&instrs), 5);
 }
 
+#[test]
+fn read_write_offset_bounds() {
+    let instrs = [
+        Read {
+            offset: 3,
+            position: None,
+        },
+        Write {
+            offset: 7,
+            position: None,
+        },
+    ];
+    assert_eq!(highest_cell_index(&instrs), 7);
+}
+
 #[test]
 fn set_offset_bounds() {
     let instrs = [
@@ -1,10 +1,54 @@
 use crate::bfir::AstNode;
+use crate::execution::ExecutionState;
+use crate::profile::{num_instrs, PROFILE_FILENAME, PROFILE_HEADER};
 use std::io::prelude::Write;
 use std::num::Wrapping;
 use std::process::{Command, Stdio};
 
-fn add_instrs_to_c_prog(instrs: &[AstNode], prog: &mut String) {
+/// The number of cells we allocate, unless the program needs more.
+const MIN_CELLS: usize = 30000;
+
+/// Input that speculative execution consumed. The compiled program
+/// checks that it receives the same input.
+pub struct ExpectedInput<'i> {
+    pub bytes: &'i [u8],
+    /// Did speculative execution read past the end of the input?
+    pub eof: bool,
+}
+
+/// Add C code for `instrs` to `prog`. When profiling, `next_id` is
+/// the number of the first instruction (see `profile`), and we count
+/// each instruction as it executes.
+fn add_instrs_to_c_prog(
+    instrs: &[AstNode],
+    start_instr: Option<&AstNode>,
+    mut next_id: Option<&mut usize>,
+    prog: &mut String,
+) {
     for instr in instrs {
+        // Runtime execution jumps here, after the work we've done at
+        // compile time.
+        if start_instr.is_some_and(|start_instr| std::ptr::eq(instr, start_instr)) {
+            prog.push_str("start:;");
+        }
+
+        let id = next_id.as_deref_mut().map(|next_id| {
+            *next_id += 1;
+            *next_id - 1
+        });
+        if let Some(id) = id {
+            prog.push_str(&format!("counts[{}]++;", id));
+        }
+        // Loops count each test of their cell, and each iteration.
+        let count_iteration = match id {
+            Some(id) => format!("iterations[{}]++;", id),
+            None => String::new(),
+        };
+        let count_test = match id {
+            Some(id) => format!("counts[{}]++;", id),
+            None => String::new(),
+        };
+
         match instr {
             AstNode::Increment { amount, offset, .. } => {
                 prog.push_str(&format!("*(ptr + {}) += {};", offset, amount));
@@ -12,12 +56,12 @@ fn add_instrs_to_c_prog(instrs: &[AstNode], prog: &mut String) {
             AstNode::PointerIncrement { amount, .. } => {
                 if *amount < 0 {
                     prog.push_str(&format!(
-                        "if ((ptr + {}) < c) {{ raise(SIGSEGV); }};",
+                        "if ((ptr + {}) < c) {{ out_of_bounds(); }};",
                         amount
                     ));
                 } else if *amount > 0 {
                     prog.push_str(&format!(
-                        "if ((ptr + {}) >= (c + NUM_CELLS)) {{ raise(SIGSEGV); }};",
+                        "if ((ptr + {}) >= (c + NUM_CELLS)) {{ out_of_bounds(); }};",
                         amount
                     ));
                 }
@@ -25,17 +69,77 @@ fn add_instrs_to_c_prog(instrs: &[AstNode], prog: &mut String) {
                     prog.push_str(&format!("ptr += {};", amount));
                 }
             }
-            AstNode::Read { .. } => {
-                prog.push_str("scanf(\"%c\", ptr);");
+            AstNode::Read { offset, .. } => {
+                // Output is buffered, so make sure the user has seen
+                // it before we wait for input.
+                prog.push_str("fflush(stdout);");
+                prog.push_str(&format!("scanf(\"%c\", ptr + {});", offset));
             }
-            AstNode::Write { .. } => {
-                prog.push_str("printf(\"%c\", *ptr);");
+            AstNode::Write { offset, .. } => {
+                prog.push_str(&format!("putchar(*(ptr + {}));", offset));
             }
-            AstNode::Loop { body, .. } => {
-                prog.push_str("while(*ptr) {");
-                add_instrs_to_c_prog(&body, prog);
-                prog.push_str("}");
+            AstNode::WriteConst { bytes, .. } => {
+                prog.push_str(&format!(
+                    "fwrite({}, 1, {}, stdout);",
+                    c_string_literal(bytes),
+                    bytes.len()
+                ));
+            }
+            AstNode::Loop { body, offset, .. } => {
+                if *offset == 0 {
+                    prog.push_str("while(*ptr) {");
+                } else {
+                    prog.push_str(&format!("while(*(ptr + {})) {{", offset));
+                }
+                prog.push_str(&count_iteration);
+                add_instrs_to_c_prog(body, start_instr, next_id.as_deref_mut(), prog);
+                prog.push_str(&count_test);
+                prog.push('}');
+            }
+            AstNode::If { body, .. } => {
+                prog.push_str("if(*ptr) {");
+                prog.push_str(&count_iteration);
+                add_instrs_to_c_prog(body, start_instr, next_id.as_deref_mut(), prog);
+                prog.push('}');
+            }
+            AstNode::MultiplyAdd { changes, .. } => {
+                let mut targets: Vec<_> = changes.keys().collect();
+                targets.sort();
+
+                for (target, operand) in targets {
+                    let factor = changes[&(*target, *operand)];
+                    prog.push_str(&format!(
+                        "*(ptr + {}) += (*ptr) * *(ptr + {}) * {};",
+                        target, operand, factor
+                    ));
+                }
             }
+            AstNode::Scan { stride, .. } => match *stride {
+                1 => {
+                    prog.push_str("ptr = memchr(ptr, 0, (c + NUM_CELLS) - ptr);");
+                    prog.push_str("if (!ptr) { out_of_bounds(); };");
+                }
+                -1 => {
+                    prog.push_str("ptr = memrchr(c, 0, (ptr - c) + 1);");
+                    prog.push_str("if (!ptr) { out_of_bounds(); };");
+                }
+                _ => {
+                    prog.push_str("while(*ptr) {");
+                    if *stride < 0 {
+                        prog.push_str(&format!(
+                            "if ((ptr + {}) < c) {{ out_of_bounds(); }};",
+                            stride
+                        ));
+                    } else {
+                        prog.push_str(&format!(
+                            "if ((ptr + {}) >= (c + NUM_CELLS)) {{ out_of_bounds(); }};",
+                            stride
+                        ));
+                    }
+                    prog.push_str(&format!("ptr += {};", stride));
+                    prog.push('}');
+                }
+            },
             AstNode::Set { amount, offset, .. } => {
                 prog.push_str(&format!("*(ptr + {}) = {};", offset, amount));
             }
@@ -59,16 +163,128 @@ fn add_instrs_to_c_prog(instrs: &[AstNode], prog: &mut String) {
     }
 }
 
-pub fn c_prog_from_instructions(instrs: &[AstNode]) -> String {
-    let mut prog =
-        "#include<stdio.h>\n#include<signal.h>\n#define NUM_CELLS 30000\nint main(){ static char c[NUM_CELLS] = { 0 }, *target, *ptr; ptr=c;"
-            .to_owned();
+/// Return a C string literal containing `bytes`. We escape anything
+/// that isn't alphanumeric or a space, so we never produce
+/// trigraphs or unbalanced quotes.
+fn c_string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || byte == b' ' {
+            literal.push(byte as char);
+        } else {
+            // Octal escapes never consume more than three digits.
+            literal.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// The initial values of the cells, as a C array initialiser.
+fn c_cells_initialiser(state: &ExecutionState) -> String {
+    let num_initialised = state
+        .cells
+        .iter()
+        .rposition(|cell| cell.0 != 0)
+        .map_or(0, |index| index + 1);
+    if num_initialised == 0 {
+        return "{ 0 }".to_owned();
+    }
+
+    let values: Vec<_> = state.cells[..num_initialised]
+        .iter()
+        .map(|cell| cell.0.to_string())
+        .collect();
+    format!("{{ {} }}", values.join(", "))
+}
+
+/// C functions that count how often each instruction executes, and
+/// write the counts to a file when the program exits.
+fn c_profile_functions(num_instrs: usize) -> String {
+    format!(
+        "#define NUM_INSTRS {}\nstatic unsigned long long counts[NUM_INSTRS], iterations[NUM_INSTRS];\n\
+         static void write_profile(void) {{ FILE *f = fopen(\"{}\", \"w\"); if (!f) {{ return; }} fprintf(f, \"{} %d\\n\", NUM_INSTRS); for (int i = 0; i < NUM_INSTRS; i++) {{ fprintf(f, \"%llu %llu\\n\", counts[i], iterations[i]); }} fclose(f); }}\n",
+        num_instrs.max(1),
+        PROFILE_FILENAME,
+        PROFILE_HEADER
+    )
+}
+
+/// A C program equivalent to `instrs`, which starts from `state`
+/// (the result of speculative execution). If `profile` is true, the
+/// program writes a profile when it exits.
+pub fn c_prog_from_instructions(
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    expected_input: Option<ExpectedInput>,
+    profile: bool,
+) -> String {
+    let mut prog = format!(
+        "#define _GNU_SOURCE\n#include<stdio.h>\n#include<stdlib.h>\n#include<string.h>\n#include<signal.h>\n#define NUM_CELLS {}\n",
+        state.cells.len().max(MIN_CELLS)
+    );
+    if profile {
+        prog.push_str(&c_profile_functions(num_instrs(instrs)));
+        prog.push_str(
+            "static void out_of_bounds(void) { fflush(stdout); write_profile(); raise(SIGSEGV); }\n",
+        );
+    } else {
+        // We buffer all output, so flush it before crashing.
+        prog.push_str("static void out_of_bounds(void) { fflush(stdout); raise(SIGSEGV); }\n");
+    }
+    if expected_input.is_some() {
+        prog.push_str(
+            "static void unexpected_input(void) { fputs(\"Input does not match the input assumed at compile time.\\n\", stderr); exit(1); }\n\
+             static void expect_input(const char *expected, size_t len, int eof) { for (size_t i = 0; i < len; i++) { if (getchar() != (unsigned char)expected[i]) { unexpected_input(); } } if (eof && getchar() != EOF) { unexpected_input(); } }\n",
+        );
+    }
+
+    prog.push_str(&format!(
+        "int main(){{ static char c[NUM_CELLS] = {}, *target, *ptr; ptr = c + {}; setvbuf(stdout, NULL, _IOFBF, 1 << 16);",
+        c_cells_initialiser(state),
+        state.cell_ptr
+    ));
+    if profile {
+        prog.push_str("atexit(write_profile);");
+    }
+    if let Some(expected_input) = expected_input {
+        prog.push_str(&format!(
+            "expect_input({}, {}, {});",
+            c_string_literal(expected_input.bytes),
+            expected_input.bytes.len(),
+            expected_input.eof as u8
+        ));
+    }
+    if !state.outputs.is_empty() {
+        let outputs: Vec<u8> = state.outputs.iter().map(|&byte| byte as u8).collect();
+        prog.push_str(&format!(
+            "fwrite({}, 1, {}, stdout);",
+            c_string_literal(&outputs),
+            outputs.len()
+        ));
+    }
 
-    add_instrs_to_c_prog(&instrs, &mut prog);
+    // If speculative execution finished the program, there's nothing
+    // left to do at runtime.
+    if let Some(start_instr) = state.start_instr {
+        prog.push_str("goto start;");
+        let mut next_id = 0;
+        add_instrs_to_c_prog(
+            instrs,
+            Some(start_instr),
+            if profile { Some(&mut next_id) } else { None },
+            &mut prog,
+        );
+    }
     prog += "}";
     prog
 }
-pub fn compile_c_program(c_program: &str, output: &str, opt_level: u8, native: bool) {
+pub fn compile_c_program(
+    c_program: &str,
+    output: &str,
+    opt_level: u8,
+    native: bool,
+) -> Result<(), String> {
     let mut args = vec!["-x", "c", "-"];
     // Optimization level
     let opt_level_arg = format!("-O{}", opt_level);
@@ -87,11 +303,83 @@ pub fn compile_c_program(c_program: &str, output: &str, opt_level: u8, native: b
         .spawn()
         .expect("Could not start C compiler");
 
-    let cc_stdin = cc
-        .stdin
-        .as_mut()
-        .expect("Could not get stdin of C compiler");
-    cc_stdin
-        .write_all(c_program.as_bytes())
-        .expect("Failed to write to C compiler");
+    {
+        let cc_stdin = cc
+            .stdin
+            .as_mut()
+            .expect("Could not get stdin of C compiler");
+        cc_stdin
+            .write_all(c_program.as_bytes())
+            .expect("Failed to write to C compiler");
+    }
+    // Close stdin so the C compiler sees EOF, then wait for it to finish.
+    drop(cc.stdin.take());
+    let status = cc.wait().expect("C compiler did not run");
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("The C compiler failed ({}).", status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::execution::{speculate, Input};
+
+    #[test]
+    fn cells_initialiser_trims_zeros() {
+        let instrs = parse("+>->>").unwrap();
+        let (state, _, _) = speculate(&instrs, 100, None, &mut Input::Runtime);
+        assert_eq!(c_cells_initialiser(&state), "{ 1, -1 }");
+    }
+
+    #[test]
+    fn finished_program_only_writes_output() {
+        let instrs = parse("+++.").unwrap();
+        let (state, _, _) = speculate(&instrs, 100, None, &mut Input::Runtime);
+        let prog = c_prog_from_instructions(&instrs, &state, None, false);
+
+        assert!(prog.contains("fwrite(\"\\003\", 1, 1, stdout);"));
+        assert!(!prog.contains("goto start;"));
+    }
+
+    #[test]
+    fn runtime_starts_at_read() {
+        let instrs = parse("+>,.").unwrap();
+        let (state, _, _) = speculate(&instrs, 100, None, &mut Input::Runtime);
+        let prog = c_prog_from_instructions(&instrs, &state, None, false);
+
+        assert!(prog.contains("ptr = c + 1;"));
+        assert!(prog.contains("goto start;"));
+        assert!(prog.contains("start:;fflush(stdout);scanf"));
+    }
+
+    #[test]
+    fn expected_input_checked() {
+        let instrs = parse(",.").unwrap();
+        let (state, _, _) = speculate(&instrs, 100, None, &mut Input::bytes(b"a"));
+        let expected_input = ExpectedInput {
+            bytes: b"a",
+            eof: false,
+        };
+        let prog = c_prog_from_instructions(&instrs, &state, Some(expected_input), false);
+
+        assert!(prog.contains("expect_input(\"a\", 1, 0);"));
+    }
+
+    #[test]
+    fn profile_counts_instructions() {
+        let instrs = parse("+[-]").unwrap();
+        let mut state = ExecutionState::initial(&instrs);
+        state.start_instr = instrs.first();
+        let prog = c_prog_from_instructions(&instrs, &state, None, true);
+
+        assert!(prog.contains("#define NUM_INSTRS 3"));
+        assert!(prog.contains("atexit(write_profile);"));
+        assert!(prog.contains(
+            "counts[1]++;while(*ptr) {iterations[1]++;counts[2]++;*(ptr + 0) += -1;counts[1]++;}"
+        ));
+    }
 }
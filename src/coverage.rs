@@ -0,0 +1,184 @@
+//! Source coverage for `bfc run --coverage`.
+//!
+//! We count how many times each character of the source executes.
+//! Loops count each time we test their cell, at both brackets.
+
+use std::fmt::Write;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode};
+
+/// The characters that are BF instructions.
+const INSTRUCTION_CHARS: &str = "+-<>,.[]";
+
+pub struct Coverage {
+    /// The number of times each character of the source executed.
+    counts: Vec<u64>,
+}
+
+/// A line of source, with the counts for its instruction characters.
+struct LineCoverage<'s> {
+    text: &'s str,
+    /// The count for each instruction character, or None for
+    /// comments.
+    counts: Vec<Option<u64>>,
+}
+
+impl<'s> LineCoverage<'s> {
+    fn has_instructions(&self) -> bool {
+        self.counts.iter().any(|count| count.is_some())
+    }
+
+    /// The most times any instruction on this line executed.
+    fn count(&self) -> u64 {
+        self.counts.iter().flatten().copied().max().unwrap_or(0)
+    }
+}
+
+impl Coverage {
+    pub fn new(source: &str) -> Self {
+        Coverage {
+            counts: vec![0; source.chars().count()],
+        }
+    }
+
+    /// Count `instr` executing.
+    pub fn record(&mut self, instr: &AstNode) {
+        let position = match get_position(instr) {
+            Some(position) => position,
+            None => return,
+        };
+        match instr {
+            Loop { .. } | If { .. } => {
+                self.counts[position.start] += 1;
+                self.counts[position.end] += 1;
+            }
+            _ => {
+                for count in &mut self.counts[position.start..=position.end] {
+                    *count += 1;
+                }
+            }
+        }
+    }
+
+    fn lines<'s>(&self, source: &'s str) -> Vec<LineCoverage<'s>> {
+        let mut offset = 0;
+        source
+            .split('\n')
+            .map(|text| {
+                let counts = text
+                    .chars()
+                    .enumerate()
+                    .map(|(column, c)| {
+                        if INSTRUCTION_CHARS.contains(c) {
+                            Some(self.counts[offset + column])
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                offset += counts.len() + 1;
+                LineCoverage { text, counts }
+            })
+            .collect()
+    }
+
+    /// An annotated listing of `source`, like gcov. Each line starts
+    /// with the number of times it executed, `#####` if it never
+    /// executed, or `-` if it has no instructions. If only part of a
+    /// line executed, we mark the instructions that didn't.
+    pub fn annotated_listing(&self, source: &str) -> String {
+        let mut listing = String::new();
+        for line in self.lines(source) {
+            let count_text = if !line.has_instructions() {
+                "-".to_owned()
+            } else if line.count() == 0 {
+                "#####".to_owned()
+            } else {
+                line.count().to_string()
+            };
+            let _ = writeln!(listing, "{:>9}: {}", count_text, line.text);
+
+            if line.count() > 0 && line.counts.contains(&Some(0)) {
+                let markers: String = line
+                    .counts
+                    .iter()
+                    .map(|count| if *count == Some(0) { '^' } else { ' ' })
+                    .collect();
+                let _ = writeln!(listing, "{:>9}  {}", "", markers.trim_end());
+            }
+        }
+        listing
+    }
+
+    /// An lcov tracefile for `source`, which is in the file `filename`.
+    pub fn lcov(&self, source: &str, filename: &str) -> String {
+        let mut report = format!("TN:\nSF:{}\n", filename);
+        let mut lines_found = 0;
+        let mut lines_hit = 0;
+        for (line_idx, line) in self.lines(source).iter().enumerate() {
+            if line.has_instructions() {
+                let _ = writeln!(report, "DA:{},{}", line_idx + 1, line.count());
+                lines_found += 1;
+                if line.count() > 0 {
+                    lines_hit += 1;
+                }
+            }
+        }
+        let _ = write!(
+            report,
+            "LF:{}\nLH:{}\nend_of_record\n",
+            lines_found, lines_hit
+        );
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::execution::Input;
+    use crate::interpreter::Interpreter;
+    use pretty_assertions::assert_eq;
+
+    fn coverage_run(source: &str) -> Coverage {
+        let instrs = parse(source).unwrap();
+        let mut coverage = Coverage::new(source);
+        let mut interpreter = Interpreter::new(&instrs);
+        while let Some(instr) = interpreter.current_instr() {
+            coverage.record(instr);
+            interpreter.step(&mut Input::Runtime).unwrap();
+        }
+        coverage
+    }
+
+    #[test]
+    fn count_loop_brackets() {
+        let coverage = coverage_run("++[-]");
+        assert_eq!(coverage.counts, vec![1, 1, 3, 2, 3]);
+    }
+
+    #[test]
+    fn listing_marks_unexecuted_code() {
+        let source = "+ set\n[-]>[+]\n\n[\n.\n]";
+        let coverage = coverage_run(source);
+
+        assert_eq!(
+            coverage.annotated_listing(source),
+            "        1: + set\n        2: [-]>[+]\n                ^\n        -: \n        1: [\n    \
+             #####: .\n        1: ]\n"
+        );
+    }
+
+    #[test]
+    fn lcov_by_line() {
+        let source = "+\n# comment\n[-]";
+        let coverage = coverage_run(source);
+
+        assert_eq!(
+            coverage.lcov(source, "test.bf"),
+            "TN:\nSF:test.bf\nDA:1,1\nDA:3,2\nLF:2\nLH:2\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,678 @@
+//! An interactive debugger for `bfc debug`.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::num::Wrapping;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Cell, Position};
+use crate::diagnostics::{self, Info, Level, Warning};
+use crate::execution::Input;
+use crate::interpreter::Interpreter;
+
+/// The number of cells we show on each side of the pointer.
+const CELLS_RADIUS: isize = 5;
+
+const HELP: &str = "\
+Commands:
+  step [N]          execute the next N instructions (default 1)
+  continue          run until a breakpoint, watchpoint, error or the end
+  back [N]          undo the last N instructions (default 1)
+  rcontinue         run backwards until a breakpoint, watchpoint or the start
+  break LINE:COL    stop before executing the instruction at LINE:COL
+  break             list breakpoints
+  delete N          remove breakpoint N
+  watch CELL        stop when CELL changes
+  unwatch CELL      stop watching CELL
+  cells [CELL]      show the cells around CELL (default: the pointer)
+  set CELL VALUE    change CELL to VALUE (a number or 'c')
+  where             show the next instruction
+  quit              exit the debugger
+";
+
+/// Why we stopped running the program.
+enum Stop {
+    Breakpoint(usize),
+    /// A watched cell changed. We keep the position of the
+    /// instruction that changed it.
+    Watchpoint {
+        cell_index: usize,
+        from: Cell,
+        to: Cell,
+        position: Option<Position>,
+    },
+    Error(Warning),
+    Finished,
+    /// We stepped back as far as our history goes.
+    Start,
+    Stepped,
+}
+
+pub struct Debugger<'a> {
+    filename: &'a str,
+    source: &'a str,
+    interpreter: Interpreter<'a>,
+    input: Input<'a>,
+    /// Breakpoints by number, as offsets into the source.
+    breakpoints: BTreeMap<usize, usize>,
+    next_breakpoint: usize,
+    /// Watched cells, and the value they had when we last stopped.
+    watchpoints: BTreeMap<usize, Cell>,
+    /// The number of bytes of output we've shown the user.
+    printed: usize,
+    /// The input before each read, by step number, so we can undo
+    /// reads.
+    input_history: Vec<(u64, Input<'a>)>,
+}
+
+/// The offset of the first instruction at or after `offset` in
+/// `source`.
+fn next_instruction_offset(source: &str, offset: usize) -> Option<usize> {
+    source
+        .chars()
+        .enumerate()
+        .skip(offset)
+        .find(|(_, c)| "+-<>,.[]".contains(*c))
+        .map(|(index, _)| index)
+}
+
+/// Convert a (one-indexed) line and column to an offset into
+/// `source`.
+fn source_offset(source: &str, line: usize, column: usize) -> Option<usize> {
+    let mut offset = 0;
+    for (line_idx, text) in source.split('\n').enumerate() {
+        let len = text.chars().count();
+        if line_idx + 1 == line {
+            return if column >= 1 && column <= len {
+                Some(offset + column - 1)
+            } else {
+                None
+            };
+        }
+        offset += len + 1;
+    }
+    None
+}
+
+/// Does executing `instr` execute the source at `offset`? Loops are
+/// only considered to start at their brackets, so a breakpoint in a
+/// loop body doesn't stop every time we test the loop's cell.
+fn instr_at_offset(instr: &AstNode, offset: usize) -> bool {
+    match (instr, get_position(instr)) {
+        (Loop { .. }, Some(position)) | (If { .. }, Some(position)) => {
+            offset == position.start || offset == position.end
+        }
+        (_, Some(position)) => position.start <= offset && offset <= position.end,
+        (_, None) => false,
+    }
+}
+
+/// Parse a cell value, either as a number or as a quoted character.
+fn parse_cell_value(src: &str) -> Option<Cell> {
+    let chars: Vec<char> = src.chars().collect();
+    if chars.len() == 3 && chars[0] == '\'' && chars[2] == '\'' && chars[1].is_ascii() {
+        return Some(Wrapping(chars[1] as u8 as i8));
+    }
+    match src.parse::<i16>() {
+        Ok(value) if (-128..=255).contains(&value) => Some(Wrapping(value as u8 as i8)),
+        _ => None,
+    }
+}
+
+impl<'a> Debugger<'a> {
+    /// Create a debugger for `instrs`, stopping at any `#` characters
+    /// in `source`.
+    pub fn new(filename: &'a str, source: &'a str, instrs: &'a [AstNode], input: &'a [u8]) -> Self {
+        let mut debugger = Debugger {
+            filename,
+            source,
+            interpreter: Interpreter::new(instrs).with_history(),
+            input: Input::bytes(input),
+            breakpoints: BTreeMap::new(),
+            next_breakpoint: 1,
+            watchpoints: BTreeMap::new(),
+            printed: 0,
+            input_history: vec![],
+        };
+
+        let hash_offsets: Vec<usize> = source
+            .chars()
+            .enumerate()
+            .filter(|&(_, c)| c == '#')
+            .filter_map(|(index, _)| next_instruction_offset(source, index + 1))
+            .collect();
+        for offset in hash_offsets {
+            if !debugger.breakpoints.values().any(|&other| other == offset) {
+                debugger.add_breakpoint(offset);
+            }
+        }
+        debugger
+    }
+
+    fn add_breakpoint(&mut self, offset: usize) -> usize {
+        let number = self.next_breakpoint;
+        self.breakpoints.insert(number, offset);
+        self.next_breakpoint += 1;
+        number
+    }
+
+    /// Describe the breakpoints and where the program starts.
+    pub fn start_message(&self) -> String {
+        let mut message = String::new();
+        if !self.breakpoints.is_empty() {
+            message.push_str(&self.breakpoint_list());
+        }
+        message.push_str(&self.location());
+        message
+    }
+
+    /// Execute a debugger command, returning the text to show the
+    /// user.
+    pub fn command(&mut self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => String::new(),
+            ["step"] | ["s"] => self.run(Some(1)),
+            ["step", steps] | ["s", steps] => match steps.parse() {
+                Ok(steps) if steps > 0 => self.run(Some(steps)),
+                _ => format!("Expected a number of steps, got '{}'.\n", steps),
+            },
+            ["continue"] | ["c"] => self.run(None),
+            ["back"] => self.run_backwards(Some(1)),
+            ["back", steps] => match steps.parse() {
+                Ok(steps) if steps > 0 => self.run_backwards(Some(steps)),
+                _ => format!("Expected a number of steps, got '{}'.\n", steps),
+            },
+            ["rcontinue"] | ["rc"] => self.run_backwards(None),
+            ["break"] | ["b"] => self.breakpoint_list(),
+            ["break", location] | ["b", location] => self.break_at(location),
+            ["delete", number] | ["d", number] => match number.parse() {
+                Ok(number) if self.breakpoints.remove(&number).is_some() => {
+                    format!("Deleted breakpoint {}.\n", number)
+                }
+                _ => format!("No breakpoint '{}'.\n", number),
+            },
+            ["watch", cell] | ["w", cell] => match self.parse_cell_index(cell) {
+                Ok(cell_index) => {
+                    let value = self.interpreter.state.cells[cell_index];
+                    self.watchpoints.insert(cell_index, value);
+                    format!("Watching cell {} (currently {}).\n", cell_index, value)
+                }
+                Err(message) => message,
+            },
+            ["unwatch", cell] => match self.parse_cell_index(cell) {
+                Ok(cell_index) if self.watchpoints.remove(&cell_index).is_some() => {
+                    format!("Stopped watching cell {}.\n", cell_index)
+                }
+                Ok(cell_index) => format!("Cell {} is not being watched.\n", cell_index),
+                Err(message) => message,
+            },
+            ["cells"] => self.cells_view(self.interpreter.state.cell_ptr),
+            ["cells", cell] => match self.parse_cell_index(cell) {
+                Ok(cell_index) => self.cells_view(cell_index as isize),
+                Err(message) => message,
+            },
+            ["set", cell, value] => match (self.parse_cell_index(cell), parse_cell_value(value)) {
+                (Ok(cell_index), Some(value)) => {
+                    self.interpreter.state.cells[cell_index] = value;
+                    // Changing a cell by hand shouldn't trigger a
+                    // watchpoint.
+                    if let Some(watched) = self.watchpoints.get_mut(&cell_index) {
+                        *watched = value;
+                    }
+                    self.cells_view(self.interpreter.state.cell_ptr)
+                }
+                (Err(message), _) => message,
+                (_, None) => format!(
+                    "Expected a value from -128 to 255 or a character like 'a', got '{}'.\n",
+                    value
+                ),
+            },
+            ["where"] => self.location(),
+            ["help"] | ["h"] => HELP.to_owned(),
+            _ => format!("Unknown command '{}'. Try 'help'.\n", line.trim()),
+        }
+    }
+
+    fn parse_cell_index(&self, src: &str) -> Result<usize, String> {
+        match src.parse::<usize>() {
+            Ok(cell_index) if cell_index < self.interpreter.state.cells.len() => Ok(cell_index),
+            _ => Err(format!(
+                "Expected a cell from 0 to {}, got '{}'.\n",
+                self.interpreter.state.cells.len() - 1,
+                src
+            )),
+        }
+    }
+
+    fn break_at(&mut self, location: &str) -> String {
+        let offset = location
+            .split_once(':')
+            .and_then(|(line, column)| Some((line.parse().ok()?, column.parse().ok()?)))
+            .and_then(|(line, column)| source_offset(self.source, line, column))
+            .and_then(|offset| next_instruction_offset(self.source, offset));
+        match offset {
+            Some(offset) => {
+                let number = self.add_breakpoint(offset);
+                format!("Breakpoint {} at {}.\n", number, self.line_column(offset))
+            }
+            None => format!(
+                "Expected LINE:COL at or before an instruction, got '{}'.\n",
+                location
+            ),
+        }
+    }
+
+    fn line_column(&self, offset: usize) -> String {
+        let (line_idx, column_idx) = diagnostics::position(self.source, offset);
+        format!("{}:{}", line_idx + 1, column_idx + 1)
+    }
+
+    fn breakpoint_list(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints.\n".to_owned();
+        }
+        self.breakpoints
+            .iter()
+            .map(|(number, &offset)| {
+                format!("Breakpoint {} at {}.\n", number, self.line_column(offset))
+            })
+            .collect()
+    }
+
+    /// The breakpoint at the next instruction, if any.
+    fn breakpoint_at_current_instr(&self) -> Option<usize> {
+        let instr = self.interpreter.current_instr()?;
+        self.breakpoints
+            .iter()
+            .find(|&(_, &offset)| instr_at_offset(instr, offset))
+            .map(|(&number, _)| number)
+    }
+
+    /// A watched cell that has changed since we last stopped.
+    fn changed_watchpoint(&mut self) -> Option<(usize, Cell)> {
+        let cells = &self.interpreter.state.cells;
+        let (&cell_index, watched) = self
+            .watchpoints
+            .iter_mut()
+            .find(|(&cell_index, watched)| cells[cell_index] != **watched)?;
+        let old_value = *watched;
+        *watched = cells[cell_index];
+        Some((cell_index, old_value))
+    }
+
+    /// Execute instructions until we've taken `max_steps` steps, or
+    /// until we reach a breakpoint, watchpoint, error or the end of
+    /// the program.
+    fn execute(&mut self, max_steps: Option<u64>) -> Stop {
+        let mut steps = 0;
+        loop {
+            if self.interpreter.is_finished() {
+                return Stop::Finished;
+            }
+
+            let instr = self.interpreter.current_instr();
+            if let Some(Read { .. }) = instr {
+                self.input_history
+                    .push((self.interpreter.steps, self.input.clone()));
+            }
+            if let Err(warning) = self.interpreter.step(&mut self.input) {
+                return Stop::Error(warning);
+            }
+            steps += 1;
+
+            if let Some((cell_index, old_value)) = self.changed_watchpoint() {
+                return Stop::Watchpoint {
+                    cell_index,
+                    from: old_value,
+                    to: self.interpreter.state.cells[cell_index],
+                    position: instr.and_then(get_position),
+                };
+            }
+            if self.interpreter.is_finished() {
+                return Stop::Finished;
+            }
+            if let Some(number) = self.breakpoint_at_current_instr() {
+                return Stop::Breakpoint(number);
+            }
+            if max_steps == Some(steps) {
+                return Stop::Stepped;
+            }
+        }
+    }
+
+    /// Undo instructions until we've gone back `max_steps` steps, or
+    /// until we reach a breakpoint, watchpoint or the oldest step we
+    /// recorded.
+    fn execute_backwards(&mut self, max_steps: Option<u64>) -> Stop {
+        let mut steps = 0;
+        loop {
+            if !self.interpreter.step_back() {
+                return Stop::Start;
+            }
+            // Give back the input that this step read.
+            if self
+                .input_history
+                .last()
+                .is_some_and(|&(step, _)| step == self.interpreter.steps)
+            {
+                self.input = self.input_history.pop().unwrap().1;
+            }
+            steps += 1;
+
+            if let Some((cell_index, old_value)) = self.changed_watchpoint() {
+                return Stop::Watchpoint {
+                    cell_index,
+                    from: self.interpreter.state.cells[cell_index],
+                    to: old_value,
+                    position: self.interpreter.current_instr().and_then(get_position),
+                };
+            }
+            if let Some(number) = self.breakpoint_at_current_instr() {
+                return Stop::Breakpoint(number);
+            }
+            if max_steps == Some(steps) {
+                return Stop::Stepped;
+            }
+        }
+    }
+
+    /// Run the program and describe where we stopped.
+    fn run(&mut self, max_steps: Option<u64>) -> String {
+        let stop = self.execute(max_steps);
+        self.describe_stop(stop)
+    }
+
+    /// Run the program backwards and describe where we stopped.
+    fn run_backwards(&mut self, max_steps: Option<u64>) -> String {
+        let stop = self.execute_backwards(max_steps);
+        self.describe_stop(stop)
+    }
+
+    fn describe_stop(&mut self, stop: Stop) -> String {
+        // Show anything the program wrote while it was running. We
+        // can't take back output when running backwards.
+        let outputs: Vec<u8> = self.interpreter.state.outputs
+            [self.printed.min(self.interpreter.state.outputs.len())..]
+            .iter()
+            .map(|&byte| byte as u8)
+            .collect();
+        self.printed = self.interpreter.state.outputs.len();
+        let mut message = String::from_utf8_lossy(&outputs).into_owned();
+        if !message.is_empty() && !message.ends_with('\n') {
+            message.push('\n');
+        }
+
+        match stop {
+            Stop::Breakpoint(number) => {
+                message.push_str(&format!("Breakpoint {}.\n", number));
+                message.push_str(&self.location());
+            }
+            Stop::Watchpoint {
+                cell_index,
+                from,
+                to,
+                position,
+            } => {
+                let info = Info {
+                    level: Level::Note,
+                    filename: self.filename.to_owned(),
+                    message: format!("Cell {} changed from {} to {} here.", cell_index, from, to),
+                    position,
+                    source: Some(self.source.to_owned()),
+                };
+                message.push_str(&format!("{}\n", info));
+                message.push_str(&self.location());
+            }
+            Stop::Error(warning) => {
+                let info = Info {
+                    level: Level::Error,
+                    filename: self.filename.to_owned(),
+                    message: warning.message,
+                    position: warning.position,
+                    source: Some(self.source.to_owned()),
+                };
+                message.push_str(&format!("{}\n", info));
+                message.push_str(&self.cells_view(self.interpreter.state.cell_ptr));
+            }
+            Stop::Finished => {
+                message.push_str(&format!(
+                    "The program finished after {} steps.\n",
+                    self.interpreter.steps
+                ));
+            }
+            Stop::Start => {
+                if self.interpreter.steps == 0 {
+                    message.push_str("Reached the start of the program.\n");
+                } else {
+                    message.push_str("Reached the oldest step that we recorded.\n");
+                }
+                message.push_str(&self.location());
+            }
+            Stop::Stepped => message.push_str(&self.location()),
+        }
+        message
+    }
+
+    /// Show the next instruction and the cells around the pointer.
+    fn location(&self) -> String {
+        let instr = match self.interpreter.current_instr() {
+            Some(instr) => instr,
+            None => return "The program has finished.\n".to_owned(),
+        };
+        // Loops can span many lines, so just highlight the bracket.
+        let position = match instr {
+            Loop { .. } | If { .. } => get_position(instr).map(|position| Position {
+                start: position.start,
+                end: position.start,
+            }),
+            _ => get_position(instr),
+        };
+        let info = Info {
+            level: Level::Note,
+            filename: self.filename.to_owned(),
+            message: format!("Paused after {} steps.", self.interpreter.steps),
+            position,
+            source: Some(self.source.to_owned()),
+        };
+        format!(
+            "{}\n{}",
+            info,
+            self.cells_view(self.interpreter.state.cell_ptr)
+        )
+    }
+
+    /// Show the cells around `centre`, marking the pointer.
+    fn cells_view(&self, centre: isize) -> String {
+        let cells = &self.interpreter.state.cells;
+        let first = (centre - CELLS_RADIUS).max(0);
+        let last = (centre + CELLS_RADIUS).min(cells.len() as isize - 1);
+
+        let mut indices = String::from("cell ");
+        let mut values = String::from("value");
+        for cell_index in first..=last {
+            let index_text = if cell_index == self.interpreter.state.cell_ptr {
+                format!("[{}]", cell_index)
+            } else {
+                cell_index.to_string()
+            };
+            let value_text = cells[cell_index as usize].to_string();
+            let width = index_text.len().max(value_text.len()) + 1;
+            indices.push_str(&format!(" {:>width$}", index_text, width = width));
+            values.push_str(&format!(" {:>width$}", value_text, width = width));
+        }
+        format!("{}\n{}\n", indices, values)
+    }
+}
+
+/// Debug `instrs` interactively, reading commands from stdin. Reads
+/// in the program take their values from `input`.
+pub fn debug(filename: &str, source: &str, instrs: &[AstNode], input: &[u8]) {
+    let mut debugger = Debugger::new(filename, source, instrs, input);
+    print!("{}", debugger.start_message());
+
+    let stdin = io::stdin();
+    loop {
+        print!("(bfc) ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if matches!(line.trim(), "quit" | "q") {
+            break;
+        }
+        print!("{}", debugger.command(&line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::peephole::optimize;
+
+    #[test]
+    fn source_offset_by_line() {
+        assert_eq!(source_offset("+\n->", 2, 2), Some(3));
+        assert_eq!(source_offset("+\n->", 2, 3), None);
+        assert_eq!(source_offset("+\n->", 3, 1), None);
+    }
+
+    #[test]
+    fn hash_breakpoints() {
+        let source = "+# comment\n+.";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        assert_eq!(debugger.breakpoints.values().collect::<Vec<_>>(), vec![&11]);
+        assert!(debugger.command("continue").contains("Breakpoint 1."));
+        assert_eq!(debugger.interpreter.steps, 1);
+    }
+
+    #[test]
+    fn break_at_line_and_column() {
+        let source = "++\n[-]";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        assert_eq!(debugger.command("break 2:2"), "Breakpoint 1 at 2:2.\n");
+        debugger.command("continue");
+        assert_eq!(debugger.interpreter.steps, 3);
+        debugger.command("continue");
+        assert_eq!(debugger.interpreter.steps, 5);
+        assert!(debugger
+            .command("continue")
+            .contains("finished after 7 steps"));
+    }
+
+    #[test]
+    fn break_in_optimised_loop() {
+        let source = ",>+<[->+<]>.";
+        let instrs = optimize(parse(source).unwrap(), &None).0;
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        debugger.command("break 1:7");
+        assert!(debugger.command("continue").contains("Breakpoint 1."));
+        assert!(matches!(
+            debugger.interpreter.current_instr(),
+            Some(MultiplyMove { .. })
+        ));
+    }
+
+    #[test]
+    fn watch_cell() {
+        let source = "+>+>+";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        debugger.command("watch 1");
+        let message = debugger.command("continue");
+        assert!(message.contains("Cell 1 changed from 0 to 1 here."));
+        assert_eq!(debugger.interpreter.steps, 3);
+    }
+
+    #[test]
+    fn set_cell_while_paused() {
+        let source = "[.]";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        debugger.command("set 0 'a'");
+        debugger.command("step");
+        assert_eq!(debugger.interpreter.state.cells[0], Wrapping(97));
+        assert!(debugger.command("step").starts_with('a'));
+        assert!(debugger
+            .command("set 0 256")
+            .starts_with("Expected a value"));
+    }
+
+    #[test]
+    fn cells_around_pointer() {
+        let source = ">>+";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        debugger.command("step 3");
+        assert_eq!(
+            debugger.command("cells"),
+            "cell   0  1  [2]\nvalue  0  0    1\n"
+        );
+    }
+
+    #[test]
+    fn step_back_after_crash() {
+        let source = "+[>+<<]";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        debugger.command("continue");
+        assert_eq!(debugger.interpreter.state.cell_ptr, 0);
+        debugger.command("back 2");
+        assert_eq!(debugger.interpreter.state.cell_ptr, 1);
+        assert_eq!(debugger.interpreter.state.cells[1], Wrapping(0));
+        assert!(debugger
+            .command("rcontinue")
+            .contains("Reached the start of the program."));
+    }
+
+    #[test]
+    fn reverse_watchpoint() {
+        let source = "+>+>+";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        debugger.command("continue");
+        debugger.command("watch 1");
+        assert!(debugger
+            .command("rcontinue")
+            .contains("Cell 1 changed from 0 to 1 here."));
+        assert_eq!(debugger.interpreter.steps, 2);
+    }
+
+    #[test]
+    fn step_back_over_read() {
+        let source = ",.";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"ab");
+
+        assert!(debugger.command("continue").starts_with('a'));
+        debugger.command("back 2");
+        debugger.command("set 0 0");
+        debugger.command("continue");
+        assert_eq!(debugger.interpreter.state.outputs, vec![b'a' as i8]);
+    }
+
+    #[test]
+    fn error_reported() {
+        let source = "<";
+        let instrs = parse(source).unwrap();
+        let mut debugger = Debugger::new("test.bf", source, &instrs, b"");
+
+        assert!(debugger
+            .command("continue")
+            .contains("This instruction moves the pointer to cell -1."));
+    }
+}
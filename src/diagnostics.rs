@@ -1,7 +1,7 @@
 //! Human-readable warnings and errors for the CLI.
 
 use ansi_term::ANSIStrings;
-use ansi_term::Colour::{Purple, Red};
+use ansi_term::Colour::{Cyan, Green, Purple, Red};
 use ansi_term::Style;
 use std::fmt;
 
@@ -13,10 +13,19 @@ pub struct Warning {
     pub position: Option<Position>,
 }
 
+/// A note about how we optimised the code at `position`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Remark {
+    pub message: String,
+    pub position: Option<Position>,
+}
+
 /// The severity of the Info.
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum Level {
+    Note,
+    Remark,
     Warning,
     Error,
 }
@@ -32,9 +41,9 @@ pub struct Info {
     pub source: Option<String>,
 }
 
-// Given an index into a string, return the line number and column
-// count (both zero-indexed).
-fn position(s: &str, i: usize) -> (usize, usize) {
+/// Given an index into a string, return the line number and column
+/// count (both zero-indexed).
+pub fn position(s: &str, i: usize) -> (usize, usize) {
     let mut char_count = 0;
     for (line_idx, line) in s.split('\n').enumerate() {
         let line_length = line.len();
@@ -54,7 +63,7 @@ impl fmt::Display for Info {
 
         // Find line and column offsets, if we have an index.
         let offsets = match (&self.position, &self.source) {
-            (&Some(range), &Some(ref source)) => {
+            (Some(range), Some(source)) => {
                 debug_assert!(range.start <= range.end);
 
                 let (line_idx, column_idx) = position(source, range.start);
@@ -68,6 +77,14 @@ impl fmt::Display for Info {
         let level_text;
         let color;
         match self.level {
+            Level::Note => {
+                color = Green;
+                level_text = " note: ";
+            }
+            Level::Remark => {
+                color = Cyan;
+                level_text = " remark: ";
+            }
             Level::Warning => {
                 color = Purple;
                 level_text = " warning: ";
@@ -80,7 +97,7 @@ impl fmt::Display for Info {
 
         let mut context_line = "".to_owned();
         let mut caret_line = "".to_owned();
-        if let (Some((line_idx, column_idx, width)), &Some(ref source)) = (offsets, &self.source) {
+        if let (Some((line_idx, column_idx, width)), Some(source)) = (offsets, &self.source) {
             // The faulty line of code.
             let line = source.split('\n').nth(line_idx).unwrap();
             context_line = "\n".to_owned() + line;
@@ -91,6 +108,9 @@ impl fmt::Display for Info {
                 caret_line += " ";
             }
             caret_line += "^";
+            // Ranges that span several lines are only highlighted
+            // up to the end of the first line.
+            let width = width.min(line.len().saturating_sub(column_idx + 1));
             if width > 0 {
                 for _ in 0..width {
                     caret_line += "~";
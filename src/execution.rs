@@ -1,11 +1,14 @@
 #![warn(trivial_numeric_casts)]
 //! Compile time execution of BF programs.
+use std::io::{self, Read as _};
 use std::num::Wrapping;
+use std::time::{Duration, Instant};
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, Cell};
+use crate::bfir::{get_position, AstNode, Cell};
 
 use crate::diagnostics::Warning;
+use crate::peephole::modified_cells;
 
 #[cfg(test)]
 use crate::bounds::MAX_CELL_INDEX;
@@ -31,6 +34,39 @@ impl<'a> ExecutionState<'a> {
     }
 }
 
+/// Where `Read` instructions get their values from during compile
+/// time execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input<'i> {
+    /// The input is only known at runtime, so stop at the first read.
+    Runtime,
+    /// Every read returns this value (used in testing).
+    #[cfg(test)]
+    Dummy(i8),
+    /// Reads consume these bytes in order. Once they're exhausted,
+    /// reads leave the cell unchanged, as compiled programs do at EOF.
+    Bytes {
+        remaining: &'i [u8],
+        /// Did we execute a read after the last byte?
+        read_eof: bool,
+    },
+    /// Reads take bytes from stdin, when interpreting a program. At
+    /// EOF, reads leave the cell unchanged.
+    Stdin {
+        /// How many bytes we've read, so checkpoints can record it.
+        bytes_read: u64,
+    },
+}
+
+impl<'i> Input<'i> {
+    pub fn bytes(bytes: &'i [u8]) -> Self {
+        Input::Bytes {
+            remaining: bytes,
+            read_eof: false,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Outcome {
     // Return the number of steps remaining at completion.
@@ -38,14 +74,145 @@ pub enum Outcome {
     ReachedRuntimeValue,
     RuntimeError(Warning),
     OutOfSteps,
+    OutOfTime,
+    /// A loop returned to a state it was in before, so it will
+    /// never terminate.
+    InfiniteLoop(Warning),
+}
+
+/// How often we check whether we've reached the deadline, in steps.
+/// Checking the clock is much slower than executing a step.
+pub const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// The number of iterations a loop runs before we start checking
+/// whether it repeats itself. Most loops finish well before this.
+const REPETITION_CHECK_ITERATIONS: u64 = 1000;
+
+/// Watches the iterations of a loop, to spot loops that return to a
+/// state they were in before and so never terminate. We use Brent's
+/// algorithm: compare each iteration with a saved state, saving a
+/// new state after 1, 2, 4, ... iterations.
+struct RepetitionCheck {
+    /// The index of the loop we're watching.
+    instr_idx: usize,
+    iterations: u64,
+    /// The cells the loop body may change, relative to the cell
+    /// pointer, or None if we can't check this loop.
+    cells: Option<Vec<isize>>,
+    saved: Vec<Option<Cell>>,
+    /// The number of iterations until we save a new state, and the
+    /// number since we last saved one.
+    save_interval: u64,
+    since_saved: u64,
+}
+
+/// Does `instrs`, or any loop inside it, read input?
+fn reads_input(instrs: &[AstNode]) -> bool {
+    instrs.iter().any(|instr| match instr {
+        Read { .. } => true,
+        Loop { body, .. } | If { body, .. } => reads_input(body),
+        _ => false,
+    })
 }
 
-/// Compile time speculative execution of instructions. We return the
-/// final state of the cells, any print side effects, and the point in
-/// the code we reached.
-pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warning>) {
+impl RepetitionCheck {
+    fn new() -> Self {
+        RepetitionCheck {
+            instr_idx: usize::MAX,
+            iterations: 0,
+            cells: None,
+            saved: vec![],
+            save_interval: 1,
+            since_saved: 0,
+        }
+    }
+
+    fn values(cells: &[isize], state: &ExecutionState) -> Vec<Option<Cell>> {
+        cells.iter().map(|offset| cell_at(state, *offset)).collect()
+    }
+
+    /// Called before each iteration of the loop at `instr_idx`.
+    /// Returns the number of iterations that the loop repeats
+    /// forever, if it has returned to an earlier state.
+    fn repeats(
+        &mut self,
+        instr_idx: usize,
+        body: &[AstNode],
+        state: &ExecutionState,
+    ) -> Option<u64> {
+        if instr_idx != self.instr_idx {
+            *self = RepetitionCheck::new();
+            self.instr_idx = instr_idx;
+        }
+        self.iterations += 1;
+        if self.iterations < REPETITION_CHECK_ITERATIONS {
+            return None;
+        }
+        if self.iterations == REPETITION_CHECK_ITERATIONS {
+            // The state of the loop is the cells that its body
+            // changes. That's only true if the body doesn't move the
+            // pointer or read input.
+            self.cells = match modified_cells(body) {
+                Some((cells, 0)) if !reads_input(body) => Some(cells.into_iter().collect()),
+                _ => None,
+            };
+            if let Some(cells) = &self.cells {
+                self.saved = RepetitionCheck::values(cells, state);
+            }
+            return None;
+        }
+
+        let cells = self.cells.as_ref()?;
+        self.since_saved += 1;
+        let same_state = cells
+            .iter()
+            .zip(&self.saved)
+            .all(|(&offset, saved)| cell_at(state, offset) == *saved);
+        if same_state {
+            return Some(self.since_saved);
+        }
+        if self.since_saved == self.save_interval {
+            self.saved = RepetitionCheck::values(cells, state);
+            self.save_interval *= 2;
+            self.since_saved = 0;
+        }
+        None
+    }
+}
+
+/// The value of the cell at `offset` from the cell pointer, or None
+/// if it's out of bounds.
+fn cell_at(state: &ExecutionState, offset: isize) -> Option<Cell> {
+    let cell_index = state.cell_ptr + offset;
+    if cell_index < 0 {
+        return None;
+    }
+    state.cells.get(cell_index as usize).copied()
+}
+
+/// Compile time speculative execution of instructions, stopping at
+/// the first read.
+#[cfg(test)]
+pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState<'_>, Option<Warning>, u64) {
+    speculate(instrs, steps, None, &mut Input::Runtime)
+}
+
+/// Compile time speculative execution of instructions, taking the
+/// values of reads from `input`. We stop after `steps` steps, or
+/// after `timeout` if given. We return the final state of the cells,
+/// any print side effects, the point in the code we reached, and the
+/// number of steps we ran.
+pub fn speculate<'a>(
+    instrs: &'a [AstNode],
+    steps: u64,
+    timeout: Option<Duration>,
+    input: &mut Input,
+) -> (ExecutionState<'a>, Option<Warning>, u64) {
     let mut state = ExecutionState::initial(instrs);
-    let outcome = execute_with_state(instrs, &mut state, steps, None);
+    let mut steps_left = steps;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let outcome = execute_with_steps_left(instrs, &mut state, &mut steps_left, deadline, input);
+    let steps_used = steps - steps_left;
 
     // Sanity check: if we have a start instruction we
     // can't have executed the entire program at compile time.
@@ -54,9 +221,41 @@ pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warnin
         None => debug_assert!(matches!(outcome, Outcome::Completed(_))),
     }
 
-    match outcome {
-        Outcome::RuntimeError(warning) => (state, Some(warning)),
-        _ => (state, None),
+    // Tell the user where we stopped, so they know why the program
+    // wasn't fully evaluated.
+    let warning = match outcome {
+        Outcome::RuntimeError(warning) | Outcome::InfiniteLoop(warning) => Some(warning),
+        Outcome::OutOfSteps => Some(Warning {
+            message: format!(
+                "Speculative execution ran out of steps here (after {} steps), so the rest \
+                 of the program will run at runtime.",
+                steps_used
+            ),
+            position: state.start_instr.and_then(get_position),
+        }),
+        Outcome::OutOfTime => Some(Warning {
+            message: format!(
+                "Speculative execution timed out here (after {} steps), so the rest of \
+                 the program will run at runtime.",
+                steps_used
+            ),
+            position: state.start_instr.and_then(get_position),
+        }),
+        Outcome::Completed(_) | Outcome::ReachedRuntimeValue => None,
+    };
+    (state, warning, steps_used)
+}
+
+/// The offset of the cell that this instruction accesses, if it
+/// has one.
+fn cell_offset(instr: &AstNode) -> Option<isize> {
+    match *instr {
+        Increment { offset, .. }
+        | Set { offset, .. }
+        | Read { offset, .. }
+        | Write { offset, .. }
+        | Loop { offset, .. } => Some(offset),
+        _ => None,
     }
 }
 
@@ -65,17 +264,66 @@ pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warnin
 ///
 /// Execution also stops if we encounter a read instruction.  Users may
 /// alternatively pass in a dummy value for the read (used in testing).
+#[cfg(test)]
 pub fn execute_with_state<'a>(
     instrs: &'a [AstNode],
     state: &mut ExecutionState<'a>,
     steps: u64,
     dummy_read_value: Option<i8>,
+) -> Outcome {
+    let mut input = match dummy_read_value {
+        Some(read_value) => Input::Dummy(read_value),
+        None => Input::Runtime,
+    };
+    execute_with_input(instrs, state, steps, &mut input)
+}
+
+/// Execute the instructions given, taking the values of reads from
+/// `input`. To avoid infinite loops, stop execution after `steps`
+/// steps.
+pub fn execute_with_input<'a>(
+    instrs: &'a [AstNode],
+    state: &mut ExecutionState<'a>,
+    steps: u64,
+    input: &mut Input,
 ) -> Outcome {
     let mut steps_left = steps;
+    execute_with_steps_left(instrs, state, &mut steps_left, None, input)
+}
+
+/// Execute the instructions given, decrementing `steps_left` for
+/// each step we take. If we reach `deadline`, stop early.
+fn execute_with_steps_left<'a>(
+    instrs: &'a [AstNode],
+    state: &mut ExecutionState<'a>,
+    steps_left: &mut u64,
+    deadline: Option<Instant>,
+    input: &mut Input,
+) -> Outcome {
     let mut instr_idx = 0;
-    while instr_idx < instrs.len() && steps_left > 0 {
+    let mut repetition_check = RepetitionCheck::new();
+    while instr_idx < instrs.len() && *steps_left > 0 {
+        if let Some(deadline) = deadline {
+            if steps_left.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                state.start_instr = Some(&instrs[instr_idx]);
+                return Outcome::OutOfTime;
+            }
+        }
+
         let cell_ptr = state.cell_ptr as usize;
 
+        // Offsets may refer to a cell before cell #0.
+        if let Some(offset) = cell_offset(&instrs[instr_idx]) {
+            let target_cell_ptr = state.cell_ptr + offset;
+            if target_cell_ptr < 0 {
+                state.start_instr = Some(&instrs[instr_idx]);
+                return Outcome::RuntimeError(Warning {
+                    message: format!("This instruction accesses cell {}.", target_cell_ptr),
+                    position: get_position(&instrs[instr_idx]),
+                });
+            }
+        }
+
         match instrs[instr_idx] {
             Increment { amount, offset, .. } => {
                 let target_cell_ptr = (cell_ptr as isize + offset) as usize;
@@ -170,47 +418,153 @@ pub fn execute_with_state<'a>(
 
                 instr_idx += 1;
             }
-            Write { .. } => {
-                let cell_value = state.cells[state.cell_ptr as usize];
+            MultiplyAdd {
+                ref changes,
+                position,
+            } => {
+                let cell_value = state.cells[cell_ptr];
+
+                if cell_value.0 != 0 {
+                    // Check every cell is in bounds before we modify
+                    // any of them.
+                    for &(target, operand) in changes.keys() {
+                        for cell_offset in [target, operand] {
+                            let cell_index = cell_ptr as isize + cell_offset;
+                            if cell_index < 0 || cell_index >= state.cells.len() as isize {
+                                state.start_instr = Some(&instrs[instr_idx]);
+                                return Outcome::RuntimeError(Warning {
+                                    message: format!(
+                                        "This multiply loop tried to access cell {} \
+                                         (offset {} from current cell {})",
+                                        cell_index, cell_offset, cell_ptr
+                                    ),
+                                    position,
+                                });
+                            }
+                        }
+                    }
+
+                    // Products use the cell values from before this
+                    // instruction.
+                    let increases: Vec<_> = changes
+                        .iter()
+                        .map(|(&(target, operand), &factor)| {
+                            let operand_value = state.cells[(cell_ptr as isize + operand) as usize];
+                            (target, cell_value * operand_value * factor)
+                        })
+                        .collect();
+                    for (target, increase) in increases {
+                        state.cells[(cell_ptr as isize + target) as usize] += increase;
+                    }
+                }
+
+                instr_idx += 1;
+            }
+            Scan { stride, position } => {
+                // Each pointer movement counts as a step, so we can
+                // stop if the scan never finds a zero cell.
+                while state.cells[state.cell_ptr as usize].0 != 0 {
+                    if *steps_left <= 1 {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::OutOfSteps;
+                    }
+
+                    let new_cell_ptr = state.cell_ptr + stride;
+                    if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This loop moves the pointer to cell {} whilst \
+                                 searching for a zero cell.",
+                                new_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+
+                    state.cell_ptr = new_cell_ptr;
+                    *steps_left -= 1;
+                }
+                instr_idx += 1;
+            }
+            Write { offset, .. } => {
+                let cell_value = state.cells[(cell_ptr as isize + offset) as usize];
                 state.outputs.push(cell_value.0);
                 instr_idx += 1;
             }
-            Read { .. } => {
-                if let Some(read_value) = dummy_read_value {
-                    // If we're given a dummy value to use for the
-                    // read, pretend that we've read that value.
-                    state.cells[state.cell_ptr as usize] = Wrapping(read_value);
-                    instr_idx += 1
-                } else {
-                    // Otherwise, we cannot proceed at compile time,
-                    // so ensure runtime execution starts from here.
-                    state.start_instr = Some(&instrs[instr_idx]);
-                    return Outcome::ReachedRuntimeValue;
+            WriteConst { ref bytes, .. } => {
+                state.outputs.extend(bytes.iter().map(|&byte| byte as i8));
+                instr_idx += 1;
+            }
+            Read { offset, .. } => {
+                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
+                match input {
+                    Input::Runtime => {
+                        // We cannot proceed at compile time, so
+                        // ensure runtime execution starts from here.
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::ReachedRuntimeValue;
+                    }
+                    #[cfg(test)]
+                    Input::Dummy(read_value) => {
+                        // If we're given a dummy value to use for the
+                        // read, pretend that we've read that value.
+                        state.cells[target_cell_ptr] = Wrapping(*read_value);
+                    }
+                    Input::Bytes {
+                        remaining,
+                        read_eof,
+                    } => match remaining.split_first() {
+                        Some((&byte, rest)) => {
+                            state.cells[target_cell_ptr] = Wrapping(byte as i8);
+                            *remaining = rest;
+                        }
+                        None => *read_eof = true,
+                    },
+                    Input::Stdin { bytes_read } => {
+                        let mut byte = [0];
+                        if let Ok(1) = io::stdin().read(&mut byte) {
+                            state.cells[target_cell_ptr] = Wrapping(byte[0] as i8);
+                            *bytes_read += 1;
+                        }
+                    }
                 }
+                instr_idx += 1;
             }
-            Loop { ref body, .. } => {
-                if state.cells[state.cell_ptr as usize].0 == 0 {
+            Loop {
+                ref body,
+                offset,
+                position,
+            } => {
+                if state.cells[(cell_ptr as isize + offset) as usize].0 == 0 {
                     // Step over the loop because the current cell is
                     // zero.
                     instr_idx += 1;
+                } else if let Some(period) = repetition_check.repeats(instr_idx, body, state) {
+                    state.start_instr = Some(&instrs[instr_idx]);
+                    return Outcome::InfiniteLoop(Warning {
+                        message: format!(
+                            "This loop never terminates, because it returns to the same \
+                             state every {} iterations.",
+                            period
+                        ),
+                        position,
+                    });
                 } else {
                     // Execute the loop body.
                     let loop_outcome =
-                        execute_with_state(body, state, steps_left, dummy_read_value);
+                        execute_with_steps_left(body, state, steps_left, deadline, input);
                     match loop_outcome {
-                        Outcome::Completed(remaining_steps) => {
-                            // We've run several steps during the loop
-                            // body, so ensure steps_left reflects
-                            // that.
-                            steps_left = remaining_steps;
-                        }
+                        Outcome::Completed(_) => {}
                         Outcome::ReachedRuntimeValue
                         | Outcome::RuntimeError(..)
-                        | Outcome::OutOfSteps => {
+                        | Outcome::OutOfSteps
+                        | Outcome::OutOfTime
+                        | Outcome::InfiniteLoop(..) => {
                             // If we ran out of steps after a complete
                             // loop iteration, start_instr will still
                             // be None, so we set it to the current loop.
-                            if state.start_instr == None {
+                            if state.start_instr.is_none() {
                                 state.start_instr = Some(&instrs[instr_idx]);
                             }
                             return loop_outcome;
@@ -218,14 +572,39 @@ pub fn execute_with_state<'a>(
                     }
                 }
             }
+            If { ref body, .. } => {
+                if state.cells[state.cell_ptr as usize].0 != 0 {
+                    let body_outcome =
+                        execute_with_steps_left(body, state, steps_left, deadline, input);
+                    match body_outcome {
+                        Outcome::Completed(_) => {}
+                        Outcome::ReachedRuntimeValue
+                        | Outcome::RuntimeError(..)
+                        | Outcome::OutOfSteps
+                        | Outcome::OutOfTime
+                        | Outcome::InfiniteLoop(..) => {
+                            // If we ran out of steps at the end of the
+                            // body, restarting at the if is fine,
+                            // because the current cell is now zero.
+                            if state.start_instr.is_none() {
+                                state.start_instr = Some(&instrs[instr_idx]);
+                            }
+                            return body_outcome;
+                        }
+                    }
+                }
+                // The body always finishes on a zero cell, so we
+                // never run it a second time.
+                instr_idx += 1;
+            }
         }
 
-        steps_left -= 1;
+        *steps_left -= 1;
     }
 
     // If we've run out of steps, runtime execution should start
     // from the next instruction.
-    if steps_left == 0 {
+    if *steps_left == 0 {
         // If the next instruction is in the current loop, use that.
         if instr_idx < instrs.len() {
             state.start_instr = Some(&instrs[instr_idx]);
@@ -236,7 +615,7 @@ pub fn execute_with_state<'a>(
 
         Outcome::OutOfSteps
     } else {
-        Outcome::Completed(steps_left)
+        Outcome::Completed(*steps_left)
     }
 }
 
@@ -340,7 +719,7 @@ mod tests {
             position: None,
         }];
 
-        let (final_state, warning) = execute(&instrs, MAX_STEPS);
+        let (final_state, warning, _) = execute(&instrs, MAX_STEPS);
         pretty_assert_eq!(warning, None);
         pretty_assert_eq!(
             final_state,
@@ -440,6 +819,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn multiply_add_executed() {
+        let mut changes = HashMap::new();
+        changes.insert((2, 1), Wrapping(2));
+        changes.insert((3, 1), Wrapping(1));
+
+        let instrs = [
+            // Initial cells: [3, 5, 1, 0]
+            Set {
+                amount: Wrapping(3),
+                offset: 0,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(5),
+                offset: 1,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: 2,
+                position: None,
+            },
+            MultiplyAdd {
+                changes,
+                position: None,
+            },
+        ];
+
+        let final_state = execute(&instrs, MAX_STEPS).0;
+        pretty_assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(3), Wrapping(5), Wrapping(31), Wrapping(15)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn multiply_add_offset_too_low() {
+        let mut changes = HashMap::new();
+        changes.insert((1, -1), Wrapping(1));
+        let instrs = [
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            MultiplyAdd {
+                changes,
+                position: None,
+            },
+        ];
+
+        let (final_state, warning, _) = execute(&instrs, MAX_STEPS);
+        pretty_assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert!(warning.is_some());
+    }
+
     #[test]
     fn set_executed() {
         let instrs = [Set {
@@ -542,7 +983,7 @@ mod tests {
     #[test]
     fn ptr_out_of_range() {
         let instrs = parse("<").unwrap();
-        let (final_state, warning) = execute(&instrs, MAX_STEPS);
+        let (final_state, warning, _) = execute(&instrs, MAX_STEPS);
 
         pretty_assert_eq!(
             final_state,
@@ -573,6 +1014,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn read_write_with_offset_executed() {
+        let instrs = [
+            Read {
+                offset: 1,
+                position: None,
+            },
+            Write {
+                offset: 1,
+                position: None,
+            },
+        ];
+        let mut state = ExecutionState::initial(&instrs);
+        let outcome = execute_with_state(&instrs, &mut state, MAX_STEPS, Some(5));
+
+        assert!(matches!(outcome, Outcome::Completed(_)));
+        assert_eq!(state.cells, vec![Wrapping(0), Wrapping(5)]);
+        assert_eq!(state.outputs, vec![5]);
+    }
+
     #[test]
     fn write_executed() {
         let instrs = parse("+.").unwrap();
@@ -589,6 +1050,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn write_const_executed() {
+        let instrs = [WriteConst {
+            bytes: vec![b'h', b'i', 200],
+            position: None,
+        }];
+        let final_state = execute(&instrs, MAX_STEPS).0;
+
+        assert_eq!(final_state.cells, vec![Wrapping(0)]);
+        assert_eq!(
+            final_state.outputs,
+            vec![b'h' as i8, b'i' as i8, 200u8 as i8]
+        );
+    }
+
     #[test]
     fn loop_executed() {
         let instrs = parse("++[-]").unwrap();
@@ -625,6 +1101,7 @@ mod tests {
         pretty_assert_eq!(
             *start_instr,
             Read {
+                offset: 0,
                 position: Some(Position { start: 3, end: 3 })
             }
         );
@@ -661,6 +1138,41 @@ mod tests {
         assert!(matches!(outcome, Outcome::Completed(_)));
     }
 
+    #[test]
+    fn speculate_with_input_bytes() {
+        let instrs = parse(",.,.").unwrap();
+        let mut input = Input::bytes(b"hi!");
+        let (final_state, warning, _) = speculate(&instrs, MAX_STEPS, None, &mut input);
+
+        assert_eq!(warning, None);
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.outputs, vec![b'h' as i8, b'i' as i8]);
+        assert_eq!(
+            input,
+            Input::Bytes {
+                remaining: b"!",
+                read_eof: false
+            }
+        );
+    }
+
+    /// Reading after the end of the input leaves the cell unchanged.
+    #[test]
+    fn speculate_reads_past_input() {
+        let instrs = parse(",+,.").unwrap();
+        let mut input = Input::bytes(b"a");
+        let (final_state, _, _) = speculate(&instrs, MAX_STEPS, None, &mut input);
+
+        assert_eq!(final_state.outputs, vec![b'b' as i8]);
+        assert_eq!(
+            input,
+            Input::Bytes {
+                remaining: b"",
+                read_eof: true
+            }
+        );
+    }
+
     /// Ensure that we have the correct InstrPosition when we finish
     /// executing a top-level loop.
     #[test]
@@ -700,6 +1212,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn detect_repeating_loop() {
+        // The loop's cell keeps its value, and the next cell cycles
+        // through all 256 values.
+        let instrs = parse("+[->+<+]").unwrap();
+        let (state, warning, _) = execute(&instrs, 1_000_000);
+
+        pretty_assert_eq!(state.start_instr, Some(&instrs[1]));
+        let warning = warning.unwrap();
+        pretty_assert_eq!(warning.position, Some(Position { start: 1, end: 7 }));
+        assert!(warning.message.contains("every 256 iterations"));
+    }
+
+    #[test]
+    fn repeating_loop_with_input_is_not_detected() {
+        let instrs = parse("+[,+]").unwrap();
+        let mut state = ExecutionState::initial(&instrs);
+        let outcome = execute_with_state(&instrs, &mut state, 100_000, Some(0));
+        pretty_assert_eq!(outcome, Outcome::OutOfSteps);
+    }
+
     #[test]
     fn loop_up_to_step_limit() {
         let instrs = parse("++[-]").unwrap();
@@ -733,6 +1266,7 @@ mod tests {
         pretty_assert_eq!(
             *start_instr,
             Read {
+                offset: 0,
                 position: Some(Position { start: 3, end: 3 })
             }
         );
@@ -764,6 +1298,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn out_of_steps_warning() {
+        let instrs = parse("++[]").unwrap();
+        let (_, warning, steps) = execute(&instrs, 20);
+
+        assert_eq!(steps, 20);
+        assert_eq!(
+            warning,
+            Some(Warning {
+                message: "Speculative execution ran out of steps here (after 20 steps), so \
+                          the rest of the program will run at runtime."
+                    .to_owned(),
+                position: Some(Position { start: 2, end: 3 }),
+            })
+        );
+    }
+
+    #[test]
+    fn timeout_stops_execution() {
+        let instrs = parse("+[]").unwrap();
+        let (final_state, warning, _) = speculate(
+            &instrs,
+            DEADLINE_CHECK_INTERVAL * 10,
+            Some(Duration::from_secs(0)),
+            &mut Input::Runtime,
+        );
+
+        assert_eq!(final_state.start_instr, Some(&instrs[0]));
+        assert!(warning.unwrap().message.contains("timed out"));
+    }
+
     #[test]
     fn up_to_nonempty_infinite_loop() {
         let instrs = parse("+[+]").unwrap();
@@ -780,6 +1345,200 @@ mod tests {
         );
     }
 
+    #[test]
+    fn scan_executed() {
+        let instrs = [
+            Set {
+                amount: Wrapping(1),
+                offset: 2,
+                position: None,
+            },
+            PointerIncrement {
+                amount: 4,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            Scan {
+                stride: -2,
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, MAX_STEPS).0;
+
+        pretty_assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![
+                    Wrapping(0),
+                    Wrapping(0),
+                    Wrapping(1),
+                    Wrapping(0),
+                    Wrapping(1)
+                ],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn scan_on_zero_cell() {
+        let instrs = [Scan {
+            stride: 1,
+            position: None,
+        }];
+        let (final_state, warning, _) = execute(&instrs, MAX_STEPS);
+
+        assert_eq!(warning, None);
+        assert_eq!(final_state.cell_ptr, 0);
+        assert_eq!(final_state.start_instr, None);
+    }
+
+    #[test]
+    fn loop_with_offset_executed() {
+        // Equivalent to >++[-<+>]
+        let instrs = [
+            Set {
+                amount: Wrapping(2),
+                offset: 1,
+                position: None,
+            },
+            Loop {
+                body: vec![
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 1,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(1),
+                        offset: 0,
+                        position: None,
+                    },
+                ],
+                offset: 1,
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, MAX_STEPS).0;
+
+        pretty_assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(2), Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn loop_offset_too_low() {
+        let instrs = [Loop {
+            body: vec![],
+            offset: -1,
+            position: None,
+        }];
+        let (final_state, warning, _) = execute(&instrs, MAX_STEPS);
+
+        assert_eq!(final_state.start_instr, Some(&instrs[0]));
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn increment_offset_too_low() {
+        let instrs = [Increment {
+            amount: Wrapping(1),
+            offset: -1,
+            position: None,
+        }];
+        let (final_state, warning, _) = execute(&instrs, MAX_STEPS);
+
+        assert_eq!(final_state.start_instr, Some(&instrs[0]));
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn if_executed() {
+        let instrs = [
+            Set {
+                amount: Wrapping(3),
+                offset: 0,
+                position: None,
+            },
+            If {
+                body: parse(">++<[-]").unwrap(),
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, MAX_STEPS).0;
+
+        pretty_assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(0), Wrapping(2)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn if_on_zero_cell() {
+        let instrs = [If {
+            body: parse(">+<").unwrap(),
+            position: None,
+        }];
+        let final_state = execute(&instrs, MAX_STEPS).0;
+
+        assert_eq!(final_state.cells, vec![Wrapping(0), Wrapping(0)]);
+        assert_eq!(final_state.start_instr, None);
+    }
+
+    #[test]
+    fn scan_out_of_range() {
+        let instrs = [
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            Scan {
+                stride: -1,
+                position: Some(Position { start: 1, end: 3 }),
+            },
+        ];
+        let (final_state, warning, _) = execute(&instrs, MAX_STEPS);
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn scan_up_to_step_limit() {
+        let instrs = [
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            Scan {
+                stride: 0,
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, 100).0;
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+    }
+
     #[test]
     fn quickcheck_cell_ptr_in_bounds() {
         fn cell_ptr_in_bounds(instrs: Vec<AstNode>) -> bool {
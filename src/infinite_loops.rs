@@ -0,0 +1,190 @@
+//! Find loops that can never terminate.
+//!
+//! If a loop body always returns to the loop's cell and never changes
+//! it, the loop runs forever once it starts. We track the cell values
+//! we know, so we can tell whether such a loop starts (as in `+[]`),
+//! or may start (as in `,[.]`). Loops on a cell that we know is zero
+//! never start, so we ignore them: `[comments]` at the start of a
+//! program are fine.
+
+use std::num::Wrapping;
+
+use crate::bfir::AstNode;
+use crate::bfir::AstNode::*;
+use crate::diagnostics::Warning;
+use crate::peephole::{modified_cells, KnownCells};
+
+/// Warnings for every loop in `instrs` that can never terminate.
+pub fn find_infinite_loops(instrs: &[AstNode]) -> Vec<Warning> {
+    let mut warnings = vec![];
+    find_infinite_loops_inner(instrs, &mut KnownCells::zeroed(), &mut warnings);
+    warnings
+}
+
+/// Does `body` always return to the loop's cell, at `offset`, without
+/// changing it?
+fn never_changes_cell(body: &[AstNode], offset: isize) -> bool {
+    match modified_cells(body) {
+        Some((modified, 0)) => !modified.contains(&offset),
+        _ => false,
+    }
+}
+
+/// Update `known` for the cell values after a loop or if with `body`
+/// that finishes on the cell at `offset`, and return the values we
+/// know inside the body.
+fn leave_body(body: &[AstNode], offset: isize, known: &mut KnownCells) -> KnownCells {
+    let mut body_known = match modified_cells(body) {
+        Some((modified, 0)) => {
+            for cell in modified {
+                known.set(cell, None);
+            }
+            known.clone()
+        }
+        _ => {
+            *known = KnownCells::unknown();
+            KnownCells::unknown()
+        }
+    };
+    known.set(offset, Some(Wrapping(0)));
+    // The cell is non-zero when the body starts, but we don't know
+    // its value.
+    body_known.set(offset, None);
+    body_known
+}
+
+fn find_infinite_loops_inner(
+    instrs: &[AstNode],
+    known: &mut KnownCells,
+    warnings: &mut Vec<Warning>,
+) {
+    for instr in instrs {
+        match instr {
+            Increment { amount, offset, .. } => {
+                let value = known.get(*offset).map(|value| value + *amount);
+                known.set(*offset, value);
+            }
+            Set { amount, offset, .. } => known.set(*offset, Some(*amount)),
+            PointerIncrement { amount, .. } => known.shift(*amount),
+            Read { offset, .. } => known.set(*offset, None),
+            Write { .. } | WriteConst { .. } => {}
+            MultiplyMove { changes, .. } => {
+                for &target in changes.keys() {
+                    known.set(target, None);
+                }
+                known.set(0, Some(Wrapping(0)));
+            }
+            MultiplyAdd { changes, .. } => {
+                for &(target, _) in changes.keys() {
+                    known.set(target, None);
+                }
+            }
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
+                let start_value = known.get(*offset);
+                if start_value == Some(Wrapping(0)) {
+                    continue;
+                }
+
+                if never_changes_cell(body, *offset) {
+                    let message = if start_value.is_some() {
+                        "This loop never terminates, because its body always returns to the \
+                         loop's cell without changing it."
+                    } else {
+                        "If this loop starts, it never terminates, because its body always \
+                         returns to the loop's cell without changing it."
+                    };
+                    warnings.push(Warning {
+                        message: message.to_owned(),
+                        position: *position,
+                    });
+                    // Don't warn about loops inside this one too.
+                    leave_body(body, *offset, known);
+                    continue;
+                }
+
+                let mut body_known = leave_body(body, *offset, known);
+                find_infinite_loops_inner(body, &mut body_known, warnings);
+            }
+            If { body, .. } => {
+                if known.get(0) == Some(Wrapping(0)) {
+                    continue;
+                }
+                let mut body_known = leave_body(body, 0, known);
+                find_infinite_loops_inner(body, &mut body_known, warnings);
+            }
+            Scan { .. } => {
+                *known = KnownCells::unknown();
+                known.set(0, Some(Wrapping(0)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::{parse, Position};
+    use crate::peephole::optimize;
+    use pretty_assertions::assert_eq;
+
+    fn infinite_loop_positions(source: &str) -> Vec<Option<Position>> {
+        let instrs = parse(source).unwrap();
+        find_infinite_loops(&instrs)
+            .into_iter()
+            .map(|warning| warning.position)
+            .collect()
+    }
+
+    #[test]
+    fn empty_loop_on_nonzero_cell() {
+        assert_eq!(
+            infinite_loop_positions("+[]"),
+            vec![Some(Position { start: 1, end: 2 })]
+        );
+    }
+
+    #[test]
+    fn loop_on_zero_cell_is_fine() {
+        assert_eq!(infinite_loop_positions("[]+[-][.]"), vec![]);
+    }
+
+    #[test]
+    fn body_returns_to_unchanged_cell() {
+        assert_eq!(
+            infinite_loop_positions("+[>+<.]"),
+            vec![Some(Position { start: 1, end: 6 })]
+        );
+        // These bodies change the cell or move.
+        assert_eq!(infinite_loop_positions("+[>+<-]+[>]+[,]"), vec![]);
+    }
+
+    #[test]
+    fn loop_may_start() {
+        let instrs = parse(",[.]").unwrap();
+        let warnings = find_infinite_loops(&instrs);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].message.starts_with("If this loop starts"));
+    }
+
+    #[test]
+    fn nested_infinite_loop() {
+        assert_eq!(
+            infinite_loop_positions("+[->+[]<]"),
+            vec![Some(Position { start: 5, end: 6 })]
+        );
+    }
+
+    #[test]
+    fn optimised_loops() {
+        let instrs = optimize(parse("+>+<[->[-]+<]>[]").unwrap(), &None).0;
+        let warnings = find_infinite_loops(&instrs);
+        assert_eq!(
+            warnings.into_iter().map(|w| w.position).collect::<Vec<_>>(),
+            vec![Some(Position { start: 14, end: 15 })]
+        );
+    }
+}
@@ -0,0 +1,556 @@
+//! Step-by-step execution of BF programs, for debugging.
+//!
+//! The executor in `execution` runs a whole program in one go. Here
+//! we keep track of where we are in the program, so execution can be
+//! paused after any instruction and resumed later.
+
+use std::collections::VecDeque;
+use std::slice;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Cell};
+use crate::diagnostics::Warning;
+use crate::execution::{execute_with_input, ExecutionState, Input, Outcome};
+use crate::snapshot::Snapshot;
+
+pub struct Interpreter<'a> {
+    instrs: &'a [AstNode],
+    /// The index of the next instruction in each enclosing loop
+    /// body, starting with the top level.
+    path: Vec<usize>,
+    /// The body that each index in `path` is in, so we don't have to
+    /// follow the path on every step.
+    bodies: Vec<&'a [AstNode]>,
+    /// The cells, pointer and outputs so far. `start_instr` is the
+    /// next instruction to execute.
+    pub state: ExecutionState<'a>,
+    /// The number of instructions executed so far.
+    pub steps: u64,
+    /// How to undo recent steps, most recent last, if we're
+    /// recording history.
+    history: Option<VecDeque<Undo>>,
+}
+
+/// The number of steps we can undo.
+const MAX_HISTORY: usize = 1_000_000;
+
+/// How to undo one step. Most steps only change a cell or the
+/// pointer, so we don't store the whole state.
+#[derive(Debug)]
+struct Undo {
+    /// The path before the step, unless the step just moved to the
+    /// next instruction in the same body.
+    path: Option<Vec<usize>>,
+    cell_ptr: isize,
+    /// The previous values of the cells that the step changed.
+    cells: Vec<(usize, Cell)>,
+    outputs_len: usize,
+}
+
+/// The cells that `instr` may write to, when the pointer is at
+/// `cell_ptr`.
+fn written_cells(instr: &AstNode, cell_ptr: isize) -> Vec<isize> {
+    match instr {
+        Increment { offset, .. } | Set { offset, .. } | Read { offset, .. } => {
+            vec![cell_ptr + offset]
+        }
+        MultiplyMove { changes, .. } => {
+            let mut cells: Vec<_> = changes.keys().map(|offset| cell_ptr + offset).collect();
+            cells.push(cell_ptr);
+            cells
+        }
+        MultiplyAdd { changes, .. } => changes
+            .keys()
+            .map(|(target, _)| cell_ptr + target)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The cell at `offset` from the pointer, if it's in bounds.
+fn cell_mut<'s>(state: &'s mut ExecutionState, offset: isize) -> Option<&'s mut Cell> {
+    let cell_index = state.cell_ptr + offset;
+    if cell_index < 0 {
+        return None;
+    }
+    state.cells.get_mut(cell_index as usize)
+}
+
+/// The instructions inside `instr`, if it has any.
+fn body(instr: &AstNode) -> &[AstNode] {
+    match instr {
+        Loop { body, .. } | If { body, .. } => body,
+        _ => &[],
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(instrs: &'a [AstNode]) -> Self {
+        let mut state = ExecutionState::initial(instrs);
+        state.start_instr = instrs.first();
+        Interpreter {
+            instrs,
+            path: vec![0],
+            bodies: vec![instrs],
+            state,
+            steps: 0,
+            history: None,
+        }
+    }
+
+    /// Resume execution from `snapshot`, which must have been taken
+    /// while running `instrs`.
+    pub fn from_snapshot(instrs: &'a [AstNode], snapshot: &Snapshot) -> Result<Self, String> {
+        let mut interpreter = Interpreter::new(instrs);
+        if snapshot.cells.len() != interpreter.state.cells.len() {
+            return Err("This snapshot is for a different program.".to_owned());
+        }
+
+        // Check that the path leads to an instruction (or the end of
+        // the program), entering only loops and ifs.
+        let invalid_path = || Err("This snapshot is for a different program.".to_owned());
+        let (&last_index, enclosing_indexes) = match snapshot.path.split_last() {
+            Some(indexes) => indexes,
+            None => return invalid_path(),
+        };
+        let mut body_instrs = instrs;
+        for &index in enclosing_indexes {
+            match body_instrs.get(index) {
+                Some(instr @ Loop { .. }) | Some(instr @ If { .. }) => body_instrs = body(instr),
+                _ => return invalid_path(),
+            }
+        }
+        if last_index > body_instrs.len()
+            || (last_index == body_instrs.len() && !enclosing_indexes.is_empty())
+        {
+            return invalid_path();
+        }
+        if snapshot.cell_ptr < 0 || snapshot.cell_ptr >= snapshot.cells.len() as isize {
+            return Err(format!(
+                "The pointer in this snapshot is at cell {}.",
+                snapshot.cell_ptr
+            ));
+        }
+
+        interpreter.set_path(snapshot.path.clone());
+        interpreter.steps = snapshot.steps;
+        interpreter.state.cells = snapshot.cells.clone();
+        interpreter.state.cell_ptr = snapshot.cell_ptr;
+        interpreter.state.outputs = snapshot.outputs.clone();
+        interpreter.state.start_instr = interpreter.current_instr();
+        Ok(interpreter)
+    }
+
+    /// An owned copy of the current state, which we can save and
+    /// resume later. `program_id` identifies the program we're
+    /// running, and `input_offset` is how many bytes it has read.
+    pub fn snapshot(&self, program_id: u64, input_offset: u64) -> Snapshot {
+        Snapshot {
+            program_id,
+            steps: self.steps,
+            path: self.path.clone(),
+            cell_ptr: self.state.cell_ptr,
+            input_offset,
+            cells: self.state.cells.clone(),
+            outputs: self.state.outputs.clone(),
+        }
+    }
+
+    /// Record each step, so we can step backwards.
+    pub fn with_history(mut self) -> Self {
+        self.history = Some(VecDeque::new());
+        self
+    }
+
+    /// The instructions in the innermost loop body we're executing.
+    fn enclosing_instrs(&self) -> &'a [AstNode] {
+        self.bodies.last().unwrap()
+    }
+
+    /// Move to `path`, which must lead to an instruction or the end
+    /// of a body.
+    fn set_path(&mut self, path: Vec<usize>) {
+        self.bodies = vec![self.instrs];
+        for &index in &path[..path.len() - 1] {
+            let instrs = self.enclosing_instrs();
+            self.bodies.push(body(&instrs[index]));
+        }
+        self.path = path;
+    }
+
+    /// The instruction that we will execute next, or None if the
+    /// program has finished.
+    pub fn current_instr(&self) -> Option<&'a AstNode> {
+        self.enclosing_instrs().get(*self.path.last().unwrap())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.current_instr().is_none()
+    }
+
+    /// Execute the next instruction, taking the values of reads from
+    /// `input`. A loop counts as an instruction each time we test
+    /// its cell. If the instruction fails (e.g. it moves the pointer
+    /// out of bounds), we don't move past it.
+    pub fn step(&mut self, input: &mut Input) -> Result<(), Warning> {
+        let instr = match self.current_instr() {
+            Some(instr) => instr,
+            None => return Ok(()),
+        };
+        if self.history.is_none() {
+            return self.execute(instr, input);
+        }
+
+        let old_path = self.path.clone();
+        let cell_ptr = self.state.cell_ptr;
+        let outputs_len = self.state.outputs.len();
+        let old_cells: Vec<(usize, Cell)> = written_cells(instr, cell_ptr)
+            .into_iter()
+            .filter(|&cell_index| cell_index >= 0 && cell_index < self.state.cells.len() as isize)
+            .map(|cell_index| (cell_index as usize, self.state.cells[cell_index as usize]))
+            .collect();
+
+        if let Err(warning) = self.execute(instr, input) {
+            // Instructions can fail part way through, so put
+            // everything back.
+            self.state.cell_ptr = cell_ptr;
+            for &(cell_index, value) in &old_cells {
+                self.state.cells[cell_index] = value;
+            }
+            self.state.outputs.truncate(outputs_len);
+            return Err(warning);
+        }
+
+        let mut advanced_path = old_path.clone();
+        *advanced_path.last_mut().unwrap() += 1;
+        let undo = Undo {
+            path: if self.path == advanced_path {
+                None
+            } else {
+                Some(old_path)
+            },
+            cell_ptr,
+            cells: old_cells
+                .into_iter()
+                .filter(|&(cell_index, value)| self.state.cells[cell_index] != value)
+                .collect(),
+            outputs_len,
+        };
+
+        let history = self.history.as_mut().unwrap();
+        if history.len() == MAX_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(undo);
+        Ok(())
+    }
+
+    /// Undo the most recent step. Returns false if there are no steps
+    /// we can undo.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(|history| history.pop_back()) {
+            Some(undo) => undo,
+            None => return false,
+        };
+
+        match undo.path {
+            Some(path) => self.set_path(path),
+            None => *self.path.last_mut().unwrap() -= 1,
+        }
+        self.state.cell_ptr = undo.cell_ptr;
+        for (cell_index, value) in undo.cells {
+            self.state.cells[cell_index] = value;
+        }
+        self.state.outputs.truncate(undo.outputs_len);
+        self.steps -= 1;
+        self.state.start_instr = self.current_instr();
+        true
+    }
+
+    /// Execute `instr`, which must be the next instruction.
+    fn execute(&mut self, instr: &'a AstNode, input: &mut Input) -> Result<(), Warning> {
+        match instr {
+            Loop { offset, .. } => {
+                if self.cell_is_nonzero(*offset, instr)? {
+                    self.enter_body(instr);
+                } else {
+                    *self.path.last_mut().unwrap() += 1;
+                }
+            }
+            If { .. } => {
+                if self.cell_is_nonzero(0, instr)? {
+                    self.enter_body(instr);
+                } else {
+                    *self.path.last_mut().unwrap() += 1;
+                }
+            }
+            _ if self.execute_simple(instr) => {
+                *self.path.last_mut().unwrap() += 1;
+            }
+            _ => {
+                self.execute_with_executor(instr, input)?;
+                *self.path.last_mut().unwrap() += 1;
+            }
+        }
+
+        self.steps += 1;
+        self.leave_finished_bodies();
+        self.state.start_instr = self.current_instr();
+        Ok(())
+    }
+
+    /// Execute `instr` with `execute_with_input`, which handles every
+    /// instruction and reports errors.
+    fn execute_with_executor(
+        &mut self,
+        instr: &'a AstNode,
+        input: &mut Input,
+    ) -> Result<(), Warning> {
+        let outcome = execute_with_input(slice::from_ref(instr), &mut self.state, u64::MAX, input);
+        self.state.start_instr = Some(instr);
+        match outcome {
+            Outcome::Completed(_) => Ok(()),
+            Outcome::RuntimeError(warning) => Err(warning),
+            Outcome::ReachedRuntimeValue => Err(Warning {
+                message: "This instruction reads input, but no input was given.".to_owned(),
+                position: get_position(instr),
+            }),
+            Outcome::OutOfSteps | Outcome::OutOfTime | Outcome::InfiniteLoop(_) => unreachable!(),
+        }
+    }
+
+    /// Execute the common instructions directly, which is much faster
+    /// than going through `execute_with_input`. Returns false if we
+    /// didn't execute `instr`, e.g. because it accesses a cell out of
+    /// bounds and the executor should report the error.
+    fn execute_simple(&mut self, instr: &AstNode) -> bool {
+        let state = &mut self.state;
+        match *instr {
+            Increment { amount, offset, .. } => match cell_mut(state, offset) {
+                Some(cell) => *cell += amount,
+                None => return false,
+            },
+            Set { amount, offset, .. } => match cell_mut(state, offset) {
+                Some(cell) => *cell = amount,
+                None => return false,
+            },
+            PointerIncrement { amount, .. } => {
+                let new_cell_ptr = state.cell_ptr + amount;
+                if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
+                    return false;
+                }
+                state.cell_ptr = new_cell_ptr;
+            }
+            Write { offset, .. } => match cell_mut(state, offset) {
+                Some(cell) => {
+                    let value = cell.0;
+                    state.outputs.push(value);
+                }
+                None => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Is the cell at `offset` from the pointer non-zero?
+    fn cell_is_nonzero(&self, offset: isize, instr: &AstNode) -> Result<bool, Warning> {
+        let cell_index = self.state.cell_ptr + offset;
+        if cell_index < 0 || cell_index >= self.state.cells.len() as isize {
+            return Err(Warning {
+                message: format!("This loop tests cell {}.", cell_index),
+                position: get_position(instr),
+            });
+        }
+        Ok(self.state.cells[cell_index as usize].0 != 0)
+    }
+
+    /// Start executing the body of `instr`, a loop or if.
+    fn enter_body(&mut self, instr: &'a AstNode) {
+        self.path.push(0);
+        self.bodies.push(body(instr));
+    }
+
+    /// If we've reached the end of a loop body, go back to the loop
+    /// so we test its cell again. If we've reached the end of an if
+    /// body, continue after the if.
+    fn leave_finished_bodies(&mut self) {
+        while self.path.len() > 1 && *self.path.last().unwrap() == self.enclosing_instrs().len() {
+            self.path.pop();
+            self.bodies.pop();
+            let parent_index = *self.path.last().unwrap();
+            if let If { .. } = self.enclosing_instrs()[parent_index] {
+                *self.path.last_mut().unwrap() += 1;
+            }
+        }
+    }
+
+    /// Run until the program finishes, returning the first error.
+    #[cfg(test)]
+    pub fn run(&mut self, input: &mut Input) -> Result<(), Warning> {
+        while !self.is_finished() {
+            self.step(input)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::execution::execute_with_state;
+    use crate::peephole::optimize;
+    use pretty_assertions::assert_eq;
+    use quickcheck::quickcheck;
+    use std::num::Wrapping;
+
+    #[test]
+    fn step_through_loop() {
+        let instrs = parse("++[-]").unwrap();
+        let mut interpreter = Interpreter::new(&instrs);
+
+        interpreter.step(&mut Input::Runtime).unwrap();
+        interpreter.step(&mut Input::Runtime).unwrap();
+        assert_eq!(interpreter.current_instr(), Some(&instrs[2]));
+
+        // Entering the loop, then each iteration decrements and
+        // returns to the loop test.
+        interpreter.step(&mut Input::Runtime).unwrap();
+        interpreter.step(&mut Input::Runtime).unwrap();
+        assert_eq!(interpreter.current_instr(), Some(&instrs[2]));
+        assert_eq!(interpreter.state.cells[0], Wrapping(1));
+
+        interpreter.run(&mut Input::Runtime).unwrap();
+        assert_eq!(interpreter.state.cells[0], Wrapping(0));
+        assert_eq!(interpreter.steps, 7);
+        assert_eq!(interpreter.state.start_instr, None);
+    }
+
+    #[test]
+    fn step_reads_input() {
+        let instrs = parse(",.,.").unwrap();
+        let mut interpreter = Interpreter::new(&instrs);
+
+        interpreter.run(&mut Input::bytes(b"a")).unwrap();
+        assert_eq!(interpreter.state.outputs, vec![b'a' as i8, b'a' as i8]);
+    }
+
+    #[test]
+    fn step_error_stays_on_instruction() {
+        let instrs = parse("+<").unwrap();
+        let mut interpreter = Interpreter::new(&instrs);
+
+        assert!(interpreter.run(&mut Input::Runtime).is_err());
+        assert_eq!(interpreter.current_instr(), Some(&instrs[1]));
+        assert!(interpreter.step(&mut Input::Runtime).is_err());
+    }
+
+    #[test]
+    fn step_optimised_if() {
+        let instrs = optimize(parse("+[>+<-]>.").unwrap(), &None).0;
+        let mut interpreter = Interpreter::new(&instrs);
+
+        interpreter.run(&mut Input::Runtime).unwrap();
+        assert_eq!(interpreter.state.outputs, vec![1]);
+    }
+
+    #[test]
+    fn step_back_restores_state() {
+        let instrs = parse("+>++[-<+>]<.").unwrap();
+        let mut interpreter = Interpreter::new(&instrs).with_history();
+        let mut states = vec![];
+        while !interpreter.is_finished() {
+            states.push((interpreter.state.clone(), interpreter.current_instr()));
+            interpreter.step(&mut Input::Runtime).unwrap();
+        }
+
+        while let Some((state, instr)) = states.pop() {
+            assert!(interpreter.step_back());
+            assert_eq!(interpreter.state, state);
+            assert_eq!(interpreter.current_instr(), instr);
+        }
+        assert!(!interpreter.step_back());
+        assert_eq!(interpreter.steps, 0);
+    }
+
+    #[test]
+    fn step_back_from_error() {
+        let instrs = parse("+[<]").unwrap();
+        let mut interpreter = Interpreter::new(&instrs).with_history();
+
+        assert!(interpreter.run(&mut Input::Runtime).is_err());
+        assert_eq!(interpreter.state.cell_ptr, 0);
+
+        // Step back over the loop test, to before the pointer moved.
+        assert!(interpreter.step_back());
+        assert_eq!(interpreter.current_instr(), Some(&instrs[1]));
+        assert!(interpreter.step_back());
+        assert_eq!(interpreter.state.cells[0], Wrapping(0));
+    }
+
+    #[test]
+    fn resume_from_snapshot() {
+        let instrs = parse("+++[>++[>+<-]<-]>>.").unwrap();
+        let mut interpreter = Interpreter::new(&instrs);
+        for _ in 0..12 {
+            interpreter.step(&mut Input::Runtime).unwrap();
+        }
+        let snapshot = Snapshot::from_text(&interpreter.snapshot(1, 0).to_text()).unwrap();
+        interpreter.run(&mut Input::Runtime).unwrap();
+
+        let mut resumed = Interpreter::from_snapshot(&instrs, &snapshot).unwrap();
+        assert_eq!(resumed.steps, 12);
+        resumed.run(&mut Input::Runtime).unwrap();
+
+        assert_eq!(resumed.steps, interpreter.steps);
+        assert_eq!(resumed.state, interpreter.state);
+    }
+
+    #[test]
+    fn reject_snapshot_for_other_program() {
+        let instrs = parse("+[-]").unwrap();
+        let mut snapshot = Interpreter::new(&instrs).snapshot(1, 0);
+
+        snapshot.path = vec![0, 0];
+        assert!(Interpreter::from_snapshot(&instrs, &snapshot).is_err());
+        snapshot.path = vec![1, 1];
+        assert!(Interpreter::from_snapshot(&instrs, &snapshot).is_err());
+        snapshot.path = vec![2];
+        assert!(Interpreter::from_snapshot(&instrs, &snapshot).is_ok());
+        snapshot.path = vec![3];
+        assert!(Interpreter::from_snapshot(&instrs, &snapshot).is_err());
+    }
+
+    #[test]
+    fn failed_step_changes_nothing() {
+        // The multiply-move may update cell #1 before it finds that
+        // cell #-1 is out of bounds.
+        let instrs = optimize(parse(",[->+<<+>]>.").unwrap(), &None).0;
+        let mut interpreter = Interpreter::new(&instrs).with_history();
+
+        assert!(interpreter.run(&mut Input::bytes(b"a")).is_err());
+        assert_eq!(interpreter.state.cells[0], Wrapping(b'a' as i8));
+        assert_eq!(interpreter.state.cells[1], Wrapping(0));
+    }
+
+    fn stepping_matches_execution(instrs: Vec<AstNode>, read_value: i8) -> bool {
+        let mut state = ExecutionState::initial(&instrs);
+        let outcome = execute_with_state(&instrs, &mut state, 1000, Some(read_value));
+        if !matches!(outcome, Outcome::Completed(_)) {
+            return true;
+        }
+
+        let mut interpreter = Interpreter::new(&instrs);
+        let bytes = vec![read_value as u8; 1000];
+        interpreter.run(&mut Input::bytes(&bytes)).unwrap();
+        interpreter.state.cells == state.cells
+            && interpreter.state.cell_ptr == state.cell_ptr
+            && interpreter.state.outputs == state.outputs
+    }
+
+    #[test]
+    fn quickcheck_stepping_matches_execution() {
+        quickcheck(stepping_matches_execution as fn(Vec<AstNode>, i8) -> bool);
+    }
+}
@@ -5,6 +5,9 @@
 #![allow(unknown_lints)]
 // TODO: enable this warning and cleanup.
 #![allow(option_unwrap_used)]
+// itertools::coalesce requires us to return both instructions in the
+// Err case, so this lint is unavoidable in our peephole passes.
+#![allow(clippy::result_large_err)]
 
 //! bfc is a highly optimising compiler for BF.
 
@@ -21,20 +24,36 @@ extern crate tempfile;
 #[macro_use]
 extern crate matches;
 
+use structopt::clap::{Error, ErrorKind};
 use structopt::StructOpt;
 
 use crate::diagnostics::{Info, Level};
+use crate::stats::{SpeculationStats, Stats};
 
 use std::fs::File;
 use std::io::prelude::Read;
+use std::io::{self, BufWriter, Write};
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::{Duration, Instant};
 
+mod bf;
 mod bfir;
 mod bounds;
+mod coverage;
+mod debugger;
 mod diagnostics;
 mod execution;
+mod infinite_loops;
+mod interpreter;
+mod partial_eval;
 mod peephole;
+mod print_ir;
+mod profile;
+mod snapshot;
+mod stats;
+mod trace;
+mod verify;
 
 mod c;
 
@@ -45,42 +64,66 @@ mod soundness_tests;
 
 // TODO: return a Vec<Info> that may contain warnings or errors,
 // instead of printing in lots of different place shere.
-fn compile_file(
-    path: &str,
-    output: &str,
-    opt_level: u8,
-    native: bool,
-    dump_ir: bool,
-    dump_c: bool,
-) -> Result<(), String> {
-    let src = match slurp_file_to_string(path) {
-        Ok(src) => src,
-        Err(info) => {
-            return Err(format!("{}", info));
+fn compile_file(path: &str, opt: &Opt) -> Result<(), String> {
+    let opt_level = opt.opt_level;
+    let emit_bf = opt.emit.as_deref() == Some("bf");
+
+    let (src, mut instrs) = parse_file(path).map_err(|info| format!("{}", info))?;
+
+    let mut stats = Stats::default();
+    if opt_level != 0 {
+        // let pass_specification = matches.opt_str("passes");
+        let pass_specification = if emit_bf {
+            Some(peephole::bf_passes())
+        } else {
+            None
+        };
+        let mut pass_manager = peephole::PassManager::new(&pass_specification);
+        if let Some(opt_iterations) = opt.opt_iterations {
+            pass_manager = pass_manager.with_max_iterations(opt_iterations);
+        }
+        if opt.stats.is_some() {
+            pass_manager = pass_manager.with_stats();
+        }
+        if opt.remarks {
+            pass_manager = pass_manager.with_remarks();
+        }
+        if opt.print_after_all {
+            pass_manager = pass_manager.with_print_after_all();
+        }
+        for pass_name in &opt.print_after {
+            pass_manager = pass_manager.with_print_after(pass_name);
+        }
+        if let Some(input_path) = &opt.verify_opt {
+            let input = match input_path {
+                Some(input_path) => {
+                    slurp_file_to_bytes(input_path).map_err(|info| format!("{}", info))?
+                }
+                None => verify::generated_input(verify::GENERATED_INPUT_LEN),
+            };
+            pass_manager = pass_manager.with_verify(input);
+        }
+        let (opt_instrs, mut report) = pass_manager.run_with_report(instrs);
+        instrs = opt_instrs;
+        if let Some(pass_stats) = report.stats {
+            stats = pass_stats;
         }
-    };
 
-    let mut instrs = match bfir::parse(&src) {
-        Ok(instrs) => instrs,
-        Err(parse_error) => {
+        report
+            .remarks
+            .sort_by_key(|remark| remark.position.map(|pos| pos.start));
+        for remark in report.remarks {
             let info = Info {
-                level: Level::Error,
+                level: Level::Remark,
                 filename: path.to_owned(),
-                message: parse_error.message,
-                position: Some(parse_error.position),
-                source: Some(src),
+                message: remark.message,
+                position: remark.position,
+                source: Some(src.clone()),
             };
-            return Err(format!("{}", info));
+            eprintln!("{}", info);
         }
-    };
 
-    if opt_level != 0 {
-        // let pass_specification = matches.opt_str("passes");
-        let pass_specification = None;
-        let (opt_instrs, warnings) = peephole::optimize(instrs, &pass_specification);
-        instrs = opt_instrs;
-
-        for warning in warnings {
+        for warning in report.warnings {
             let info = Info {
                 level: Level::Warning,
                 filename: path.to_owned(),
@@ -90,23 +133,151 @@ fn compile_file(
             };
             eprintln!("{}", info);
         }
+
+        if let Some(divergence) = report.divergence {
+            let info = Info {
+                level: Level::Error,
+                filename: path.to_owned(),
+                message: format!(
+                    "The pass '{}' changed the behaviour of this program. {}",
+                    divergence.pass_name, divergence.message
+                ),
+                position: divergence.position,
+                source: Some(src),
+            };
+            return Err(format!("{}", info));
+        }
+    }
+
+    // Optimisation can show that a loop body does nothing useful,
+    // so we look for infinite loops afterwards.
+    let infinite_loops = infinite_loops::find_infinite_loops(&instrs);
+    for warning in &infinite_loops {
+        let info = Info {
+            level: Level::Warning,
+            filename: path.to_owned(),
+            message: warning.message.clone(),
+            position: warning.position,
+            source: Some(src.clone()),
+        };
+        eprintln!("{}", info);
     }
 
-    if dump_ir {
+    if opt.dump_ir {
         for instr in &instrs {
             println!("{}", instr);
         }
+        print_stats(opt, &stats);
         return Ok(());
     }
 
-    let (state, execution_warning) = if opt_level == 2 {
-        execution::execute(&instrs, 10_000_000)
+    if emit_bf {
+        print!("{}", bf::bf_from_instructions(&instrs));
+        print_stats(opt, &stats);
+        return Ok(());
+    }
+
+    let assumed_input = match &opt.assume_input {
+        Some(input_path) => {
+            Some(slurp_file_to_bytes(input_path).map_err(|info| format!("{}", info))?)
+        }
+        None => None,
+    };
+    let mut input = match &assumed_input {
+        Some(assumed_input) => execution::Input::bytes(assumed_input),
+        None => execution::Input::Runtime,
+    };
+
+    let (state, execution_warning, residual) = if opt_level == 2 && opt.spec_steps > 0 {
+        let start_time = Instant::now();
+        let (state, mut warning, mut steps) =
+            execution::speculate(&instrs, opt.spec_steps, opt.spec_timeout, &mut input);
+
+        // If we stopped at a read, keep going with the input unknown.
+        let mut residual = partial_eval::partially_evaluate(
+            &instrs,
+            &state,
+            opt.spec_steps - steps,
+            opt.spec_timeout
+                .map(|timeout| timeout.saturating_sub(start_time.elapsed())),
+        );
+        if let Some(residual) = residual.as_mut() {
+            steps += residual.steps;
+            warning = warning.or(residual.warning.take());
+        }
+
+        if opt.print_after_all || opt.print_after.iter().any(|name| name == "speculation") {
+            let runtime_instrs = match &residual {
+                Some(residual) => &residual.instrs,
+                None => &instrs,
+            };
+            eprint!(
+                "{}",
+                print_ir::ir_after("speculation", &instrs, runtime_instrs)
+            );
+            eprintln!(
+                "*** Speculative execution ran {} steps, wrote {} bytes, and runtime starts at {} ***",
+                steps,
+                state.outputs.len(),
+                match state.start_instr.and_then(bfir::get_position) {
+                    Some(position) => format!("position {:?}", position),
+                    None => "the end of the program".to_owned(),
+                }
+            );
+        }
+        stats.speculation = Some(SpeculationStats {
+            steps,
+            executed_instrs: stats::executed_instrs(&instrs, state.start_instr),
+            total_instrs: instrs.len(),
+        });
+        (state, warning, residual)
     } else {
         let mut init_state = execution::ExecutionState::initial(&instrs[..]);
-        // TODO: this will crash on the empty program.
-        init_state.start_instr = Some(&instrs[0]);
-        (init_state, None)
+        init_state.start_instr = instrs.first();
+        (init_state, None, None)
     };
+
+    // If we partially evaluated the program, runtime execution
+    // starts at the beginning of the residual instructions.
+    let runtime_instrs = residual.map(|residual| residual.instrs);
+    let state = match &runtime_instrs {
+        Some(runtime_instrs) => execution::ExecutionState {
+            start_instr: runtime_instrs.first(),
+            cells: state.cells,
+            cell_ptr: state.cell_ptr,
+            outputs: state.outputs,
+        },
+        None => state,
+    };
+
+    if let Some(profile_path) = &opt.profile_report {
+        let contents = slurp_file_to_string(profile_path).map_err(|info| format!("{}", info))?;
+        let profile = profile::Profile::from_file_contents(
+            runtime_instrs.as_deref().unwrap_or(&instrs),
+            &contents,
+        )
+        .map_err(|message| {
+            format!(
+                "{}",
+                Info {
+                    level: Level::Error,
+                    filename: profile_path.to_owned(),
+                    message,
+                    position: None,
+                    source: None,
+                }
+            )
+        })?;
+        print_profile(&profile, path, &src);
+        return Ok(());
+    }
+
+    // We've already warned about loops that we can see are infinite.
+    let execution_warning = execution_warning.filter(|execution_warning| {
+        !infinite_loops
+            .iter()
+            .any(|warning| warning.position == execution_warning.position)
+    });
     if let Some(execution_warning) = execution_warning {
         let info = Info {
             level: Level::Warning,
@@ -118,24 +289,302 @@ fn compile_file(
         eprintln!("{}", info);
     }
 
-    let c_program = c::c_prog_from_instructions(&instrs);
-    if dump_c {
+    print_stats(opt, &stats);
+
+    // Unless asked not to, the compiled program checks that it
+    // receives the input that we consumed at compile time.
+    let expected_input = match (&assumed_input, input) {
+        (
+            Some(assumed_input),
+            execution::Input::Bytes {
+                remaining,
+                read_eof,
+            },
+        ) if !opt.ignore_assumed_input => Some(c::ExpectedInput {
+            bytes: &assumed_input[..assumed_input.len() - remaining.len()],
+            eof: read_eof,
+        }),
+        _ => None,
+    };
+    let c_program = c::c_prog_from_instructions(
+        runtime_instrs.as_deref().unwrap_or(&instrs),
+        &state,
+        expected_input,
+        opt.profile,
+    );
+    if opt.dump_c {
         println!("{}", c_program);
         return Ok(());
     }
-    c::compile_c_program(&c_program, output, opt_level, native);
+    c::compile_c_program(
+        &c_program,
+        opt.output.to_str().unwrap(),
+        opt_level,
+        opt.native,
+    )
+}
+
+/// Read and parse the BF program at `path`, returning its source and
+/// instructions.
+fn parse_file(path: &str) -> Result<(String, Vec<bfir::AstNode>), Info> {
+    let src = slurp_file_to_string(path)?;
+    match bfir::parse(&src) {
+        Ok(instrs) => Ok((src, instrs)),
+        Err(parse_error) => Err(Info {
+            level: Level::Error,
+            filename: path.to_owned(),
+            message: parse_error.message,
+            position: Some(parse_error.position),
+            source: Some(src),
+        }),
+    }
+}
+
+/// Read and parse the BF program at `path` for interpreting,
+/// optimising it unless `opt_level` is 0.
+fn load_for_interpreter(path: &str, opt_level: u8) -> Result<(String, Vec<bfir::AstNode>), String> {
+    let (src, mut instrs) = parse_file(path).map_err(|info| format!("{}", info))?;
+    if opt_level != 0 {
+        instrs = peephole::PassManager::new(&None).run_with_report(instrs).0;
+    }
+    Ok((src, instrs))
+}
 
+/// Run the program at `path` in the interactive debugger.
+fn debug_file(path: &str, opt: &DebugOpt) -> Result<(), String> {
+    let (src, instrs) = load_for_interpreter(path, opt.opt_level)?;
+
+    let input = match &opt.input {
+        Some(input_path) => slurp_file_to_bytes(input_path).map_err(|info| format!("{}", info))?,
+        None => vec![],
+    };
+    debugger::debug(path, &src, &instrs, &input);
     Ok(())
 }
 
+/// Interpret the program at `path`, reading from stdin and writing
+/// to stdout.
+fn run_file(path: &str, opt: &RunOpt) -> Result<(), String> {
+    let (src, instrs) = load_for_interpreter(path, opt.opt_level)?;
+
+    let mut tracer = match &opt.trace {
+        Some(trace_path) => {
+            let tracer = File::create(trace_path).and_then(|file| {
+                trace::Tracer::new(BufWriter::new(file), opt.trace_range, opt.trace_steps)
+            });
+            Some(tracer.map_err(|message| {
+                format!(
+                    "{}",
+                    Info {
+                        level: Level::Error,
+                        filename: trace_path.to_owned(),
+                        message: format!("{}", message),
+                        position: None,
+                        source: None,
+                    }
+                )
+            })?)
+        }
+        None => None,
+    };
+
+    let mut profile = if opt.profile {
+        Some(profile::Profile::new(&instrs))
+    } else {
+        None
+    };
+
+    let mut coverage = if opt.coverage || opt.lcov.is_some() {
+        Some(coverage::Coverage::new(&src))
+    } else {
+        None
+    };
+
+    let program_id = snapshot::program_id(&src, opt.opt_level);
+    let (mut interpreter, bytes_read) = match &opt.resume {
+        Some(snapshot_path) => resume_interpreter(&instrs, program_id, snapshot_path)?,
+        None => (interpreter::Interpreter::new(&instrs), 0),
+    };
+    let mut input = execution::Input::Stdin { bytes_read };
+    let mut error = None;
+    let stdout = io::stdout();
+    let mut stdout = BufWriter::new(stdout.lock());
+
+    while let Some(instr) = interpreter.current_instr() {
+        // Make sure the user has seen our output before we wait for
+        // input.
+        if let bfir::AstNode::Read { .. } = instr {
+            let _ = stdout.flush();
+        }
+
+        let step = interpreter.steps + 1;
+        let cell_ptr = interpreter.state.cell_ptr;
+        let cell_index = trace::traced_cell(instr, cell_ptr);
+        let before = match &tracer {
+            Some(tracer) if tracer.should_trace(step, instr) && cell_index >= 0 => {
+                interpreter.state.cells.get(cell_index as usize).copied()
+            }
+            _ => None,
+        };
+
+        if let Some(profile) = profile.as_mut() {
+            profile.record(instr, &interpreter.state);
+        }
+        if let Some(coverage) = coverage.as_mut() {
+            coverage.record(instr);
+        }
+        let result = interpreter.step(&mut input);
+
+        if !interpreter.state.outputs.is_empty() {
+            let outputs: Vec<u8> = interpreter
+                .state
+                .outputs
+                .drain(..)
+                .map(|byte| byte as u8)
+                .collect();
+            let _ = stdout.write_all(&outputs);
+        }
+
+        if let Err(warning) = result {
+            error = Some(warning);
+            break;
+        }
+
+        if let (Some(tracer), Some(before)) = (tracer.as_mut(), before) {
+            let after = interpreter.state.cells[cell_index as usize];
+            if let Err(message) = tracer.record(step, instr, cell_ptr, before, after) {
+                return Err(format!("Could not write trace: {}", message));
+            }
+        }
+
+        if let Some(checkpoint_path) = &opt.checkpoint {
+            if opt.checkpoint_steps > 0 && interpreter.steps % opt.checkpoint_steps == 0 {
+                // Don't lose output if we're resumed from here.
+                let _ = stdout.flush();
+                let bytes_read = match input {
+                    execution::Input::Stdin { bytes_read } => bytes_read,
+                    _ => unreachable!(),
+                };
+                let snapshot = interpreter.snapshot(program_id, bytes_read);
+                if let Err(message) = save_snapshot(&snapshot, checkpoint_path) {
+                    return Err(format!("Could not write checkpoint: {}", message));
+                }
+            }
+        }
+    }
+
+    // Report on the run, even if the program crashed.
+    let _ = stdout.flush();
+    if let Some(profile) = &profile {
+        print_profile(profile, path, &src);
+    }
+    if let Some(coverage) = &coverage {
+        if opt.coverage {
+            eprint!("{}", coverage.annotated_listing(&src));
+        }
+        if let Some(lcov_path) = &opt.lcov {
+            if let Err(message) = std::fs::write(lcov_path, coverage.lcov(&src, path)) {
+                return Err(format!("Could not write coverage report: {}", message));
+            }
+        }
+    }
+    if let Some(tracer) = tracer {
+        if let Err(message) = tracer.finish() {
+            return Err(format!("Could not write trace: {}", message));
+        }
+    }
+
+    match error {
+        Some(warning) => {
+            let info = Info {
+                level: Level::Error,
+                filename: path.to_owned(),
+                message: warning.message,
+                position: warning.position,
+                source: Some(src),
+            };
+            Err(format!("{}", info))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Load the snapshot at `path`, and start an interpreter for
+/// `instrs` from it. We skip the input that the program had already
+/// read, and return how many bytes that was.
+fn resume_interpreter<'a>(
+    instrs: &'a [bfir::AstNode],
+    program_id: u64,
+    path: &str,
+) -> Result<(interpreter::Interpreter<'a>, u64), String> {
+    let error = |message: String| {
+        format!(
+            "{}",
+            Info {
+                level: Level::Error,
+                filename: path.to_owned(),
+                message,
+                position: None,
+                source: None,
+            }
+        )
+    };
+
+    let contents = slurp_file_to_string(path).map_err(|info| format!("{}", info))?;
+    let snapshot = snapshot::Snapshot::from_text(&contents).map_err(error)?;
+    if snapshot.program_id != program_id {
+        return Err(error(
+            "This snapshot is for a different program. Resume with the same program and -O \
+             level that you checkpointed with."
+                .to_owned(),
+        ));
+    }
+    let interpreter = interpreter::Interpreter::from_snapshot(instrs, &snapshot).map_err(error)?;
+
+    let skipped = io::copy(
+        &mut io::stdin().lock().take(snapshot.input_offset),
+        &mut io::sink(),
+    )
+    .map_err(|message| error(format!("Could not read input: {}", message)))?;
+    if skipped < snapshot.input_offset {
+        return Err(error(format!(
+            "The program had read {} bytes of input when this snapshot was taken, but \
+             the input only has {}. Resume with the same input.",
+            snapshot.input_offset, skipped
+        )));
+    }
+    Ok((interpreter, skipped))
+}
+
+/// Write `snapshot` to `path`. We write to a temporary file first, so
+/// we never leave a partial snapshot if we're interrupted.
+fn save_snapshot(snapshot: &snapshot::Snapshot, path: &str) -> io::Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    std::fs::write(&temp_path, snapshot.to_text())?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// Print the hottest loops in `profile` to stderr.
+fn print_profile(profile: &profile::Profile, path: &str, src: &str) {
+    eprintln!("Executed {} steps.", profile.total_steps());
+    for info in profile.report(path, src) {
+        eprintln!("{}", info);
+    }
+}
+
+/// Print optimisation statistics to stderr, if the user asked for
+/// them.
+fn print_stats(opt: &Opt, stats: &Stats) {
+    match &opt.stats {
+        Some(Some(format)) if format == "json" => eprint!("{}", stats.to_json()),
+        Some(_) => eprint!("{}", stats.to_table()),
+        None => {}
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "bfc", about = "Optimizing brainfuck compiler")]
 struct Opt {
-    /// Activate debug mode
-    // short and long flags (-d, --debug) will be deduced from the field's name
-    #[structopt(short, long)]
-    debug: bool,
-
     /// print BF IR generated
     #[structopt(long = "dump-ir")]
     dump_ir: bool,
@@ -144,27 +593,199 @@ struct Opt {
     #[structopt(long = "dump-c")]
     dump_c: bool,
 
+    /// print the optimised program in another language instead of compiling
+    #[structopt(long = "emit", possible_values = &["bf"])]
+    emit: Option<String>,
+
     /// optimize level (0 to 2)
     #[structopt(short = "O", default_value = "2")]
     opt_level: u8,
 
+    /// maximum number of times to run the peephole optimisations
+    #[structopt(long = "opt-iterations")]
+    opt_iterations: Option<u64>,
+
+    /// print optimisation statistics to stderr, as a table (default) or JSON
+    #[structopt(
+        long = "stats",
+        possible_values = &["table", "json"],
+        require_equals = true
+    )]
+    stats: Option<Option<String>>,
+
+    /// describe how the optimiser transformed each loop
+    #[structopt(long = "remarks")]
+    remarks: bool,
+
+    /// print the IR after the named passes, or after "speculation" (comma-separated)
+    #[structopt(long = "print-after", require_equals = true, use_delimiter = true)]
+    print_after: Vec<String>,
+
+    /// print the IR after every pass
+    #[structopt(long = "print-after-all")]
+    print_after_all: bool,
+
+    /// check each pass by running the program before and after it, on
+    /// the bytes in the given file or on generated input
+    #[structopt(long = "verify-opt", require_equals = true)]
+    verify_opt: Option<Option<String>>,
+
+    /// assume the program is run with the bytes in this file as input,
+    /// so speculative execution can execute reads
+    #[structopt(long = "assume-input", require_equals = true)]
+    assume_input: Option<String>,
+
+    /// don't check at runtime that stdin starts with the assumed input
+    #[structopt(long = "ignore-assumed-input")]
+    ignore_assumed_input: bool,
+
+    /// maximum number of steps of speculative execution at -O2: a
+    /// number, 0 to disable, or "unlimited"
+    #[structopt(
+        long = "spec-steps",
+        default_value = "10000000",
+        parse(try_from_str = parse_spec_steps)
+    )]
+    spec_steps: u64,
+
+    /// stop speculative execution after this many seconds
+    #[structopt(long = "spec-timeout", parse(try_from_str = parse_seconds))]
+    spec_timeout: Option<Duration>,
+
+    /// make the compiled program count how often each instruction
+    /// runs, and write the counts to bfc.profile when it exits
+    #[structopt(long = "profile")]
+    profile: bool,
+
+    /// report the hottest loops from a bfc.profile file, instead of
+    /// compiling (use the same options that you compiled with)
+    #[structopt(long = "profile-report", require_equals = true)]
+    profile_report: Option<String>,
+
     /// build for the native architecture
     #[structopt(long = "native")]
     native: bool,
 
-    /// strip symbols from the binary (default: yes)
-    #[structopt(long = "strip")]
-    strip: bool,
-
     // output binary
     #[structopt(short = "o", default_value = "a.out", parse(from_os_str))]
     output: PathBuf,
 
     // TODO: Replace with Vec<PathBuf>
+    #[structopt(parse(from_os_str))]
+    file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Step through a program in an interactive debugger
+    Debug(DebugOpt),
+    /// Run a program in the interpreter
+    Run(RunOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct RunOpt {
+    /// run the instructions after optimisation at this level (0 to 2)
+    #[structopt(short = "O", default_value = "0")]
+    opt_level: u8,
+
+    /// write every instruction executed to this file
+    #[structopt(long = "trace", require_equals = true)]
+    trace: Option<String>,
+
+    /// only trace instructions in this range of source offsets, e.g. 10-20
+    #[structopt(
+        long = "trace-range",
+        require_equals = true,
+        parse(try_from_str = parse_position)
+    )]
+    trace_range: Option<bfir::Position>,
+
+    /// only trace the first N steps
+    #[structopt(long = "trace-steps", require_equals = true)]
+    trace_steps: Option<u64>,
+
+    /// report the loops where the program spent the most time
+    #[structopt(long = "profile")]
+    profile: bool,
+
+    /// show which parts of the source executed
+    #[structopt(long = "coverage")]
+    coverage: bool,
+
+    /// write an lcov coverage report to this file
+    #[structopt(long = "lcov", require_equals = true)]
+    lcov: Option<String>,
+
+    /// save the state of the program to this file periodically, so
+    /// the run can be resumed with --resume
+    #[structopt(long = "checkpoint", require_equals = true)]
+    checkpoint: Option<String>,
+
+    /// save a checkpoint every N steps
+    #[structopt(
+        long = "checkpoint-steps",
+        require_equals = true,
+        default_value = "100000000"
+    )]
+    checkpoint_steps: u64,
+
+    /// continue from a state saved with --checkpoint
+    #[structopt(long = "resume", require_equals = true)]
+    resume: Option<String>,
+
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct DebugOpt {
+    /// step over the instructions after optimisation at this level (0 to 2)
+    #[structopt(short = "O", default_value = "0")]
+    opt_level: u8,
+
+    /// give the program the bytes in this file as input
+    #[structopt(long = "input", require_equals = true)]
+    input: Option<String>,
+
     #[structopt(parse(from_os_str))]
     file: PathBuf,
 }
 
+/// Parse the argument to `--spec-steps`.
+fn parse_spec_steps(src: &str) -> Result<u64, String> {
+    if src == "unlimited" {
+        return Ok(u64::MAX);
+    }
+    src.parse()
+        .map_err(|_| format!("Expected a number of steps or 'unlimited', got '{}'", src))
+}
+
+/// Parse a source offset, or a range of offsets like `10-20`.
+fn parse_position(src: &str) -> Result<bfir::Position, String> {
+    let (start, end) = src.split_once('-').unwrap_or((src, src));
+    match (start.parse(), end.parse()) {
+        (Ok(start), Ok(end)) if start <= end => Ok(bfir::Position { start, end }),
+        _ => Err(format!(
+            "Expected an offset or a range like 10-20, got '{}'",
+            src
+        )),
+    }
+}
+
+/// Parse a (possibly fractional) number of seconds.
+fn parse_seconds(src: &str) -> Result<Duration, String> {
+    match src.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => {
+            Ok(Duration::from_secs_f64(seconds))
+        }
+        _ => Err(format!("Expected a number of seconds, got '{}'", src)),
+    }
+}
+
 fn main() {
     let opt = Opt::from_args();
 
@@ -173,14 +794,31 @@ fn main() {
         exit(1);
     }
 
-    match compile_file(
-        opt.file.to_str().unwrap(),
-        opt.output.to_str().unwrap(),
-        opt.opt_level,
-        opt.native,
-        opt.dump_ir,
-        opt.dump_c,
-    ) {
+    let pass_names = peephole::pass_names();
+    for pass_name in &opt.print_after {
+        if pass_name != "speculation" && !pass_names.contains(&pass_name.as_str()) {
+            eprintln!(
+                "Unknown pass '{}' for --print-after. Passes are: {}, speculation",
+                pass_name,
+                pass_names.join(", ")
+            );
+            exit(1);
+        }
+    }
+
+    let result = match (&opt.command, &opt.file) {
+        (Some(Command::Debug(debug_opt)), _) => {
+            debug_file(debug_opt.file.to_str().unwrap(), debug_opt)
+        }
+        (Some(Command::Run(run_opt)), _) => run_file(run_opt.file.to_str().unwrap(), run_opt),
+        (None, Some(file)) => compile_file(file.to_str().unwrap(), &opt),
+        (None, None) => Error::with_description(
+            "The following required arguments were not provided:\n    <file>",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
+    match result {
         Ok(_) => {}
         Err(e) => {
             eprintln!("{}", e);
@@ -189,6 +827,18 @@ fn main() {
     }
 }
 
+/// Read the contents of the file at path as bytes. Return a
+/// diagnostic if we can't read the file.
+fn slurp_file_to_bytes(path: &str) -> Result<Vec<u8>, Info> {
+    std::fs::read(path).map_err(|message| Info {
+        level: Level::Error,
+        filename: path.to_owned(),
+        message: format!("{}", message),
+        position: None,
+        source: None,
+    })
+}
+
 /// Read the contents of the file at path, and return a string of its
 /// contents. Return a diagnostic if we can't open or read the file.
 fn slurp_file_to_string(path: &str) -> Result<String, Info> {
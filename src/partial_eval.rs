@@ -0,0 +1,656 @@
+//! Partial evaluation of BF programs. Speculative execution stops at
+//! the first read, but the rest of the program often does plenty of
+//! work that doesn't depend on input. We continue past the read,
+//! treating cells that depend on input as unknown, and only keep the
+//! instructions that need an unknown value. Loops on unknown cells
+//! run at runtime, but if they don't move the pointer, we know which
+//! cells they change and keep going afterwards.
+
+use std::collections::BTreeSet;
+use std::mem;
+use std::num::Wrapping;
+use std::time::{Duration, Instant};
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Cell, Combine, Position};
+use crate::diagnostics::Warning;
+use crate::execution::{ExecutionState, DEADLINE_CHECK_INTERVAL};
+use crate::peephole::modified_cells;
+
+/// The most instructions we produce before giving up. Loops that
+/// run many times can produce a residual instruction per iteration,
+/// which would make the compiled program larger than the original.
+const MAX_RESIDUAL_INSTRS: usize = 10_000;
+
+/// The result of partial evaluation.
+#[derive(Debug)]
+pub struct Residual {
+    /// The instructions to run at runtime, starting from the cells
+    /// and cell pointer where speculative execution stopped.
+    pub instrs: Vec<AstNode>,
+    pub steps: u64,
+    /// Set if we stopped because we ran out of steps or time.
+    pub warning: Option<Warning>,
+}
+
+/// Why we couldn't evaluate an instruction.
+#[derive(Debug, PartialEq, Eq)]
+enum Stop {
+    /// The instruction needs a value that depends on input, or may
+    /// access a cell out of bounds.
+    Unknown,
+    OutOfSteps,
+    OutOfTime,
+}
+
+/// What we know about the program whilst partially evaluating it.
+#[derive(Debug, Clone)]
+struct PartialState {
+    /// The value of each cell, or None if it depends on input.
+    cells: Vec<Option<Cell>>,
+    cell_ptr: isize,
+    /// Cells whose value we know, but haven't yet written at runtime.
+    stale: BTreeSet<usize>,
+    /// The cell pointer at runtime. Residual instructions use offsets
+    /// relative to this, so we rarely need to move it.
+    runtime_ptr: isize,
+    /// Output we know, but haven't written yet.
+    pending_output: Vec<u8>,
+    pending_position: Option<Position>,
+    residual: Vec<AstNode>,
+    steps_left: u64,
+}
+
+impl PartialState {
+    fn new(state: &ExecutionState, steps: u64) -> Self {
+        PartialState {
+            cells: state.cells.iter().map(|&cell| Some(cell)).collect(),
+            cell_ptr: state.cell_ptr,
+            stale: BTreeSet::new(),
+            runtime_ptr: state.cell_ptr,
+            pending_output: vec![],
+            pending_position: None,
+            residual: vec![],
+            steps_left: steps,
+        }
+    }
+
+    /// The index of the cell at `offset` from the cell pointer.
+    fn cell_index(&self, offset: isize) -> Result<usize, Stop> {
+        let index = self.cell_ptr + offset;
+        if index < 0 || index >= self.cells.len() as isize {
+            // Let the runtime report the error.
+            return Err(Stop::Unknown);
+        }
+        Ok(index as usize)
+    }
+
+    /// The offset of the cell at `index` from the runtime pointer.
+    fn runtime_offset(&self, index: usize) -> isize {
+        index as isize - self.runtime_ptr
+    }
+
+    fn step(&mut self, deadline: Option<Instant>) -> Result<(), Stop> {
+        if self.steps_left == 0 {
+            return Err(Stop::OutOfSteps);
+        }
+        if self.residual.len() > MAX_RESIDUAL_INSTRS {
+            return Err(Stop::Unknown);
+        }
+        if let Some(deadline) = deadline {
+            if self.steps_left.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline
+            {
+                return Err(Stop::OutOfTime);
+            }
+        }
+        self.steps_left -= 1;
+        Ok(())
+    }
+
+    /// Increase the unknown cell at `index` at runtime.
+    fn push_increment(&mut self, index: usize, amount: Cell, position: Option<Position>) {
+        let offset = self.runtime_offset(index);
+        if let Some(Increment {
+            amount: previous_amount,
+            offset: previous_offset,
+            ..
+        }) = self.residual.last_mut()
+        {
+            if *previous_offset == offset {
+                *previous_amount += amount;
+                return;
+            }
+        }
+        self.residual.push(Increment {
+            amount,
+            offset,
+            position,
+        });
+    }
+
+    /// Output `bytes`, which we know at compile time.
+    fn push_output(&mut self, bytes: &[u8], position: Option<Position>) {
+        self.pending_position = if self.pending_output.is_empty() {
+            position
+        } else {
+            self.pending_position.combine(position)
+        };
+        self.pending_output.extend(bytes);
+    }
+
+    /// Write any known output, so it appears before the next
+    /// residual instruction.
+    fn flush_output(&mut self) {
+        if !self.pending_output.is_empty() {
+            self.residual.push(WriteConst {
+                bytes: mem::take(&mut self.pending_output),
+                position: self.pending_position.take(),
+            });
+        }
+    }
+
+    /// Ensure the cell at `index` has its known value at runtime.
+    fn sync(&mut self, index: usize) {
+        if self.stale.remove(&index) {
+            let amount = self.cells[index].unwrap();
+            self.residual.push(Set {
+                amount,
+                offset: self.runtime_offset(index),
+                position: None,
+            });
+        }
+    }
+
+    /// Ensure the runtime cell pointer matches the cell pointer.
+    fn sync_ptr(&mut self) {
+        if self.runtime_ptr != self.cell_ptr {
+            self.residual.push(PointerIncrement {
+                amount: self.cell_ptr - self.runtime_ptr,
+                position: None,
+            });
+            self.runtime_ptr = self.cell_ptr;
+        }
+    }
+
+    /// Keep `instr`, which only accesses the cells at `indexes` and
+    /// doesn't move the pointer. Afterwards, the cells at
+    /// `written_indexes` are unknown.
+    fn residualise(&mut self, instr: &AstNode, indexes: &[usize], written_indexes: &[usize]) {
+        self.flush_output();
+        for &index in indexes {
+            self.sync(index);
+        }
+        self.sync_ptr();
+        self.residual.push(instr.clone());
+        for &index in written_indexes {
+            self.cells[index] = None;
+        }
+    }
+
+    /// Keep the loop or if `instr`, whose cell at `offset` is
+    /// unknown. If `body` always returns to the same cell, we can
+    /// carry on afterwards: only the cells it modifies become
+    /// unknown, and the loop's cell is zero.
+    fn residualise_loop(
+        &mut self,
+        instr: &AstNode,
+        body: &[AstNode],
+        offset: isize,
+    ) -> Result<(), Stop> {
+        let modified = match modified_cells(body) {
+            Some((modified, 0)) => modified,
+            _ => return Err(Stop::Unknown),
+        };
+        let written_indexes = modified
+            .into_iter()
+            .map(|offset| self.cell_index(offset))
+            .collect::<Result<Vec<_>, _>>()?;
+        let index = self.cell_index(offset)?;
+
+        // The body may read any cell, so write every known value.
+        let indexes: Vec<_> = self.stale.iter().copied().collect();
+        self.residualise(instr, &indexes, &written_indexes);
+        self.cells[index] = Some(Wrapping(0));
+        Ok(())
+    }
+
+    fn eval_all(&mut self, instrs: &[AstNode], deadline: Option<Instant>) -> Result<(), Stop> {
+        for instr in instrs {
+            self.eval(instr, deadline)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluate `instr`. Simple instructions leave the state
+    /// unchanged if they return an error, but instructions with
+    /// bodies may not.
+    fn eval(&mut self, instr: &AstNode, deadline: Option<Instant>) -> Result<(), Stop> {
+        self.step(deadline)?;
+
+        match *instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => {
+                let index = self.cell_index(offset)?;
+                match self.cells[index] {
+                    Some(value) => {
+                        self.cells[index] = Some(value + amount);
+                        self.stale.insert(index);
+                    }
+                    None => self.push_increment(index, amount, position),
+                }
+            }
+            Set { amount, offset, .. } => {
+                let index = self.cell_index(offset)?;
+                self.cells[index] = Some(amount);
+                self.stale.insert(index);
+            }
+            PointerIncrement { amount, .. } => {
+                let new_cell_ptr = self.cell_ptr + amount;
+                if new_cell_ptr < 0 || new_cell_ptr >= self.cells.len() as isize {
+                    return Err(Stop::Unknown);
+                }
+                self.cell_ptr = new_cell_ptr;
+            }
+            Read { offset, position } => {
+                let index = self.cell_index(offset)?;
+                self.flush_output();
+                // At EOF, reads leave the cell unchanged, so it needs
+                // its known value.
+                self.sync(index);
+                self.cells[index] = None;
+                self.residual.push(Read {
+                    offset: self.runtime_offset(index),
+                    position,
+                });
+            }
+            Write { offset, position } => {
+                let index = self.cell_index(offset)?;
+                match self.cells[index] {
+                    Some(value) => self.push_output(&[value.0 as u8], position),
+                    None => {
+                        self.flush_output();
+                        self.residual.push(Write {
+                            offset: self.runtime_offset(index),
+                            position,
+                        });
+                    }
+                }
+            }
+            WriteConst {
+                ref bytes,
+                position,
+            } => self.push_output(bytes, position),
+            Loop {
+                ref body, offset, ..
+            } => loop {
+                let index = self.cell_index(offset)?;
+                match self.cells[index] {
+                    None => return self.residualise_loop(instr, body, offset),
+                    Some(value) if value.0 == 0 => break,
+                    Some(_) => {
+                        self.eval_all(body, deadline)?;
+                        self.step(deadline)?;
+                    }
+                }
+            },
+            If { ref body, .. } => {
+                let index = self.cell_index(0)?;
+                match self.cells[index] {
+                    None => self.residualise_loop(instr, body, 0)?,
+                    Some(value) if value.0 == 0 => {}
+                    Some(_) => self.eval_all(body, deadline)?,
+                }
+            }
+            Scan { stride, .. } => loop {
+                let index = self.cell_index(0)?;
+                match self.cells[index] {
+                    None => return Err(Stop::Unknown),
+                    Some(value) if value.0 == 0 => break,
+                    Some(_) => {
+                        self.step(deadline)?;
+                        self.cell_ptr += stride;
+                    }
+                }
+            },
+            MultiplyMove { ref changes, .. } => {
+                let source = self.cell_index(0)?;
+                let targets = changes
+                    .keys()
+                    .map(|&offset| self.cell_index(offset))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                match self.cells[source] {
+                    Some(value) if value.0 == 0 => {}
+                    Some(value) => {
+                        for (&offset, &factor) in changes {
+                            let index = self.cell_index(offset)?;
+                            let increase = value * factor;
+                            match self.cells[index] {
+                                Some(target_value) => {
+                                    self.cells[index] = Some(target_value + increase);
+                                    self.stale.insert(index);
+                                }
+                                None => self.push_increment(index, increase, get_position(instr)),
+                            }
+                        }
+                        self.cells[source] = Some(Wrapping(0));
+                        self.stale.insert(source);
+                    }
+                    None => {
+                        let mut indexes = targets.clone();
+                        indexes.push(source);
+                        self.residualise(instr, &indexes, &targets);
+                        // A multiply move always finishes on a zero
+                        // cell, so we know the source afterwards.
+                        self.cells[source] = Some(Wrapping(0));
+                    }
+                }
+            }
+            MultiplyAdd { ref changes, .. } => {
+                let source = self.cell_index(0)?;
+                let mut indexes = vec![source];
+                let mut targets = vec![];
+                for &(target, operand) in changes.keys() {
+                    targets.push(self.cell_index(target)?);
+                    indexes.push(self.cell_index(target)?);
+                    indexes.push(self.cell_index(operand)?);
+                }
+
+                if indexes.iter().all(|&index| self.cells[index].is_some()) {
+                    let value = self.cells[source].unwrap();
+                    // Products use the cell values from before this
+                    // instruction.
+                    let increases: Vec<_> = changes
+                        .iter()
+                        .map(|(&(target, operand), &factor)| {
+                            let operand_value =
+                                self.cells[(self.cell_ptr + operand) as usize].unwrap();
+                            (target, value * operand_value * factor)
+                        })
+                        .collect();
+                    for (target, increase) in increases {
+                        let index = (self.cell_ptr + target) as usize;
+                        self.cells[index] = Some(self.cells[index].unwrap() + increase);
+                        self.stale.insert(index);
+                    }
+                } else {
+                    self.residualise(instr, &indexes, &targets);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Continue speculative execution from `state`, which stopped at a
+/// read at the top level of `instrs`. We return the instructions to
+/// run at runtime instead of resuming at the read, or None if we
+/// can't continue from where speculative execution stopped.
+pub fn partially_evaluate(
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    steps: u64,
+    timeout: Option<Duration>,
+) -> Option<Residual> {
+    let start_instr = state.start_instr?;
+    if !matches!(start_instr, Read { .. }) {
+        return None;
+    }
+    let start = instrs
+        .iter()
+        .position(|instr| std::ptr::eq(instr, start_instr))?;
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut partial = PartialState::new(state, steps);
+    let mut stop_index = instrs.len();
+    let mut warning = None;
+    for (i, instr) in instrs.iter().enumerate().skip(start) {
+        // If we can't finish a loop, we restart it at runtime, so
+        // discard anything we did inside it.
+        let snapshot = match instr {
+            Loop { .. } | If { .. } | Scan { .. } => Some(partial.clone()),
+            _ => None,
+        };
+
+        if let Err(stop) = partial.eval(instr, deadline) {
+            if let Some(snapshot) = snapshot {
+                // We still count the steps we took.
+                let steps_left = partial.steps_left;
+                partial = snapshot;
+                partial.steps_left = steps_left;
+            }
+            stop_index = i;
+
+            let reason = match stop {
+                Stop::Unknown => None,
+                Stop::OutOfSteps => Some("ran out of steps"),
+                Stop::OutOfTime => Some("timed out"),
+            };
+            warning = reason.map(|reason| Warning {
+                message: format!(
+                    "Speculative execution {} here (after {} steps), so the rest of the \
+                     program will run at runtime.",
+                    reason,
+                    steps - partial.steps_left
+                ),
+                position: get_position(instr),
+            });
+            break;
+        }
+    }
+
+    partial.flush_output();
+    // If there's more to run, the runtime needs every cell and the
+    // cell pointer to be correct.
+    if stop_index < instrs.len() {
+        for index in partial.stale.clone() {
+            partial.sync(index);
+        }
+        partial.sync_ptr();
+    }
+
+    let mut residual = partial.residual;
+    residual.extend(instrs[stop_index..].iter().cloned());
+    Some(Residual {
+        instrs: residual,
+        steps: steps - partial.steps_left,
+        warning,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::execution::{execute, execute_with_state, Outcome};
+    use pretty_assertions::assert_eq;
+    use quickcheck::{quickcheck, TestResult};
+    use std::num::Wrapping;
+
+    const MAX_STEPS: u64 = 10_000;
+
+    fn residual_for(src: &str) -> Vec<AstNode> {
+        let instrs = parse(src).unwrap();
+        let (state, _, _) = execute(&instrs, MAX_STEPS);
+        partially_evaluate(&instrs, &state, MAX_STEPS, None)
+            .unwrap()
+            .instrs
+    }
+
+    #[test]
+    fn only_at_read() {
+        let instrs = parse("+[]").unwrap();
+        let (state, _, _) = execute(&instrs, 100);
+        assert!(partially_evaluate(&instrs, &state, 100, None).is_none());
+    }
+
+    #[test]
+    fn known_work_after_read() {
+        // Read a byte, then print a constant.
+        let residual = residual_for(",>+++[<+++>-]<.>++.");
+        assert_eq!(
+            residual,
+            vec![
+                Read {
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Increment {
+                    amount: Wrapping(9),
+                    offset: 0,
+                    position: Some(Position { start: 7, end: 7 }),
+                },
+                Write {
+                    offset: 0,
+                    position: Some(Position { start: 14, end: 14 }),
+                },
+                WriteConst {
+                    bytes: vec![2],
+                    position: Some(Position { start: 18, end: 18 }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_loop_is_residualised() {
+        let instrs = parse(",>++<[.-]").unwrap();
+        let (state, _, _) = execute(&instrs, MAX_STEPS);
+        let residual = partially_evaluate(&instrs, &state, MAX_STEPS, None).unwrap();
+
+        // We set cell #1 before the loop, and keep the loop as is.
+        assert_eq!(
+            residual.instrs,
+            vec![
+                instrs[0].clone(),
+                Set {
+                    amount: Wrapping(2),
+                    offset: 1,
+                    position: None,
+                },
+                instrs[5].clone(),
+            ]
+        );
+        assert_eq!(residual.warning, None);
+    }
+
+    #[test]
+    fn known_work_after_unknown_loop() {
+        let instrs = parse(",[.-]>+++[>+++<-]>[.-]").unwrap();
+        let (state, _, _) = execute(&instrs, MAX_STEPS);
+        let residual = partially_evaluate(&instrs, &state, MAX_STEPS, None).unwrap();
+
+        // Only the first loop depends on input, and it doesn't change
+        // the cells that the later loops use.
+        assert_eq!(
+            residual.instrs,
+            vec![
+                instrs[0].clone(),
+                instrs[1].clone(),
+                WriteConst {
+                    bytes: (1..=9).rev().collect(),
+                    position: Some(Position { start: 19, end: 19 }),
+                },
+            ]
+        );
+        assert_eq!(residual.warning, None);
+    }
+
+    #[test]
+    fn read_in_unknown_loop() {
+        let residual = residual_for(",[,]+++.");
+        assert_eq!(
+            residual.last(),
+            Some(&WriteConst {
+                bytes: vec![3],
+                position: Some(Position { start: 7, end: 7 }),
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_multiply_move_is_residualised() {
+        let mut changes = std::collections::HashMap::new();
+        changes.insert(1, Wrapping(2));
+        let instrs = vec![
+            Read {
+                offset: 0,
+                position: None,
+            },
+            MultiplyMove {
+                changes,
+                position: None,
+            },
+            Write {
+                offset: 0,
+                position: None,
+            },
+        ];
+        let (state, _, _) = execute(&instrs, MAX_STEPS);
+        let residual = partially_evaluate(&instrs, &state, MAX_STEPS, None).unwrap();
+
+        // The source cell is zero afterwards.
+        assert_eq!(
+            residual.instrs,
+            vec![
+                instrs[0].clone(),
+                instrs[1].clone(),
+                WriteConst {
+                    bytes: vec![0],
+                    position: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_steps_warning() {
+        let instrs = parse(",>+[]").unwrap();
+        let (state, _, _) = execute(&instrs, MAX_STEPS);
+        let residual = partially_evaluate(&instrs, &state, 100, None).unwrap();
+
+        assert_eq!(residual.steps, 100);
+        assert_eq!(
+            residual.warning.map(|warning| warning.position),
+            Some(Some(Position { start: 3, end: 4 }))
+        );
+        // We restart the infinite loop at runtime.
+        assert_eq!(residual.instrs.last(), Some(&instrs[3]));
+    }
+
+    /// Running the residual program from the speculative state should
+    /// behave the same as running the original program.
+    #[test]
+    fn quickcheck_residual_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>, read_value: i8) -> TestResult {
+            let (state, _, _) = execute(&instrs, MAX_STEPS);
+            let residual = match partially_evaluate(&instrs, &state, MAX_STEPS, None) {
+                Some(residual) => residual,
+                None => return TestResult::discard(),
+            };
+
+            let mut expected = ExecutionState::initial(&instrs);
+            let outcome = execute_with_state(&instrs, &mut expected, MAX_STEPS, Some(read_value));
+            if !matches!(outcome, Outcome::Completed(_)) {
+                return TestResult::discard();
+            }
+
+            let mut actual = ExecutionState {
+                start_instr: None,
+                cells: state.cells.clone(),
+                cell_ptr: state.cell_ptr,
+                outputs: state.outputs.clone(),
+            };
+            let outcome =
+                execute_with_state(&residual.instrs, &mut actual, MAX_STEPS, Some(read_value));
+            if !matches!(outcome, Outcome::Completed(_)) {
+                return TestResult::failed();
+            }
+            TestResult::from_bool(expected.outputs == actual.outputs)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>, i8) -> TestResult);
+    }
+}
@@ -1,128 +1,483 @@
 //! Optimisations that replace parts of the BF AST with faster
 //! equivalents.
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
+use std::time::Instant;
 
 use itertools::Itertools;
 
-use crate::diagnostics::Warning;
+use crate::diagnostics::{Remark, Warning};
+use crate::print_ir::ir_after;
+use crate::stats::{count_changes, PassStats, Stats};
+use crate::verify::{Divergence, Verifier};
 
 use crate::bfir::AstNode::*;
 use crate::bfir::{get_position, AstNode, Cell, Combine, Position};
 
 const MAX_OPT_ITERATIONS: u64 = 40;
 
-/// Given a sequence of BF instructions, apply peephole optimisations
-/// (repeatedly if necessary).
-pub fn optimize(
-    instrs: Vec<AstNode>,
-    pass_specification: &Option<String>,
-) -> (Vec<AstNode>, Vec<Warning>) {
-    // Many of our individual peephole optimisations remove
-    // instructions, creating new opportunities to combine. We run
-    // until we've found a fixed-point where no further optimisations
-    // can be made.
-    let mut prev = instrs.clone();
-    let mut warnings = vec![];
+/// The passes we run (in this order) unless the user asks otherwise.
+pub const DEFAULT_PASSES: &str = "combine_inc,combine_ptr,known_zero,\
+                                  scan,multiply,zeroing_loop,nested_multiply,if,combine_set,\
+                                  const_prop,dead_loop,redundant_set,read_clobber,\
+                                  write_const,pure_removal,dead_store,offset_sort";
+
+/// An optimisation that we apply to a whole program.
+pub trait Pass {
+    /// The name used to select this pass, e.g. "combine_inc".
+    fn name(&self) -> &'static str;
+
+    /// Apply this pass once, along with any warning about the
+    /// program that the pass discovered, and whether the pass
+    /// changed the program.
+    fn run(&self, instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>, bool);
+
+    /// Whether we run this pass when emitting BF. Passes that produce
+    /// instructions with no BF equivalent, or replace loops with
+    /// constants that take more BF to write, don't.
+    fn suits_bf(&self) -> bool {
+        true
+    }
+}
 
-    let (mut result, warning) = optimize_once(instrs, pass_specification);
+/// A hash of `instrs`, so we can tell whether a pass changed the
+/// program without keeping a copy of it.
+fn fingerprint(instrs: &[AstNode]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    instrs.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A pass that never produces warnings.
+struct SimplePass {
+    name: &'static str,
+    transform: fn(Vec<AstNode>) -> Vec<AstNode>,
+    suits_bf: bool,
+}
 
-    if let Some(warning) = warning {
-        warnings.push(warning);
+impl Pass for SimplePass {
+    fn name(&self) -> &'static str {
+        self.name
     }
 
-    for _ in 0..MAX_OPT_ITERATIONS {
-        if prev == result {
-            return (result, warnings);
-        } else {
-            prev = result.clone();
+    fn run(&self, instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>, bool) {
+        let before = fingerprint(&instrs);
+        let after = (self.transform)(instrs);
+        let changed = fingerprint(&after) != before;
+        (after, None, changed)
+    }
 
-            let (new_result, new_warning) = optimize_once(result, pass_specification);
+    fn suits_bf(&self) -> bool {
+        self.suits_bf
+    }
+}
 
-            if let Some(warning) = new_warning {
-                warnings.push(warning);
-            }
-            result = new_result;
-        }
+struct PureRemovalPass;
+
+impl Pass for PureRemovalPass {
+    fn name(&self) -> &'static str {
+        "pure_removal"
+    }
+
+    fn run(&self, instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>, bool) {
+        // This pass only removes instructions from the end.
+        let num_instrs = instrs.len();
+        let (instrs, warning) = remove_pure_code(instrs);
+        let changed = instrs.len() != num_instrs;
+        (instrs, warning, changed)
     }
+}
 
-    // TODO: use proper Info here.
-    eprintln!(
-        "Warning: ran peephole optimisations {} times but did not reach a fixed point!",
-        MAX_OPT_ITERATIONS
-    );
+/// The names of every pass we know about.
+pub fn pass_names() -> Vec<&'static str> {
+    all_passes().iter().map(|pass| pass.name()).collect()
+}
 
-    (result, warnings)
+/// The default passes that we run when emitting BF, in the same
+/// format as `DEFAULT_PASSES`.
+pub fn bf_passes() -> String {
+    let bf_pass_names: Vec<_> = all_passes()
+        .iter()
+        .filter(|pass| pass.suits_bf())
+        .map(|pass| pass.name())
+        .collect();
+    DEFAULT_PASSES
+        .split(',')
+        .filter(|name| bf_pass_names.contains(name))
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
-/// Apply all our peephole optimisations once and return the result.
-fn optimize_once(
-    instrs: Vec<AstNode>,
-    pass_specification: &Option<String>,
-) -> (Vec<AstNode>, Option<Warning>) {
-    let pass_specification = pass_specification.clone().unwrap_or(
-        "combine_inc,combine_ptr,known_zero,\
-         multiply,zeroing_loop,combine_set,\
-         dead_loop,redundant_set,read_clobber,\
-         pure_removal,offset_sort"
-            .to_owned(),
-    );
-    let passes: Vec<_> = pass_specification.split(',').collect();
+/// Every pass we know about, in the order we run them.
+fn all_passes() -> Vec<Box<dyn Pass>> {
+    let simple = |name, transform| -> Box<dyn Pass> {
+        Box::new(SimplePass {
+            name,
+            transform,
+            suits_bf: true,
+        })
+    };
+    let non_bf = |name, transform| -> Box<dyn Pass> {
+        Box::new(SimplePass {
+            name,
+            transform,
+            suits_bf: false,
+        })
+    };
+
+    vec![
+        simple("combine_inc", combine_increments),
+        simple("combine_ptr", combine_ptr_increments),
+        simple("known_zero", annotate_known_zero),
+        simple("scan", extract_scan),
+        simple("multiply", extract_multiply),
+        simple("zeroing_loop", zeroing_loops),
+        non_bf("nested_multiply", extract_nested_multiply),
+        simple("if", extract_if),
+        simple("combine_set", combine_set_and_increments),
+        non_bf("const_prop", propagate_constants),
+        simple("dead_loop", remove_dead_loops),
+        simple("redundant_set", remove_redundant_sets),
+        simple("read_clobber", remove_read_clobber),
+        non_bf("write_const", combine_writes),
+        Box::new(PureRemovalPass),
+        // Dead store removal also removes pure code at the end of
+        // the program, so run it afterwards to get warnings.
+        simple("dead_store", remove_dead_stores),
+        simple("offset_sort", sort_by_offset),
+    ]
+}
+
+/// Runs a sequence of passes repeatedly until the program stops
+/// changing.
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    max_iterations: u64,
+    collect_stats: bool,
+    collect_remarks: bool,
+    /// Passes whose output we print to stderr.
+    print_after: Vec<String>,
+    print_after_all: bool,
+    /// Input for checking each pass with `Verifier`, if requested.
+    verify_input: Option<Vec<u8>>,
+}
 
-    let mut instrs = instrs;
+/// What the pass manager found whilst optimising.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub warnings: Vec<Warning>,
+    /// What each pass did, if requested with `with_remarks`.
+    pub remarks: Vec<Remark>,
+    /// Per-pass statistics, if requested with `with_stats`.
+    pub stats: Option<Stats>,
+    /// The first pass that changed the program's behaviour, if
+    /// requested with `with_verify`.
+    pub divergence: Option<Divergence>,
+}
 
-    if passes.contains(&"combine_inc") {
-        instrs = combine_increments(instrs);
+impl PassManager {
+    /// A pass manager for the comma-separated pass names in
+    /// `pass_specification`, or `DEFAULT_PASSES` if None. Passes
+    /// always run in the same order, regardless of the order given.
+    pub fn new(pass_specification: &Option<String>) -> Self {
+        let pass_specification = pass_specification
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PASSES.to_owned());
+        let names: Vec<_> = pass_specification.split(',').collect();
+
+        PassManager {
+            passes: all_passes()
+                .into_iter()
+                .filter(|pass| names.contains(&pass.name()))
+                .collect(),
+            max_iterations: MAX_OPT_ITERATIONS,
+            collect_stats: false,
+            collect_remarks: false,
+            print_after: vec![],
+            print_after_all: false,
+            verify_input: None,
+        }
     }
-    if passes.contains(&"combine_ptr") {
-        instrs = combine_ptr_increments(instrs);
+
+    /// Give up looking for a fixed point after running every pass
+    /// `max_iterations` times.
+    pub fn with_max_iterations(mut self, max_iterations: u64) -> Self {
+        self.max_iterations = max_iterations;
+        self
     }
-    if passes.contains(&"known_zero") {
-        instrs = annotate_known_zero(instrs);
+
+    /// Record how many instructions each pass changed, and how long
+    /// it took.
+    pub fn with_stats(mut self) -> Self {
+        self.collect_stats = true;
+        self
     }
-    if passes.contains(&"multiply") {
-        instrs = extract_multiply(instrs);
+
+    /// Record which loops each pass transformed.
+    pub fn with_remarks(mut self) -> Self {
+        self.collect_remarks = true;
+        self
     }
-    if passes.contains(&"zeroing_loop") {
-        instrs = zeroing_loops(instrs);
+
+    /// Print the IR to stderr after each run of the pass called
+    /// `pass_name`.
+    pub fn with_print_after(mut self, pass_name: &str) -> Self {
+        self.print_after.push(pass_name.to_owned());
+        self
     }
-    if passes.contains(&"combine_set") {
-        instrs = combine_set_and_increments(instrs);
+
+    /// Print the IR to stderr after every pass.
+    pub fn with_print_after_all(mut self) -> Self {
+        self.print_after_all = true;
+        self
     }
-    if passes.contains(&"dead_loop") {
-        instrs = remove_dead_loops(instrs);
+
+    /// After each pass, execute the program before and after it on
+    /// `input` and check that they behave the same.
+    pub fn with_verify(mut self, input: Vec<u8>) -> Self {
+        self.verify_input = Some(input);
+        self
     }
-    if passes.contains(&"redundant_set") {
-        instrs = remove_redundant_sets(instrs);
+
+    /// Apply our passes to `instrs`, and report what they did.
+    pub fn run_with_report(&self, mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Report) {
+        // Many of our individual peephole optimisations remove
+        // instructions, creating new opportunities to combine. We run
+        // until we've found a fixed-point where no further
+        // optimisations can be made.
+        let mut report = Report::default();
+        if self.collect_stats {
+            report.stats = Some(Stats {
+                passes: self
+                    .passes
+                    .iter()
+                    .map(|pass| PassStats::new(pass.name()))
+                    .collect(),
+                ..Stats::default()
+            });
+        }
+
+        // The number of times a pass has changed the program. If a
+        // pass left the program unchanged, and no pass has changed it
+        // since, we don't need to rerun it.
+        let mut num_changes: u64 = 0;
+        let mut unchanged_after: Vec<Option<u64>> = vec![None; self.passes.len()];
+
+        let mut verifier = self
+            .verify_input
+            .as_ref()
+            .map(|input| Verifier::new(&instrs, input.clone()));
+
+        for iteration in 1..=self.max_iterations {
+            // Some passes undo each other, so compare the whole
+            // iteration rather than checking whether any pass
+            // changed the program.
+            let iteration_start = fingerprint(&instrs);
+            if let Some(stats) = report.stats.as_mut() {
+                stats.iterations += 1;
+            }
+
+            for (i, (pass, unchanged)) in self
+                .passes
+                .iter()
+                .zip(unchanged_after.iter_mut())
+                .enumerate()
+            {
+                if *unchanged == Some(num_changes) {
+                    continue;
+                }
+
+                // We only need a copy of the program if we're
+                // describing what the pass did.
+                let print =
+                    self.print_after_all || self.print_after.iter().any(|name| name == pass.name());
+                let before = if print
+                    || self.collect_remarks
+                    || report.stats.is_some()
+                    || verifier.is_some()
+                {
+                    Some(instrs.clone())
+                } else {
+                    None
+                };
+
+                let start_time = Instant::now();
+                let (after, warning, changed) = pass.run(instrs);
+                let elapsed = start_time.elapsed();
+                report.warnings.extend(warning);
+
+                if let Some(stats) = report.stats.as_mut() {
+                    let pass_stats = &mut stats.passes[i];
+                    pass_stats.runs += 1;
+                    pass_stats.time += elapsed;
+                    if changed {
+                        let (removed, rewritten) = count_changes(before.as_ref().unwrap(), &after);
+                        pass_stats.changes += 1;
+                        pass_stats.removed += removed;
+                        pass_stats.rewritten += rewritten;
+                    }
+                }
+                if let Some(before) = &before {
+                    if print {
+                        let title = format!("{} (iteration {})", pass.name(), iteration);
+                        eprint!("{}", ir_after(&title, before, &after));
+                    }
+                    if self.collect_remarks && changed {
+                        loop_remarks(pass.name(), before, &after, &mut report.remarks);
+                    }
+                    if let Some(verifier) = verifier.as_mut() {
+                        if report.divergence.is_none() && changed {
+                            report.divergence = verifier.check(pass.name(), before, &after);
+                        }
+                    }
+                }
+
+                if changed {
+                    num_changes += 1;
+                    *unchanged = None;
+                } else {
+                    *unchanged = Some(num_changes);
+                }
+                instrs = after;
+            }
+
+            if fingerprint(&instrs) == iteration_start {
+                return (instrs, report);
+            }
+        }
+
+        report.warnings.push(Warning {
+            message: format!(
+                "Ran peephole optimisations {} times but did not reach a fixed point.",
+                self.max_iterations
+            ),
+            position: None,
+        });
+        (instrs, report)
     }
-    if passes.contains(&"read_clobber") {
-        instrs = remove_read_clobber(instrs);
+}
+
+/// Find the positions of every instruction in `instrs`, including
+/// loop bodies.
+fn instrs_by_position<'a>(instrs: &'a [AstNode], result: &mut HashMap<Position, Vec<&'a AstNode>>) {
+    for instr in instrs {
+        if let Some(position) = get_position(instr) {
+            result.entry(position).or_default().push(instr);
+        }
+        if let Loop { body, .. } | If { body, .. } = instr {
+            instrs_by_position(body, result);
+        }
     }
-    let warning = if passes.contains(&"pure_removal") {
-        let (removed, pure_warning) = remove_pure_code(instrs);
-        instrs = removed;
-        pure_warning
-    } else {
-        None
-    };
+}
+
+/// Describe what happened to the loops in `before` after running the
+/// pass `pass_name`. Passes keep the loop's position when they replace
+/// it, so we can see what each loop became.
+fn loop_remarks(pass_name: &str, before: &[AstNode], after: &[AstNode], remarks: &mut Vec<Remark>) {
+    let mut after_by_position = HashMap::new();
+    instrs_by_position(after, &mut after_by_position);
+
+    loop_remarks_inner(pass_name, before, &after_by_position, remarks);
+}
 
-    if passes.contains(&"offset_sort") {
-        instrs = sort_by_offset(instrs);
+/// Is there a multiply-add at `position` in `instrs`?
+fn contains_multiply_add<'a>(
+    instrs: impl IntoIterator<Item = &'a AstNode>,
+    position: Position,
+) -> bool {
+    instrs
+        .into_iter()
+        .any(|instr| matches!(instr, MultiplyAdd { position: Some(p), .. } if *p == position))
+}
+
+fn loop_remarks_inner(
+    pass_name: &str,
+    before: &[AstNode],
+    after_by_position: &HashMap<Position, Vec<&AstNode>>,
+    remarks: &mut Vec<Remark>,
+) {
+    for instr in before {
+        let (kind, body, position) = match instr {
+            Loop { body, position, .. } => ("Loop", &body[..], position),
+            If { body, position } => ("If", &body[..], position),
+            Scan { position, .. } => ("Scan", &[][..], position),
+            _ => continue,
+        };
+        let position = match position {
+            Some(position) => *position,
+            None => continue,
+        };
+
+        let replacements = after_by_position
+            .get(&position)
+            .map(|instrs| &instrs[..])
+            .unwrap_or(&[]);
+        let same_kind = replacements.iter().any(|replacement| {
+            std::mem::discriminant(*replacement) == std::mem::discriminant(instr)
+        });
+        if same_kind {
+            // Nested multiplies keep the loop to run the first
+            // iteration, so look for a new multiply-add inside it.
+            if contains_multiply_add(replacements.iter().copied(), position)
+                && !contains_multiply_add(body, position)
+            {
+                remarks.push(Remark {
+                    message: format!("{} converted to a nested multiply ({}).", kind, pass_name),
+                    position: Some(position),
+                });
+            }
+            // This loop is still here, but its body may have changed.
+            loop_remarks_inner(pass_name, body, after_by_position, remarks);
+            continue;
+        }
+
+        let description = match replacements.first() {
+            Some(MultiplyMove { .. }) => "converted to a multiply-move",
+            Some(Scan { .. }) => "converted to a scan",
+            Some(If { .. }) => "converted to an if",
+            Some(Set { .. }) => "converted to a set",
+            Some(_) => "simplified",
+            None => "removed as dead",
+        };
+        remarks.push(Remark {
+            message: format!("{} {} ({}).", kind, description, pass_name),
+            position: Some(position),
+        });
     }
+}
 
-    (instrs, warning)
+/// Given a sequence of BF instructions, apply peephole optimisations
+/// (repeatedly if necessary).
+#[cfg(test)]
+pub fn optimize(
+    instrs: Vec<AstNode>,
+    pass_specification: &Option<String>,
+) -> (Vec<AstNode>, Vec<Warning>) {
+    let (instrs, report) = PassManager::new(pass_specification).run_with_report(instrs);
+    (instrs, report.warnings)
 }
 
-/// Defines a method on iterators to map a function over all loop bodies.
+/// Defines a method on iterators to map a function over all loop
+/// (and if) bodies.
 trait MapLoopsExt: Iterator<Item = AstNode> {
     fn map_loops<F>(&mut self, f: F) -> Vec<AstNode>
     where
         F: Fn(Vec<AstNode>) -> Vec<AstNode>,
     {
         self.map(|instr| match instr {
-            Loop { body, position } => Loop {
+            Loop {
+                body,
+                offset,
+                position,
+            } => Loop {
+                body: f(body),
+                offset,
+                position,
+            },
+            If { body, position } => If {
                 body: f(body),
                 position,
             },
@@ -134,18 +489,67 @@ trait MapLoopsExt: Iterator<Item = AstNode> {
 
 impl<I> MapLoopsExt for I where I: Iterator<Item = AstNode> {}
 
+/// The cell values we know at a point in the program, relative to
+/// the cell pointer.
+#[derive(Clone)]
+pub struct KnownCells {
+    /// Cells with a known value (Some) or a value we've lost track
+    /// of (None).
+    values: HashMap<isize, Option<Cell>>,
+    /// Whether every cell not in `values` is zero.
+    others_zero: bool,
+}
+
+impl KnownCells {
+    /// Every cell is zero, as at the start of the program.
+    pub fn zeroed() -> Self {
+        KnownCells {
+            values: HashMap::new(),
+            others_zero: true,
+        }
+    }
+
+    pub fn unknown() -> Self {
+        KnownCells {
+            values: HashMap::new(),
+            others_zero: false,
+        }
+    }
+
+    pub fn get(&self, offset: isize) -> Option<Cell> {
+        match self.values.get(&offset) {
+            Some(value) => *value,
+            None if self.others_zero => Some(Wrapping(0)),
+            None => None,
+        }
+    }
+
+    pub fn set(&mut self, offset: isize, value: Option<Cell>) {
+        self.values.insert(offset, value);
+    }
+
+    /// Update offsets after the cell pointer has moved by `amount`.
+    pub fn shift(&mut self, amount: isize) {
+        self.values = self
+            .values
+            .drain()
+            .map(|(offset, value)| (offset - amount, value))
+            .collect();
+    }
+}
+
 /// Given an index into a vector of instructions, find the index of
-/// the previous instruction that modified the current cell. If we're
-/// unsure, or there isn't one, return None.
+/// the previous instruction that modified the cell at `offset` from
+/// the current cell. If we're unsure, or there isn't one, return None.
 ///
 /// Note this totally ignores the instruction at the index given, even
 /// if it has an offset. E.g. if the instruction is
 /// Set {amount:100, offset: 1}, we're still considering previous instructions that
-/// modify the current cell, not the (cell_index + 1)th cell.
-pub fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
+/// modify the cell at `offset`, not the (cell_index + 1)th cell.
+pub fn previous_cell_change(instrs: &[AstNode], index: usize, offset: isize) -> Option<usize> {
     assert!(index < instrs.len());
 
-    let mut needed_offset = 0;
+    let mut needed_offset = offset;
     for i in (0..index).rev() {
         match instrs[i] {
             Increment { offset, .. } | Set { offset, .. } => {
@@ -166,11 +570,25 @@ pub fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            MultiplyAdd { ref changes, .. } => {
+                // Only the target cells are written to.
+                if changes.keys().any(|&(target, _)| target == needed_offset) {
+                    return Some(i);
+                }
+            }
             // No cells changed, so just keep working backwards.
-            Write { .. } => {}
-            // These instructions may have modified the cell, so
-            // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Write { .. } | WriteConst { .. } => {}
+            // We can't remove or combine reads, so we return None
+            // if this read changed the cell.
+            Read { offset, .. } => {
+                if offset == needed_offset {
+                    return None;
+                }
+            }
+            // These instructions may have modified the cell (or
+            // moved the pointer by an unknown amount), so we return
+            // None for "I don't know".
+            Loop { .. } | If { .. } | Scan { .. } => return None,
         }
     }
     None
@@ -183,10 +601,10 @@ pub fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
 /// vector. This proved extremely hard to reason about. Instead, we
 /// have copied the body of `previous_cell_change` and highlighted the
 /// differences.
-pub fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
+pub fn next_cell_change(instrs: &[AstNode], index: usize, offset: isize) -> Option<usize> {
     assert!(index < instrs.len());
 
-    let mut needed_offset = 0;
+    let mut needed_offset = offset;
     // Unlike previous_cell_change, we iterate forward.
     for (i, instr) in instrs.iter().enumerate().skip(index + 1) {
         match *instr {
@@ -209,11 +627,25 @@ pub fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            MultiplyAdd { ref changes, .. } => {
+                // Only the target cells are written to.
+                if changes.keys().any(|&(target, _)| target == needed_offset) {
+                    return Some(i);
+                }
+            }
             // No cells changed, so just keep working backwards.
-            Write { .. } => {}
-            // These instructions may have modified the cell, so
-            // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Write { .. } | WriteConst { .. } => {}
+            // We can't remove or combine reads, so we return None
+            // if this read changed the cell.
+            Read { offset, .. } => {
+                if offset == needed_offset {
+                    return None;
+                }
+            }
+            // These instructions may have modified the cell (or
+            // moved the pointer by an unknown amount), so we return
+            // None for "I don't know".
+            Loop { .. } | If { .. } | Scan { .. } => return None,
         }
     }
     None
@@ -301,9 +733,9 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
 
     for (index, instr) in instrs.iter().enumerate() {
         match *instr {
-            Read { .. } => {
+            Read { offset, .. } => {
                 // If we can find the time this cell was modified:
-                if let Some(prev_modify_index) = previous_cell_change(&instrs, index) {
+                if let Some(prev_modify_index) = previous_cell_change(&instrs, index, offset) {
                     // This modify instruction is not redundant if we
                     // wrote anything afterwards.
                     if let Some(write_index) = last_write_index {
@@ -312,9 +744,12 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
                         }
                     }
 
-                    // MultiplyMove instructions are not redundant,
-                    // because they affect other cells too.
-                    if matches!(instrs[prev_modify_index], MultiplyMove { ..}) {
+                    // MultiplyMove and MultiplyAdd instructions are
+                    // not redundant, because they affect other cells too.
+                    if matches!(
+                        instrs[prev_modify_index],
+                        MultiplyMove { .. } | MultiplyAdd { .. }
+                    ) {
                         continue;
                     }
 
@@ -336,23 +771,220 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .map_loops(remove_read_clobber)
 }
 
+/// Which cells may be read before they're next overwritten, relative
+/// to the cell pointer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Liveness {
+    /// Whether cells are live by default.
+    others_live: bool,
+    /// Cells whose liveness is the opposite of `others_live`.
+    exceptions: HashSet<isize>,
+}
+
+impl Liveness {
+    fn all_live() -> Self {
+        Liveness {
+            others_live: true,
+            exceptions: HashSet::new(),
+        }
+    }
+
+    fn all_dead() -> Self {
+        Liveness {
+            others_live: false,
+            exceptions: HashSet::new(),
+        }
+    }
+
+    fn is_live(&self, offset: isize) -> bool {
+        self.others_live != self.exceptions.contains(&offset)
+    }
+
+    fn set(&mut self, offset: isize, live: bool) {
+        if live == self.others_live {
+            self.exceptions.remove(&offset);
+        } else {
+            self.exceptions.insert(offset);
+        }
+    }
+
+    /// Update offsets for the liveness before the cell pointer moved
+    /// by `amount`.
+    fn unshift(&mut self, amount: isize) {
+        self.exceptions = self
+            .exceptions
+            .drain()
+            .map(|offset| offset + amount)
+            .collect();
+    }
+
+    /// The cells that are live in either `self` or `other`.
+    fn union(&self, other: &Liveness) -> Liveness {
+        let exceptions = match (self.others_live, other.others_live) {
+            (true, true) => self
+                .exceptions
+                .intersection(&other.exceptions)
+                .cloned()
+                .collect(),
+            (false, false) => self.exceptions.union(&other.exceptions).cloned().collect(),
+            (true, false) => self
+                .exceptions
+                .difference(&other.exceptions)
+                .cloned()
+                .collect(),
+            (false, true) => other
+                .exceptions
+                .difference(&self.exceptions)
+                .cloned()
+                .collect(),
+        };
+        Liveness {
+            others_live: self.others_live || other.others_live,
+            exceptions,
+        }
+    }
+}
+
+/// Does executing `body` leave the cell pointer where it started?
+fn is_balanced(body: &[AstNode]) -> bool {
+    matches!(modified_cells(body), Some((_, 0)))
+}
+
+/// Remove instructions that modify cells which are always overwritten
+/// before they're read, e.g. the first increment in `+>+<[-]`.
+///
+/// Cell values at the end of the program aren't observable, so this
+/// may change them.
+pub fn remove_dead_stores(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    remove_dead_stores_inner(instrs, &mut Liveness::all_dead())
+}
+
+/// Remove dead stores from `instrs`, given the cells that are live
+/// afterwards. Updates `live` to the cells that are live beforehand.
+fn remove_dead_stores_inner(instrs: Vec<AstNode>, live: &mut Liveness) -> Vec<AstNode> {
+    let mut result = vec![];
+
+    for instr in instrs.into_iter().rev() {
+        match instr {
+            Set { offset, .. } => {
+                if live.is_live(offset) {
+                    live.set(offset, false);
+                    result.push(instr);
+                }
+            }
+            Increment { offset, .. } => {
+                if live.is_live(offset) {
+                    result.push(instr);
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                live.unshift(amount);
+                result.push(instr);
+            }
+            Read { offset, .. } => {
+                live.set(offset, false);
+                result.push(instr);
+            }
+            Write { offset, .. } => {
+                live.set(offset, true);
+                result.push(instr);
+            }
+            WriteConst { .. } => {
+                result.push(instr);
+            }
+            MultiplyMove { ref changes, .. } => {
+                // Targets are increased, so their liveness doesn't
+                // change.
+                if live.is_live(0) || changes.keys().any(|&target| live.is_live(target)) {
+                    live.set(0, true);
+                    result.push(instr);
+                }
+            }
+            MultiplyAdd { ref changes, .. } => {
+                live.set(0, true);
+                for &(_, operand) in changes.keys() {
+                    live.set(operand, true);
+                }
+                result.push(instr);
+            }
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
+                let body = if is_balanced(&body) {
+                    // The body finishes at the loop head, so find the
+                    // cells live there by iterating to a fixed point.
+                    let mut head = live.clone();
+                    head.set(offset, true);
+                    loop {
+                        let mut body_live = head.clone();
+                        let new_body = remove_dead_stores_inner(body.clone(), &mut body_live);
+                        let new_head = head.union(&body_live);
+                        if new_head == head {
+                            *live = head;
+                            break new_body;
+                        }
+                        head = new_head;
+                    }
+                } else {
+                    *live = Liveness::all_live();
+                    remove_dead_stores_inner(body, &mut Liveness::all_live())
+                };
+                result.push(Loop {
+                    body,
+                    offset,
+                    position,
+                });
+            }
+            If { body, position } => {
+                let body = if is_balanced(&body) {
+                    // The body must still finish on a zero cell.
+                    let mut body_live = live.clone();
+                    body_live.set(0, true);
+                    let body = remove_dead_stores_inner(body, &mut body_live);
+                    *live = live.union(&body_live);
+                    live.set(0, true);
+                    body
+                } else {
+                    *live = Liveness::all_live();
+                    remove_dead_stores_inner(body, &mut Liveness::all_live())
+                };
+                result.push(If { body, position });
+            }
+            Scan { .. } => {
+                *live = Liveness::all_live();
+                result.push(instr);
+            }
+        }
+    }
+
+    result.reverse();
+    result
+}
+
 /// Convert [-] to Set 0.
 pub fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
     instrs
         .into_iter()
         .map(|instr| {
-            if let Loop { ref body, position } = instr {
+            if let Loop {
+                ref body,
+                offset,
+                position,
+            } = instr
+            {
                 // If the loop is [-]
-                if body.len() == 1 {
-                    if let Increment {
-                        amount: Wrapping(-1),
-                        offset: 0,
-                        ..
-                    } = body[0]
-                    {
+                if let [Increment {
+                    amount: Wrapping(-1),
+                    offset: inc_offset,
+                    ..
+                }] = body[..]
+                {
+                    if inc_offset == offset {
                         return Set {
                             amount: Wrapping(0),
-                            offset: 0,
+                            offset,
                             position,
                         };
                     }
@@ -363,29 +995,30 @@ pub fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .map_loops(zeroing_loops)
 }
 
-/// Remove any loops where we know the current cell is zero.
+/// Remove any loops (including scans and ifs) where we know the
+/// current cell is zero.
 pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
     instrs
         .clone()
         .into_iter()
         .enumerate()
         .filter(|&(index, ref instr)| {
-            match *instr {
-                Loop { .. } => {}
+            let loop_offset = match *instr {
+                Loop { offset, .. } => offset,
+                If { .. } | Scan { .. } => 0,
                 // Keep all instructions that aren't loops.
                 _ => {
                     return true;
                 }
-            }
+            };
 
             // Find the previous change instruction:
-            if let Some(prev_change_index) = previous_cell_change(&instrs, index) {
+            if let Some(prev_change_index) = previous_cell_change(&instrs, index, loop_offset) {
                 let prev_instr = &instrs[prev_change_index];
                 // If the previous instruction set to zero, our loop is dead.
                 // TODO: MultiplyMove also zeroes the current cell.
                 if let Set {
                     amount: Wrapping(0),
-                    offset: 0,
                     ..
                 } = *prev_instr
                 {
@@ -405,13 +1038,48 @@ pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
 /// Increment { amount: 1, offset: 1 }
 /// Increment { amount: 2, offset: 2 }
 /// PointerIncrement(1)
+///
+/// Reads and writes use offsets too, but we don't reorder
+/// instructions across them.
+///
+/// Loops whose bodies only use offsets are part of the sequence too,
+/// so given ">>[-<+>]" we return:
+/// Loop { offset: 2 }
+///   Increment { amount: 1, offset: 1 }
+///   Increment { amount: -1, offset: 2 }
+/// PointerIncrement(2)
 pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut sequence = vec![];
     let mut result = vec![];
 
     for instr in instrs {
+        let instr = match instr {
+            Loop {
+                body,
+                offset,
+                position,
+            } => Loop {
+                body: sort_by_offset(body),
+                offset,
+                position,
+            },
+            If { body, position } => If {
+                body: sort_by_offset(body),
+                position,
+            },
+            other => other,
+        };
+
         match instr {
-            Increment { .. } | Set { .. } | PointerIncrement { .. } => {
+            Increment { .. }
+            | Set { .. }
+            | PointerIncrement { .. }
+            | Read { .. }
+            | Write { .. }
+            | WriteConst { .. } => {
+                sequence.push(instr);
+            }
+            Loop { ref body, .. } if is_offset_only(body) => {
                 sequence.push(instr);
             }
             _ => {
@@ -419,14 +1087,7 @@ pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     result.extend(sort_sequence_by_offset(sequence));
                     sequence = vec![];
                 }
-                if let Loop { body, position } = instr {
-                    result.push(Loop {
-                        body: sort_by_offset(body),
-                        position,
-                    });
-                } else {
-                    result.push(instr);
-                }
+                result.push(instr);
             }
         }
     }
@@ -438,32 +1099,90 @@ pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     result
 }
 
-/// Given a `HashMap` with orderable keys, return the values according to
-/// the key order.
-/// {2: 'foo': 1: 'bar'} => vec!['bar', 'foo']
-fn ordered_values<K: Ord + Hash + Eq, V>(map: HashMap<K, V>) -> Vec<V> {
-    let mut items: Vec<_> = map.into_iter().collect();
-    items.sort_by(|a, b| a.0.cmp(&b.0));
-    items.into_iter().map(|(_, v)| v).collect()
+/// Can we move these instructions to different cells just by
+/// changing their offsets?
+fn is_offset_only(instrs: &[AstNode]) -> bool {
+    instrs.iter().all(|instr| match *instr {
+        Increment { .. } | Set { .. } | Read { .. } | Write { .. } | WriteConst { .. } => true,
+        Loop { ref body, .. } => is_offset_only(body),
+        _ => false,
+    })
 }
 
-/// Given a BF program, combine sets/increments using offsets so we
-/// have single `PointerIncrement` at the end.
-fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut instrs_by_offset: HashMap<isize, Vec<AstNode>> = HashMap::new();
-    let mut current_offset = 0;
-    let mut last_ptr_inc_pos = None;
-
-    for instr in instrs {
-        match instr {
+/// Add `shift` to the offsets of instructions that satisfy
+/// `is_offset_only`.
+fn shift_offsets(instrs: Vec<AstNode>, shift: isize) -> Vec<AstNode> {
+    instrs
+        .into_iter()
+        .map(|instr| match instr {
             Increment {
                 amount,
                 offset,
                 position,
-            } => {
+            } => Increment {
+                amount,
+                offset: offset + shift,
+                position,
+            },
+            Set {
+                amount,
+                offset,
+                position,
+            } => Set {
+                amount,
+                offset: offset + shift,
+                position,
+            },
+            Read { offset, position } => Read {
+                offset: offset + shift,
+                position,
+            },
+            Write { offset, position } => Write {
+                offset: offset + shift,
+                position,
+            },
+            Loop {
+                body,
+                offset,
+                position,
+            } => Loop {
+                body: shift_offsets(body, shift),
+                offset: offset + shift,
+                position,
+            },
+            // Constant writes don't access any cells.
+            WriteConst { .. } => instr,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Given a `HashMap` with orderable keys, return the values according to
+/// the key order.
+/// {2: 'foo': 1: 'bar'} => vec!['bar', 'foo']
+fn ordered_values<K: Ord + Hash + Eq, V>(map: HashMap<K, V>) -> Vec<V> {
+    let mut items: Vec<_> = map.into_iter().collect();
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items.into_iter().map(|(_, v)| v).collect()
+}
+
+/// Given a BF program, combine sets/increments using offsets so we
+/// have single `PointerIncrement` at the end.
+fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut instrs_by_offset: HashMap<isize, Vec<AstNode>> = HashMap::new();
+    let mut current_offset = 0;
+    let mut last_ptr_inc_pos = None;
+    let mut results: Vec<AstNode> = vec![];
+
+    for instr in instrs {
+        match instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => {
                 let new_offset = offset + current_offset;
-                let same_offset_instrs =
-                    instrs_by_offset.entry(new_offset).or_insert_with(|| vec![]);
+                let same_offset_instrs = instrs_by_offset.entry(new_offset).or_default();
                 same_offset_instrs.push(Increment {
                     amount,
                     offset: new_offset,
@@ -476,8 +1195,7 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
                 position,
             } => {
                 let new_offset = offset + current_offset;
-                let same_offset_instrs =
-                    instrs_by_offset.entry(new_offset).or_insert_with(|| vec![]);
+                let same_offset_instrs = instrs_by_offset.entry(new_offset).or_default();
                 same_offset_instrs.push(Set {
                     amount,
                     offset: new_offset,
@@ -488,18 +1206,29 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
                 current_offset += amount;
                 last_ptr_inc_pos = Some(position);
             }
+            Loop { .. } | Read { .. } | Write { .. } | WriteConst { .. } => {
+                // We can't reorder instructions across loops or I/O,
+                // so append everything we've seen so far.
+                for same_offset_instrs in ordered_values(instrs_by_offset) {
+                    results.extend(same_offset_instrs);
+                }
+                instrs_by_offset = HashMap::new();
+
+                // The pointer hasn't moved yet, so this instruction
+                // needs `current_offset` adding to its offsets.
+                results.extend(shift_offsets(vec![instr], current_offset));
+            }
             // We assume that we were only given a Vec of
-            // Increment/Set/PointerIncrement instructions. It's
-            // the job of this function to create instructions with
-            // offset.
+            // Increment/Set/PointerIncrement/Read/Write/WriteConst
+            // instructions and loops that only use offsets. It's the job of this
+            // function to create instructions with offset.
             _ => unreachable!(),
         }
     }
 
     // Append the increment/set instructions, in offset order.
-    let mut results: Vec<AstNode> = vec![];
     for same_offset_instrs in ordered_values(instrs_by_offset) {
-        results.extend(same_offset_instrs.into_iter());
+        results.extend(same_offset_instrs);
     }
 
     // Add a single PointerIncrement at the end, reflecting the net
@@ -625,22 +1354,22 @@ fn remove_redundant_sets_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut redundant_instr_positions = HashSet::new();
 
     for (index, instr) in instrs.iter().enumerate() {
-        match *instr {
-            Loop { .. } | MultiplyMove { .. } => {
-                // There's no point setting to zero after a loop, as
-                // the cell is already zero.
-                if let Some(next_index) = next_cell_change(&instrs, index) {
-                    if let Set {
-                        amount: Wrapping(0),
-                        offset: 0,
-                        ..
-                    } = instrs[next_index]
-                    {
-                        redundant_instr_positions.insert(next_index);
-                    }
-                }
+        let zeroed_offset = match *instr {
+            Loop { offset, .. } => offset,
+            If { .. } | MultiplyMove { .. } | Scan { .. } => 0,
+            _ => continue,
+        };
+
+        // There's no point setting to zero after a loop, as
+        // the cell is already zero.
+        if let Some(next_index) = next_cell_change(&instrs, index, zeroed_offset) {
+            if let Set {
+                amount: Wrapping(0),
+                ..
+            } = instrs[next_index]
+            {
+                redundant_instr_positions.insert(next_index);
             }
-            _ => {}
         }
     }
 
@@ -652,6 +1381,204 @@ fn remove_redundant_sets_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .map_loops(remove_redundant_sets_inner)
 }
 
+/// The cells that `instrs` may modify, relative to the initial cell
+/// pointer, along with the net pointer movement. Returns None if we
+/// can't tell, e.g. because `instrs` contains a scan.
+pub fn modified_cells(instrs: &[AstNode]) -> Option<(HashSet<isize>, isize)> {
+    let mut modified = HashSet::new();
+    let mut cell_index: isize = 0;
+
+    for instr in instrs {
+        match instr {
+            Increment { offset, .. } | Set { offset, .. } | Read { offset, .. } => {
+                modified.insert(cell_index + offset);
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            Write { .. } | WriteConst { .. } => {}
+            MultiplyMove { changes, .. } => {
+                modified.insert(cell_index);
+                modified.extend(changes.keys().map(|target| cell_index + target));
+            }
+            MultiplyAdd { changes, .. } => {
+                modified.extend(changes.keys().map(|(target, _)| cell_index + target));
+            }
+            // We can only say where a loop or if leaves the pointer
+            // if its body has no net movement.
+            Loop { body, .. } | If { body, .. } => match modified_cells(body)? {
+                (body_modified, 0) => {
+                    modified.extend(body_modified.into_iter().map(|cell| cell_index + cell));
+                }
+                _ => return None,
+            },
+            Scan { .. } => return None,
+        }
+    }
+
+    Some((modified, cell_index))
+}
+
+/// Track the values of cells through the program, and replace
+/// instructions whose effect we know at compile time. Increments of
+/// known cells become sets, multiply loops with a known source become
+/// sets or increments, and loops whose cell is known to be zero are
+/// removed.
+pub fn propagate_constants(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    propagate_constants_inner(instrs, &mut KnownCells::zeroed())
+}
+
+fn propagate_constants_inner(instrs: Vec<AstNode>, known: &mut KnownCells) -> Vec<AstNode> {
+    let mut result = vec![];
+
+    for instr in instrs {
+        match instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => match known.get(offset) {
+                Some(value) => {
+                    known.set(offset, Some(value + amount));
+                    result.push(Set {
+                        amount: value + amount,
+                        offset,
+                        position,
+                    });
+                }
+                None => result.push(instr),
+            },
+            Set { amount, offset, .. } => {
+                // Setting a cell to the value it already has does
+                // nothing.
+                if known.get(offset) != Some(amount) {
+                    known.set(offset, Some(amount));
+                    result.push(instr);
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                known.shift(amount);
+                result.push(instr);
+            }
+            Read { offset, .. } => {
+                known.set(offset, None);
+                result.push(instr);
+            }
+            Write { .. } | WriteConst { .. } => {
+                result.push(instr);
+            }
+            MultiplyMove { changes, position } => match known.get(0) {
+                // Multiplying by zero does nothing.
+                Some(Wrapping(0)) => {}
+                Some(source) => {
+                    let mut targets: Vec<_> = changes.into_iter().collect();
+                    targets.sort();
+
+                    for (target, factor) in targets {
+                        let amount = source * factor;
+                        match known.get(target) {
+                            Some(value) => {
+                                known.set(target, Some(value + amount));
+                                result.push(Set {
+                                    amount: value + amount,
+                                    offset: target,
+                                    position,
+                                });
+                            }
+                            None => {
+                                if amount != Wrapping(0) {
+                                    result.push(Increment {
+                                        amount,
+                                        offset: target,
+                                        position,
+                                    });
+                                }
+                            }
+                        }
+                    }
+
+                    known.set(0, Some(Wrapping(0)));
+                    result.push(Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position,
+                    });
+                }
+                None => {
+                    for &target in changes.keys() {
+                        known.set(target, None);
+                    }
+                    known.set(0, Some(Wrapping(0)));
+                    result.push(MultiplyMove { changes, position });
+                }
+            },
+            MultiplyAdd { ref changes, .. } => {
+                for &(target, _) in changes.keys() {
+                    known.set(target, None);
+                }
+                result.push(instr);
+            }
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
+                // A loop on a zero cell never runs.
+                if known.get(offset) == Some(Wrapping(0)) {
+                    continue;
+                }
+
+                let body = propagate_loop_body(body, known);
+                known.set(offset, Some(Wrapping(0)));
+                result.push(Loop {
+                    body,
+                    offset,
+                    position,
+                });
+            }
+            If { body, position } => {
+                if known.get(0) == Some(Wrapping(0)) {
+                    continue;
+                }
+
+                let body = propagate_loop_body(body, known);
+                known.set(0, Some(Wrapping(0)));
+                result.push(If { body, position });
+            }
+            Scan { .. } => {
+                // A scan starting on a zero cell doesn't move.
+                if known.get(0) == Some(Wrapping(0)) {
+                    continue;
+                }
+
+                *known = KnownCells::unknown();
+                known.set(0, Some(Wrapping(0)));
+                result.push(instr);
+            }
+        }
+    }
+
+    result
+}
+
+/// Propagate constants into the body of a loop or if, and update
+/// `known` for the cell values after it. Cells that the body never
+/// modifies keep their values throughout.
+fn propagate_loop_body(body: Vec<AstNode>, known: &mut KnownCells) -> Vec<AstNode> {
+    match modified_cells(&body) {
+        Some((modified, 0)) => {
+            for cell in modified {
+                known.set(cell, None);
+            }
+            propagate_constants_inner(body, &mut known.clone())
+        }
+        _ => {
+            *known = KnownCells::unknown();
+            propagate_constants_inner(body, &mut KnownCells::unknown())
+        }
+    }
+}
+
 pub fn annotate_known_zero(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut result = vec![];
 
@@ -687,10 +1614,15 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
         let instr = instr.clone();
 
         match instr {
-            // After a loop, we know the cell is currently zero.
-            Loop { body, position } => {
+            // After a loop, we know the loop's cell is currently zero.
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
                 result.push(Loop {
                     body: annotate_known_zero_inner(body),
+                    offset,
                     position,
                 });
                 // Treat this set as positioned at the ].
@@ -701,13 +1633,49 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
 
                 let set_instr = Set {
                     amount: Wrapping(0),
-                    offset: 0,
+                    offset,
                     position: set_pos,
                 };
                 if instrs.get(i + 1) != Some(&set_instr) {
                     result.push(set_instr.clone());
                 }
             }
+            // After an if, the cell is zero whether or not the body
+            // ran.
+            If { body, position } => {
+                result.push(If {
+                    body: annotate_known_zero_inner(body),
+                    position,
+                });
+
+                let set_instr = Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: position.map(|if_pos| Position {
+                        start: if_pos.end,
+                        end: if_pos.end,
+                    }),
+                };
+                if instrs.get(i + 1) != Some(&set_instr) {
+                    result.push(set_instr);
+                }
+            }
+            // Likewise, a scan always finishes on a zero cell.
+            Scan { position, .. } => {
+                result.push(instr);
+
+                let set_instr = Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: position.map(|scan_pos| Position {
+                        start: scan_pos.end,
+                        end: scan_pos.end,
+                    }),
+                };
+                if instrs.get(i + 1) != Some(&set_instr) {
+                    result.push(set_instr);
+                }
+            }
             _ => {
                 result.push(instr);
             }
@@ -717,16 +1685,145 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
     result
 }
 
+/// Replace writes of cells whose value is known at compile time with
+/// `WriteConst`, merging neighbouring constant writes. For example,
+/// `+.+.` writes the bytes 1 and 2 with a single instruction.
+pub fn combine_writes(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    combine_writes_inner(instrs, &mut KnownCells::zeroed())
+}
+
+fn combine_writes_inner(instrs: Vec<AstNode>, known: &mut KnownCells) -> Vec<AstNode> {
+    let mut result = vec![];
+    // The index in `result` of a WriteConst we can still append to.
+    let mut last_write_const: Option<usize> = None;
+
+    for instr in instrs {
+        match instr {
+            Increment { amount, offset, .. } => {
+                let value = known.get(offset).map(|value| value + amount);
+                known.set(offset, value);
+                result.push(instr);
+            }
+            Set { amount, offset, .. } => {
+                known.set(offset, Some(amount));
+                result.push(instr);
+            }
+            PointerIncrement { amount, .. } => {
+                // Don't merge writes across pointer movement, as the
+                // movement may be out of bounds.
+                known.shift(amount);
+                last_write_const = None;
+                result.push(instr);
+            }
+            Read { offset, .. } => {
+                known.set(offset, None);
+                last_write_const = None;
+                result.push(instr);
+            }
+            Write { offset, position } => match known.get(offset) {
+                Some(value) => {
+                    let byte = value.0 as u8;
+                    match last_write_const.map(|index| &mut result[index]) {
+                        Some(WriteConst {
+                            bytes,
+                            position: const_position,
+                        }) => {
+                            bytes.push(byte);
+                            // Span every write we've merged, even if
+                            // there are other instructions between.
+                            if let (Some(const_pos), Some(pos)) = (*const_position, position) {
+                                *const_position = Some(Position {
+                                    start: const_pos.start,
+                                    end: pos.end,
+                                });
+                            }
+                        }
+                        _ => {
+                            last_write_const = Some(result.len());
+                            result.push(WriteConst {
+                                bytes: vec![byte],
+                                position,
+                            });
+                        }
+                    }
+                }
+                None => {
+                    last_write_const = None;
+                    result.push(instr);
+                }
+            },
+            WriteConst { .. } => {
+                last_write_const = Some(result.len());
+                result.push(instr);
+            }
+            MultiplyMove { ref changes, .. } => {
+                let source = known.get(0);
+                for (&target, &factor) in changes {
+                    let value = match (source, known.get(target)) {
+                        (Some(source), Some(target_value)) => Some(target_value + source * factor),
+                        _ => None,
+                    };
+                    known.set(target, value);
+                }
+                known.set(0, Some(Wrapping(0)));
+                result.push(instr);
+            }
+            MultiplyAdd { ref changes, .. } => {
+                for &(target, _) in changes.keys() {
+                    known.set(target, None);
+                }
+                result.push(instr);
+            }
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
+                *known = KnownCells::unknown();
+                known.set(offset, Some(Wrapping(0)));
+                last_write_const = None;
+                result.push(Loop {
+                    body: combine_writes_inner(body, &mut KnownCells::unknown()),
+                    offset,
+                    position,
+                });
+            }
+            If { body, position } => {
+                *known = KnownCells::unknown();
+                known.set(0, Some(Wrapping(0)));
+                last_write_const = None;
+                result.push(If {
+                    body: combine_writes_inner(body, &mut KnownCells::unknown()),
+                    position,
+                });
+            }
+            Scan { .. } => {
+                *known = KnownCells::unknown();
+                known.set(0, Some(Wrapping(0)));
+                last_write_const = None;
+                result.push(instr);
+            }
+        }
+    }
+
+    result
+}
+
 /// Remove code at the end of the program that has no side
 /// effects. This means we have no write commands afterwards, nor
 /// loops (which may not terminate so we should not remove).
 pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>) {
     let mut pure_instrs = vec![];
-    while !instrs.is_empty() {
-        let last_instr = instrs.pop().unwrap();
-
+    while let Some(last_instr) = instrs.pop() {
         match last_instr {
-            Read { .. } | Write { .. } | Loop { .. } => {
+            // Scans may move the pointer out of bounds, so they
+            // aren't pure.
+            Read { .. }
+            | Write { .. }
+            | WriteConst { .. }
+            | Loop { .. }
+            | If { .. }
+            | Scan { .. } => {
                 instrs.push(last_instr);
                 break;
             }
@@ -736,6 +1833,18 @@ pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warni
         }
     }
 
+    // Sorting by offset can move pointer increments after the I/O
+    // that needed them, so only warn about instructions that are
+    // after the last remaining instruction in the source.
+    let last_end = instrs.last().and_then(get_position).map(|pos| pos.end);
+    let pure_instrs: Vec<_> = pure_instrs
+        .into_iter()
+        .filter(|instr| match (get_position(instr), last_end) {
+            (Some(pos), Some(end)) => pos.start > end,
+            _ => true,
+        })
+        .collect();
+
     let warning = if pure_instrs.is_empty() {
         None
     } else {
@@ -754,6 +1863,96 @@ pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warni
     (instrs, warning)
 }
 
+/// Convert loops that only move the pointer, such as `[>]` or
+/// `[<<]`, to scans.
+pub fn extract_scan(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    instrs
+        .into_iter()
+        .map(|instr| {
+            if let Loop {
+                ref body,
+                offset: 0,
+                position,
+            } = instr
+            {
+                if let [PointerIncrement { amount, .. }] = body[..] {
+                    return Scan {
+                        stride: amount,
+                        position,
+                    };
+                }
+            }
+            instr
+        })
+        .map_loops(extract_scan)
+}
+
+/// Does executing `body` always leave the pointer on a zero cell?
+/// E.g. `>+<[-]` or `[-]>`.
+fn finishes_on_zero_cell(body: &[AstNode]) -> bool {
+    // The offset of the final cell relative to the pointer at each
+    // instruction, working backwards.
+    let mut needed_offset = 0;
+    for instr in body.iter().rev() {
+        match *instr {
+            PointerIncrement { amount, .. } => {
+                needed_offset += amount;
+            }
+            Set { amount, offset, .. } => {
+                if offset == needed_offset {
+                    return amount == Wrapping(0);
+                }
+            }
+            Increment { offset, .. } => {
+                if offset == needed_offset {
+                    return false;
+                }
+            }
+            MultiplyMove { ref changes, .. } => {
+                if needed_offset == 0 {
+                    return true;
+                }
+                if changes.contains_key(&needed_offset) {
+                    return false;
+                }
+            }
+            MultiplyAdd { ref changes, .. } => {
+                if changes.keys().any(|&(target, _)| target == needed_offset) {
+                    return false;
+                }
+            }
+            Read { offset, .. } => {
+                if offset == needed_offset {
+                    return false;
+                }
+            }
+            Write { .. } | WriteConst { .. } => {}
+            // These finish on a zero cell, but we don't know what
+            // happened to other cells.
+            Loop { .. } | If { .. } | Scan { .. } => return needed_offset == 0,
+        }
+    }
+    false
+}
+
+/// Convert loops whose body always finishes on a zero cell, such as
+/// `[>+<[-]]`, to ifs. These loops run at most once.
+pub fn extract_if(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    instrs
+        .into_iter()
+        .map_loops(extract_if)
+        .into_iter()
+        .map(|instr| match instr {
+            Loop {
+                body,
+                offset: 0,
+                position,
+            } if finishes_on_zero_cell(&body) => If { body, position },
+            other => other,
+        })
+        .collect()
+}
+
 /// Does this loop body represent a multiplication operation?
 /// E.g. "[->>>++<<<]" sets cell #3 to 2*cell #0.
 fn is_multiply_loop_body(body: &[AstNode]) -> bool {
@@ -778,15 +1977,35 @@ fn is_multiply_loop_body(body: &[AstNode]) -> bool {
     }
 
     let changes = cell_changes(body);
-    // A multiply loop must decrement cell #0.
-    if let Some(&Wrapping(-1)) = changes.get(&0) {
-    } else {
-        return false;
+    // A multiply loop must change cell #0 by an odd amount. Odd
+    // amounts are guaranteed to reach zero under wrapping
+    // arithmetic, whereas e.g. [-->+<] never terminates if cell #0
+    // is odd.
+    match changes.get(&0) {
+        Some(&step) if multiplicative_inverse(step).is_some() => {}
+        _ => return false,
     }
 
     changes.len() >= 2
 }
 
+/// Return the multiplicative inverse of `value` modulo 256, if there
+/// is one. Only odd values have an inverse.
+fn multiplicative_inverse(value: Cell) -> Option<Cell> {
+    if value.0 % 2 == 0 {
+        return None;
+    }
+
+    // Every odd number is its own inverse modulo 8, and each
+    // iteration of Newton's method doubles the number of correct
+    // bits.
+    let mut inverse = value;
+    for _ in 0..2 {
+        inverse *= Wrapping(2) - value * inverse;
+    }
+    Some(inverse)
+}
+
 /// Return a hashmap of all the cells that are affected by this
 /// sequence of instructions, and how much they change.
 /// E.g. "->>+++>+" -> {0: -1, 2: 3, 3: 1}
@@ -798,7 +2017,7 @@ fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, Cell> {
         match *instr {
             Increment { amount, offset, .. } => {
                 let current_amount = *changes.get(&(cell_index + offset)).unwrap_or(&Wrapping(0));
-                changes.insert(cell_index, current_amount + amount);
+                changes.insert(cell_index + offset, current_amount + amount);
             }
             PointerIncrement { amount, .. } => {
                 cell_index += amount;
@@ -816,23 +2035,286 @@ pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .into_iter()
         .map(|instr| {
             match instr {
-                Loop { body, position } => {
-                    if is_multiply_loop_body(&body) {
+                Loop {
+                    body,
+                    offset,
+                    position,
+                } => {
+                    if offset == 0 && is_multiply_loop_body(&body) {
                         let mut changes = cell_changes(&body);
                         // MultiplyMove is for where we move to, so ignore
                         // the cell we're moving from.
-                        changes.remove(&0);
+                        let step = changes.remove(&0).unwrap();
+
+                        // If cell #0 starts at x and changes by step
+                        // each iteration, we run for n iterations
+                        // where x + n * step = 0, so n = x * (-step)^-1.
+                        let iterations_factor = multiplicative_inverse(-step).unwrap();
+                        for factor in changes.values_mut() {
+                            *factor *= iterations_factor;
+                        }
 
                         MultiplyMove { changes, position }
                     } else {
                         Loop {
                             body: extract_multiply(body),
+                            offset,
                             position,
                         }
                     }
                 }
+                If { body, position } => If {
+                    body: extract_multiply(body),
+                    position,
+                },
                 i => i,
             }
         })
         .collect()
 }
+
+/// A linear combination of the cell values at the start of a loop
+/// iteration, plus a constant. Cells are relative to the loop's
+/// cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LinearExpr {
+    constant: Cell,
+    terms: HashMap<isize, Cell>,
+}
+
+impl LinearExpr {
+    fn constant(value: Cell) -> Self {
+        LinearExpr {
+            constant: value,
+            terms: HashMap::new(),
+        }
+    }
+
+    /// The value of the cell at `offset` at the start of the loop.
+    fn cell(offset: isize) -> Self {
+        let mut terms = HashMap::new();
+        terms.insert(offset, Wrapping(1));
+        LinearExpr {
+            constant: Wrapping(0),
+            terms,
+        }
+    }
+
+    /// Add `factor` * `other` to this expression.
+    fn add_multiple(&mut self, other: &LinearExpr, factor: Cell) {
+        self.constant += other.constant * factor;
+        for (&offset, &coefficient) in &other.terms {
+            let term = self.terms.entry(offset).or_insert(Wrapping(0));
+            *term += coefficient * factor;
+            if *term == Wrapping(0) {
+                self.terms.remove(&offset);
+            }
+        }
+    }
+
+    fn is_constant(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Replace the cells in `values` with their known values.
+    fn substitute(&self, values: &HashMap<isize, Cell>) -> LinearExpr {
+        let mut result = LinearExpr::constant(self.constant);
+        for (&offset, &coefficient) in &self.terms {
+            match values.get(&offset) {
+                Some(&value) => result.constant += value * coefficient,
+                None => result.add_multiple(&LinearExpr::cell(offset), coefficient),
+            }
+        }
+        result
+    }
+}
+
+/// Symbolically execute a single iteration of a loop body, returning
+/// the new value of every cell that was modified. Returns None if the
+/// body isn't straight-line code with zero net pointer movement.
+fn loop_body_effect(body: &[AstNode]) -> Option<HashMap<isize, LinearExpr>> {
+    let mut cells: HashMap<isize, LinearExpr> = HashMap::new();
+    let mut cell_index: isize = 0;
+
+    let current_value = |cells: &HashMap<isize, LinearExpr>, offset: isize| {
+        cells
+            .get(&offset)
+            .cloned()
+            .unwrap_or_else(|| LinearExpr::cell(offset))
+    };
+
+    for instr in body {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let mut value = current_value(&cells, cell_index + offset);
+                value.constant += amount;
+                cells.insert(cell_index + offset, value);
+            }
+            Set { amount, offset, .. } => {
+                cells.insert(cell_index + offset, LinearExpr::constant(amount));
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                // This is linear even when the current cell is zero,
+                // as we add zero and the cell stays zero.
+                let source = current_value(&cells, cell_index);
+                for (&offset, &factor) in changes {
+                    let mut value = current_value(&cells, cell_index + offset);
+                    value.add_multiple(&source, factor);
+                    cells.insert(cell_index + offset, value);
+                }
+                cells.insert(cell_index, LinearExpr::constant(Wrapping(0)));
+            }
+            _ => return None,
+        }
+    }
+
+    if cell_index == 0 {
+        Some(cells)
+    } else {
+        None
+    }
+}
+
+/// If this loop is a counted loop that adds products of cells, return
+/// equivalent instructions without the loop. For example,
+/// `[->[->+>+<<]>>[-<<+>>]<<<]` adds cell #0 * cell #1 to cell #2.
+///
+/// We require the loop counter to change by an odd amount, so the
+/// number of iterations is known (see `extract_multiply`). Every
+/// other cell modified must either:
+///
+/// * be set to a constant (e.g. temporary cells that are zeroed), or
+/// * be unchanged after the first iteration, once those constants are
+///   known, or
+/// * increase by the same amount every iteration, depending only on
+///   the unchanged cells.
+fn nested_multiply_closed_form(
+    body: &[AstNode],
+    position: Option<Position>,
+) -> Option<Vec<AstNode>> {
+    // extract_multiply already handles loops without inner loops or
+    // sets.
+    if !body
+        .iter()
+        .any(|instr| matches!(instr, MultiplyMove { .. } | Set { .. }))
+    {
+        return None;
+    }
+
+    let cells = loop_body_effect(body)?;
+
+    let counter = cells.get(&0)?;
+    if counter.terms != LinearExpr::cell(0).terms {
+        return None;
+    }
+    let iterations_factor = multiplicative_inverse(-counter.constant)?;
+
+    // Cells that are set to a constant, regardless of their
+    // previous value.
+    let resets: HashMap<isize, Cell> = cells
+        .iter()
+        .filter(|&(&offset, value)| offset != 0 && value.is_constant())
+        .map(|(&offset, value)| (offset, value.constant))
+        .collect();
+
+    // How much each cell changes on every iteration after the first.
+    let mut increases: HashMap<isize, LinearExpr> = HashMap::new();
+    for (&offset, value) in &cells {
+        if offset == 0 || resets.contains_key(&offset) {
+            continue;
+        }
+
+        let mut increase = value.substitute(&resets);
+        increase.add_multiple(&LinearExpr::cell(offset), Wrapping(-1));
+        if increase.terms.contains_key(&offset) {
+            // E.g. the cell is doubled every iteration.
+            return None;
+        }
+        if increase != LinearExpr::constant(Wrapping(0)) {
+            increases.insert(offset, increase);
+        }
+    }
+
+    // The increases may only depend on cells that don't change.
+    for increase in increases.values() {
+        for operand in increase.terms.keys() {
+            if *operand == 0 || increases.contains_key(operand) {
+                return None;
+            }
+        }
+    }
+
+    let mut products = HashMap::new();
+    let mut linear_changes = HashMap::new();
+    for (target, increase) in increases {
+        if increase.constant != Wrapping(0) {
+            linear_changes.insert(target, increase.constant * iterations_factor);
+        }
+        for (operand, coefficient) in increase.terms {
+            products.insert((target, operand), coefficient * iterations_factor);
+        }
+    }
+
+    let mut closed_form = vec![];
+    if !products.is_empty() {
+        closed_form.push(MultiplyAdd {
+            changes: products,
+            position,
+        });
+    }
+    // Use the loop counter for the linear changes, zeroing it.
+    if linear_changes.is_empty() {
+        closed_form.push(Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position,
+        });
+    } else {
+        closed_form.push(MultiplyMove {
+            changes: linear_changes,
+            position,
+        });
+    }
+
+    // Temporary cells only hold their constants once we've run the
+    // loop body, so we run the first iteration as normal. The loop
+    // counter is zero afterwards, so this loop body executes at most
+    // once.
+    let mut first_iteration = body.to_vec();
+    first_iteration.extend(closed_form);
+    Some(vec![Loop {
+        body: first_iteration,
+        offset: 0,
+        position,
+    }])
+}
+
+/// Replace nested counted loops with closed-form updates (see
+/// `nested_multiply_closed_form`). This should run after
+/// `extract_multiply`, so inner multiply loops have already been
+/// converted.
+pub fn extract_nested_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    instrs
+        .into_iter()
+        .map_loops(extract_nested_multiply)
+        .into_iter()
+        .flat_map(|instr| match instr {
+            Loop {
+                body,
+                offset: 0,
+                position,
+            } => match nested_multiply_closed_form(&body, position) {
+                Some(replacement) => replacement,
+                None => vec![Loop {
+                    body,
+                    offset: 0,
+                    position,
+                }],
+            },
+            other => vec![other],
+        })
+        .collect()
+}
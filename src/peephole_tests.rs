@@ -6,7 +6,7 @@ use quickcheck::quickcheck;
 
 use crate::bfir::AstNode::*;
 use crate::bfir::{AstNode, Position};
-use crate::diagnostics::Warning;
+use crate::diagnostics::{Remark, Warning};
 
 use crate::bfir::parse;
 use crate::peephole::*;
@@ -21,7 +21,7 @@ impl Arbitrary for AstNode {
 // We define a separate function so we can recurse on max_depth.
 // See https://github.com/BurntSushi/quickcheck/issues/23
 fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
-    let modulus = if max_depth == 0 { 8 } else { 9 };
+    let modulus = if max_depth == 0 { 11 } else { 13 };
 
     // If max_depth is zero, don't create loops.
     match g.next_u32() % modulus {
@@ -42,9 +42,11 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             position: Some(Position { start: 0, end: 0 }),
         },
         3 => Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         4 => Write {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         5 => {
@@ -90,10 +92,27 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             ];
             Loop {
                 body,
+                offset: 0,
                 position: None,
             }
         }
-        8 => {
+        8 => Scan {
+            stride: Arbitrary::arbitrary(g),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        9 => {
+            let mut changes = HashMap::new();
+            changes.insert((1, 2), Wrapping(3));
+            MultiplyAdd {
+                changes,
+                position: Some(Position { start: 0, end: 0 }),
+            }
+        }
+        10 => WriteConst {
+            bytes: Arbitrary::arbitrary(g),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        11 => {
             assert!(max_depth > 0);
             let loop_length = g.next_u32() % 10;
             let mut body: Vec<_> = vec![];
@@ -101,6 +120,25 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
                 body.push(arbitrary_instr(g, max_depth - 1));
             }
             Loop {
+                body,
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }
+        }
+        12 => {
+            assert!(max_depth > 0);
+            let body_length = g.next_u32() % 10;
+            let mut body: Vec<_> = vec![];
+            for _ in 0..body_length {
+                body.push(arbitrary_instr(g, max_depth - 1));
+            }
+            // The body of an if must finish on a zero cell.
+            body.push(Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            });
+            If {
                 body,
                 position: Some(Position { start: 0, end: 0 }),
             }
@@ -136,6 +174,7 @@ fn combine_increments_nested() {
             offset: 0,
             position: Some(Position { start: 1, end: 2 }),
         }],
+        offset: 0,
         position: Some(Position { start: 0, end: 3 }),
     }];
     assert_eq!(combine_increments(initial), expected);
@@ -152,7 +191,7 @@ fn quickcheck_combine_increments_remove_zero_any_offset() {
     fn combine_increments_remove_zero_any_offset(offset: isize) -> bool {
         let initial = vec![Increment {
             amount: Wrapping(0),
-            offset: offset,
+            offset,
             position: Some(Position { start: 0, end: 0 }),
         }];
         combine_increments(initial) == vec![]
@@ -217,9 +256,11 @@ fn should_combine_before_read() {
     let initial = parse("+,.").unwrap();
     let expected = vec![
         Read {
+            offset: 0,
             position: Some(Position { start: 1, end: 1 }),
         },
         Write {
+            offset: 0,
             position: Some(Position { start: 2, end: 2 }),
         },
     ];
@@ -236,6 +277,7 @@ fn dont_combine_before_read_different_offset() {
             position: Some(Position { start: 0, end: 0 }),
         },
         Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
@@ -254,8 +296,10 @@ fn should_combine_before_read_nested() {
         },
         Loop {
             body: vec![Read {
+                offset: 0,
                 position: Some(Position { start: 3, end: 3 }),
             }],
+            offset: 0,
             position: Some(Position { start: 1, end: 4 }),
         },
     ];
@@ -281,12 +325,35 @@ fn combine_before_read_not_consecutive() {
             position: Some(Position { start: 3, end: 3 }),
         },
         Read {
+            offset: 0,
             position: Some(Position { start: 4, end: 4 }),
         },
     ];
     assert_eq!(remove_read_clobber(initial), expected);
 }
 
+#[test]
+fn combine_before_read_with_offset() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 2,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+        Read {
+            offset: 2,
+            position: None,
+        },
+    ];
+    let expected = vec![initial[1].clone(), initial[2].clone()];
+    assert_eq!(remove_read_clobber(initial), expected);
+}
+
 #[test]
 fn no_combine_before_read_after_write() {
     let initial = vec![
@@ -295,8 +362,14 @@ fn no_combine_before_read_after_write() {
             offset: 0,
             position: None,
         },
-        Write { position: None },
-        Read { position: None },
+        Write {
+            offset: 0,
+            position: None,
+        },
+        Read {
+            offset: 0,
+            position: None,
+        },
     ];
     // TODO: write an assert_unchanged! macro.
     let expected = initial.clone();
@@ -312,7 +385,10 @@ fn no_combine_before_read_after_multiply() {
             changes,
             position: None,
         },
-        Read { position: None },
+        Read {
+            offset: 0,
+            position: None,
+        },
     ];
     let expected = initial.clone();
     assert_eq!(remove_read_clobber(initial), expected);
@@ -329,6 +405,25 @@ fn simplify_zeroing_loop() {
     assert_eq!(zeroing_loops(initial), expected);
 }
 
+#[test]
+fn simplify_zeroing_loop_with_offset() {
+    let initial = vec![Loop {
+        body: vec![Increment {
+            amount: Wrapping(-1),
+            offset: 2,
+            position: None,
+        }],
+        offset: 2,
+        position: None,
+    }];
+    let expected = vec![Set {
+        amount: Wrapping(0),
+        offset: 2,
+        position: None,
+    }];
+    assert_eq!(zeroing_loops(initial), expected);
+}
+
 #[test]
 fn simplify_nested_zeroing_loop() {
     let initial = parse("[[-]]").unwrap();
@@ -338,6 +433,7 @@ fn simplify_nested_zeroing_loop() {
             offset: 0,
             position: Some(Position { start: 1, end: 3 }),
         }],
+        offset: 0,
         position: Some(Position { start: 0, end: 4 }),
     }];
     assert_eq!(zeroing_loops(initial), expected);
@@ -362,10 +458,12 @@ fn remove_repeated_loops() {
         },
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
@@ -377,6 +475,7 @@ fn remove_repeated_loops() {
         },
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
@@ -393,6 +492,7 @@ fn remove_dead_loops_after_set() {
         },
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
@@ -415,9 +515,11 @@ fn remove_dead_loops_nested() {
             },
             Loop {
                 body: vec![],
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ],
+        offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
     let expected = vec![Loop {
@@ -426,6 +528,7 @@ fn remove_dead_loops_nested() {
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         }],
+        offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
     assert_eq!(remove_dead_loops(initial), expected);
@@ -446,6 +549,7 @@ fn remove_dead_loops_not_adjacent() {
         },
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
@@ -477,18 +581,18 @@ fn quickcheck_should_combine_set_and_increment() {
         let initial = vec![
             Set {
                 amount: set_amount,
-                offset: offset,
+                offset,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Increment {
                 amount: increment_amount,
-                offset: offset,
+                offset,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
         let expected = vec![Set {
             amount: set_amount + increment_amount,
-            offset: offset,
+            offset,
             position: Some(Position { start: 0, end: 0 }),
         }];
         combine_set_and_increments(initial) == expected
@@ -569,18 +673,18 @@ fn quickcheck_combine_set_and_set() {
         let initial = vec![
             Set {
                 amount: Wrapping(set_amount_before),
-                offset: offset,
+                offset,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Set {
                 amount: Wrapping(set_amount_after),
-                offset: offset,
+                offset,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
         let expected = vec![Set {
             amount: Wrapping(set_amount_after),
-            offset: offset,
+            offset,
             position: Some(Position { start: 0, end: 0 }),
         }];
         combine_set_and_increments(initial) == expected
@@ -634,6 +738,7 @@ fn should_combine_set_and_set_nested() {
                 position: Some(Position { start: 0, end: 0 }),
             },
         ],
+        offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
     let expected = vec![Loop {
@@ -642,6 +747,7 @@ fn should_combine_set_and_set_nested() {
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         }],
+        offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
     assert_eq!(combine_set_and_increments(initial), expected);
@@ -653,18 +759,18 @@ fn quickcheck_should_combine_increment_and_set() {
         let initial = vec![
             Increment {
                 amount: Wrapping(2),
-                offset: offset,
+                offset,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Set {
                 amount: Wrapping(3),
-                offset: offset,
+                offset,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
         let expected = vec![Set {
             amount: Wrapping(3),
-            offset: offset,
+            offset,
             position: Some(Position { start: 0, end: 0 }),
         }];
         combine_set_and_increments(initial) == expected
@@ -677,6 +783,7 @@ fn should_remove_redundant_set() {
     let initial = vec![
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Set {
@@ -693,6 +800,7 @@ fn should_remove_redundant_set() {
     let expected = vec![
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Set {
@@ -734,6 +842,7 @@ fn not_redundant_set_when_nonzero() {
     let instrs = vec![
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Set {
@@ -748,13 +857,13 @@ fn not_redundant_set_when_nonzero() {
 fn is_pure(instrs: &[AstNode]) -> bool {
     for instr in instrs {
         match *instr {
-            Loop { .. } => {
+            Loop { .. } | If { .. } | Scan { .. } => {
                 return false;
             }
             Read { .. } => {
                 return false;
             }
-            Write { .. } => {
+            Write { .. } | WriteConst { .. } => {
                 return false;
             }
             _ => (),
@@ -767,7 +876,14 @@ fn is_pure(instrs: &[AstNode]) -> bool {
 fn quickcheck_should_annotate_known_zero_at_start() {
     fn should_annotate_known_zero_at_start(instrs: Vec<AstNode>) -> bool {
         let annotated = annotate_known_zero(instrs);
-        matches!(annotated[0], Set { amount: Wrapping(0), offset: 0, .. })
+        matches!(
+            annotated[0],
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                ..
+            }
+        )
     }
     quickcheck(should_annotate_known_zero_at_start as fn(Vec<AstNode>) -> bool);
 }
@@ -804,6 +920,7 @@ fn should_annotate_known_zero() {
         },
         Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 1, end: 2 }),
         },
         Set {
@@ -828,6 +945,7 @@ fn should_annotate_known_zero_nested() {
             body: vec![
                 Loop {
                     body: vec![],
+                    offset: 0,
                     position: Some(Position { start: 1, end: 2 }),
                 },
                 Set {
@@ -836,6 +954,7 @@ fn should_annotate_known_zero_nested() {
                     position: Some(Position { start: 2, end: 2 }),
                 },
             ],
+            offset: 0,
             position: Some(Position { start: 0, end: 3 }),
         },
         Set {
@@ -853,9 +972,14 @@ fn should_annotate_known_zero_nested() {
 #[test]
 fn should_annotate_known_zero_cleaned_up() {
     let initial = vec![Write {
+        offset: 0,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let expected = vec![WriteConst {
+        bytes: vec![0],
         position: Some(Position { start: 0, end: 0 }),
     }];
-    assert_eq!(optimize(initial.clone(), &None).0, initial);
+    assert_eq!(optimize(initial, &None).0, expected);
 }
 
 #[test]
@@ -863,9 +987,26 @@ fn should_preserve_set_0_in_loop() {
     // Regression test.
     let initial = vec![
         Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Loop {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    // The loop only runs once, so it becomes an if.
+    let expected = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        If {
             body: vec![Set {
                 amount: Wrapping(0),
                 offset: 0,
@@ -874,7 +1015,7 @@ fn should_preserve_set_0_in_loop() {
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(optimize(initial.clone(), &None).0, initial);
+    assert_eq!(optimize(initial, &None).0, expected);
 }
 
 #[test]
@@ -882,16 +1023,10 @@ fn should_remove_pure_code() {
     // The final increment here is side-effect free and can be
     // removed.
     let initial = parse("+.+").unwrap();
-    let expected = vec![
-        Set {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Write {
-            position: Some(Position { start: 1, end: 1 }),
-        },
-    ];
+    let expected = vec![WriteConst {
+        bytes: vec![1],
+        position: Some(Position { start: 1, end: 1 }),
+    }];
 
     let (result, warnings) = optimize(initial, &None);
 
@@ -905,6 +1040,28 @@ fn should_remove_pure_code() {
     );
 }
 
+/// Sorting by offset moves the `>` after the write, but it isn't
+/// dead code in the original program.
+#[test]
+fn should_not_warn_on_pointer_increment_before_write() {
+    let initial = parse(">,.").unwrap();
+    let expected = vec![
+        Read {
+            offset: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Write {
+            offset: 1,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+
+    let (result, warnings) = optimize(initial, &None);
+
+    assert_eq!(result, expected);
+    assert_eq!(warnings, vec![]);
+}
+
 #[test]
 fn quickcheck_should_remove_dead_pure_code() {
     fn should_remove_dead_pure_code(instrs: Vec<AstNode>) -> TestResult {
@@ -932,6 +1089,7 @@ fn quickcheck_optimize_should_be_idempotent() {
 fn pathological_optimisation_opportunity() {
     let instrs = vec![
         Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Increment {
@@ -971,15 +1129,18 @@ fn pathological_optimisation_opportunity() {
             position: Some(Position { start: 0, end: 0 }),
         },
         Write {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
 
     let expected = vec![
         Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Write {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
@@ -1034,6 +1195,7 @@ fn should_extract_multiply_nested() {
             changes: dest_cells,
             position: Some(Position { start: 1, end: 6 }),
         }],
+        offset: 0,
         position: Some(Position { start: 0, end: 7 }),
     }];
 
@@ -1087,495 +1249,1540 @@ fn should_not_extract_multiply_with_inner_loop() {
     assert_eq!(extract_multiply(instrs.clone()), instrs);
 }
 
-/// We need to decrement the initial cell in order for this to be a
-/// multiply.
+/// If we increment the initial cell, we still reach zero, but we
+/// run (256 - x) times.
 #[test]
-fn should_not_extract_multiply_without_decrement() {
+fn should_extract_multiply_with_increment() {
     let instrs = parse("[+>++<]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
-}
 
-#[test]
-fn should_not_extract_multiply_with_read() {
-    let instrs = parse("[+>++<,]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+    let mut dest_cells = HashMap::new();
+    dest_cells.insert(1, Wrapping(-2));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: Some(Position { start: 0, end: 6 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
 }
 
+/// Any odd step is guaranteed to reach zero.
 #[test]
-fn should_not_extract_multiply_with_write() {
-    let instrs = parse("[+>++<.]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+fn should_extract_multiply_odd_step() {
+    let instrs = parse("[--->+<]").unwrap();
+
+    // 3 * 171 = 513 = 1 (mod 256), so we run x * 171 times.
+    let mut dest_cells = HashMap::new();
+    dest_cells.insert(1, Wrapping(171u8 as i8));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: Some(Position { start: 0, end: 7 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
 }
 
+/// [-->+<] never terminates if the initial cell is odd, so it's not a
+/// multiply.
 #[test]
-fn sort_by_offset_increment() {
-    let instrs = parse("+>+>").unwrap();
-    let expected = vec![
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: 1,
-            position: Some(Position { start: 2, end: 2 }),
-        },
-        PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 3, end: 3 }),
-        },
-    ];
-    assert_eq!(sort_by_offset(instrs), expected);
+fn should_not_extract_multiply_even_step() {
+    let instrs = parse("[-->+<]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
 }
 
 #[test]
-fn sort_by_offset_increment_nested() {
-    let instrs = parse("[+>+>]").unwrap();
-    let expected = vec![Loop {
-        body: (vec![
+fn should_extract_multiply_with_offsets() {
+    // The same as [->+++<] but after sorting by offset.
+    let instrs = vec![Loop {
+        body: vec![
             Increment {
-                amount: Wrapping(1),
+                amount: Wrapping(-1),
                 offset: 0,
-                position: Some(Position { start: 1, end: 1 }),
+                position: None,
             },
             Increment {
-                amount: Wrapping(1),
+                amount: Wrapping(3),
                 offset: 1,
-                position: Some(Position { start: 3, end: 3 }),
-            },
-            PointerIncrement {
-                amount: 2,
-                position: Some(Position { start: 4, end: 4 }),
+                position: None,
             },
-        ]),
-        position: Some(Position { start: 0, end: 5 }),
+        ],
+        offset: 0,
+        position: None,
     }];
-    assert_eq!(sort_by_offset(instrs), expected);
+
+    let mut dest_cells = HashMap::new();
+    dest_cells.insert(1, Wrapping(3));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: None,
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
 }
 
 #[test]
-fn sort_by_offset_remove_redundant() {
-    let initial = parse("><").unwrap();
-    assert_eq!(sort_by_offset(initial), vec![]);
+fn should_not_extract_multiply_with_read() {
+    let instrs = parse("[+>++<,]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
 }
 
-// If there's a read instruction, we should only combine before and
-// after.
 #[test]
-fn sort_by_offset_read() {
-    let instrs = parse(">>,>>").unwrap();
-    let expected = vec![
-        PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 1, end: 1 }),
-        },
-        Read {
-            position: Some(Position { start: 2, end: 2 }),
-        },
-        PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 4, end: 4 }),
-        },
-    ];
-    assert_eq!(sort_by_offset(instrs), expected);
+fn should_not_extract_multiply_with_write() {
+    let instrs = parse("[+>++<.]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
 }
 
 #[test]
-fn quickcheck_sort_by_offset_set() {
-    fn sort_by_offset_set(amount1: i8, amount2: i8) -> bool {
-        let instrs = vec![
-            Set {
-                amount: Wrapping(amount1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
+fn should_extract_nested_multiply() {
+    // Add cell #0 * cell #1 to cell #2, using cell #3 as a temporary.
+    let instrs = extract_multiply(parse("[->[->+>+<<]>>[-<<+>>]<<<]").unwrap());
+    let body = match instrs[0] {
+        Loop { ref body, .. } => body.clone(),
+        _ => unreachable!(),
+    };
+
+    let mut products = HashMap::new();
+    products.insert((2, 1), Wrapping(1));
+    let mut expected_body = body;
+    expected_body.push(MultiplyAdd {
+        changes: products,
+        position: Some(Position { start: 0, end: 25 }),
+    });
+    expected_body.push(Set {
+        amount: Wrapping(0),
+        offset: 0,
+        position: Some(Position { start: 0, end: 25 }),
+    });
+    // We don't know that cell #3 starts at zero, so we execute the
+    // first iteration normally.
+    let expected = vec![Loop {
+        body: expected_body,
+        offset: 0,
+        position: Some(Position { start: 0, end: 25 }),
+    }];
+
+    assert_eq!(extract_nested_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_nested_multiply_constant_increase() {
+    // Cell #2 increases by cell #1 + 2 on every iteration.
+    let instrs = extract_multiply(parse("[->>++<[->+>+<<]>>[-<<+>>]<<<]").unwrap());
+    let replaced = extract_nested_multiply(instrs);
+
+    let mut products = HashMap::new();
+    products.insert((2, 1), Wrapping(1));
+    let mut linear_changes = HashMap::new();
+    linear_changes.insert(2, Wrapping(2));
+
+    match replaced[..] {
+        [Loop { ref body, .. }] => {
+            let closed_form = &body[body.len() - 2..];
+            assert_eq!(
+                closed_form,
+                &[
+                    MultiplyAdd {
+                        changes: products,
+                        position: Some(Position { start: 0, end: 29 }),
+                    },
+                    MultiplyMove {
+                        changes: linear_changes,
+                        position: Some(Position { start: 0, end: 29 }),
+                    },
+                ]
+            );
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn should_not_extract_nested_multiply_with_unbounded_growth() {
+    // Cell #1 doubles every iteration.
+    let instrs = extract_multiply(parse("[->[->++<]>[-<+>]<<]").unwrap());
+    assert_eq!(extract_nested_multiply(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_not_extract_nested_multiply_with_inner_loop() {
+    let instrs = extract_multiply(parse("[->[->+<]>[>]<<]").unwrap());
+    assert_eq!(extract_nested_multiply(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_extract_if() {
+    let instrs = zeroing_loops(parse("[>+<[-]]").unwrap());
+    let expected = vec![If {
+        body: vec![
             PointerIncrement {
-                amount: -1,
-                position: Some(Position { start: 0, end: 0 }),
+                amount: 1,
+                position: Some(Position { start: 1, end: 1 }),
             },
-            Set {
-                amount: Wrapping(amount2),
+            Increment {
+                amount: Wrapping(1),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 2, end: 2 }),
             },
-        ];
-
-        let expected = vec![
-            Set {
-                amount: Wrapping(amount2),
-                offset: -1,
-                position: Some(Position { start: 0, end: 0 }),
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 3, end: 3 }),
             },
             Set {
-                amount: Wrapping(amount1),
+                amount: Wrapping(0),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            PointerIncrement {
-                amount: -1,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 4, end: 6 }),
             },
-        ];
-        sort_by_offset(instrs) == expected
-    }
-    quickcheck(sort_by_offset_set as fn(i8, i8) -> bool);
+        ],
+        position: Some(Position { start: 0, end: 7 }),
+    }];
+    assert_eq!(extract_if(instrs), expected);
 }
 
 #[test]
-fn quickcheck_sort_by_offset_pointer_increments() {
-    fn sort_by_offset_pointer_increments(amount1: isize, amount2: isize) -> TestResult {
-        // Although in principle our optimisations would work outside
-        // MAX_CELL_INDEX, we restrict the range to avoid overflow.
-        if amount1 < -30000 || amount1 > 30000 || amount2 < -30000 || amount2 > 30000 {
-            return TestResult::discard();
-        }
-        // We should discard the pointer increment if the two cancel out,
-        // but we don't test that here.
-        if amount1 + amount2 == 0 {
-            return TestResult::discard();
-        }
-
-        let instrs = vec![
-            PointerIncrement {
-                amount: amount1,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            PointerIncrement {
-                amount: amount2,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = vec![PointerIncrement {
-            amount: amount1 + amount2,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        TestResult::from_bool(sort_by_offset(instrs) == expected)
-    }
-    quickcheck(sort_by_offset_pointer_increments as fn(isize, isize) -> TestResult);
+fn should_extract_if_ending_with_loop() {
+    let instrs = parse("[>+<[<]]").unwrap();
+    assert!(matches!(extract_if(instrs)[..], [If { .. }]));
 }
 
-// Don't combine instruction positions when they weren't originally
-// adjacent.
 #[test]
-fn combine_increments_non_adjacent_instrs() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+fn should_extract_if_with_offsets() {
+    // Zero cell #1, then move to it.
+    let body = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 1,
+            position: None,
         },
         Increment {
             amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 2, end: 2 }),
+            position: None,
+        },
+        PointerIncrement {
+            amount: 1,
+            position: None,
         },
     ];
-    let expected = vec![Increment {
-        amount: Wrapping(2),
+    let instrs = vec![Loop {
+        body: body.clone(),
         offset: 0,
-        position: Some(Position { start: 2, end: 2 }),
+        position: None,
     }];
-    assert_eq!(combine_increments(instrs), expected);
+    let expected = vec![If {
+        body,
+        position: None,
+    }];
+    assert_eq!(extract_if(instrs), expected);
 }
 
-// Don't combine instruction positions when they weren't originally
-// adjacent.
 #[test]
-fn combine_set_and_increment_non_adjacent_instrs() {
+fn should_not_extract_if_on_other_cell() {
+    let instrs = zeroing_loops(parse("[[-]>]").unwrap());
+    assert_eq!(extract_if(instrs.clone()), instrs);
+
+    // Cell #1 is modified after being zeroed.
+    let instrs = zeroing_loops(parse("[>[-]+]").unwrap());
+    assert_eq!(extract_if(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_remove_dead_if() {
     let instrs = vec![
         Set {
-            amount: Wrapping(1),
+            amount: Wrapping(0),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: None,
+        },
+        If {
+            body: vec![Write {
+                offset: 0,
+                position: None,
+            }],
+            position: None,
         },
+    ];
+    assert_eq!(remove_dead_loops(instrs.clone()), vec![instrs[0].clone()]);
+}
+
+#[test]
+fn should_extract_scan() {
+    let instrs = parse("[>>]").unwrap();
+    let expected = vec![Scan {
+        stride: 2,
+        position: Some(Position { start: 0, end: 3 }),
+    }];
+    assert_eq!(extract_scan(combine_ptr_increments(instrs)), expected);
+}
+
+#[test]
+fn should_extract_scan_nested() {
+    let instrs = parse("+[[<]-]").unwrap();
+    let expected = vec![
         Increment {
             amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 2, end: 2 }),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![
+                Scan {
+                    stride: -1,
+                    position: Some(Position { start: 2, end: 4 }),
+                },
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 5, end: 5 }),
+                },
+            ],
+            offset: 0,
+            position: Some(Position { start: 1, end: 6 }),
         },
     ];
-    let expected = vec![Set {
-        amount: Wrapping(2),
-        offset: 0,
-        position: Some(Position { start: 2, end: 2 }),
-    }];
-    assert_eq!(combine_set_and_increments(instrs), expected);
+    assert_eq!(extract_scan(instrs), expected);
 }
 
-/// Ensure that we combine after sorting, since sorting creates new
-/// combination opportunities.
 #[test]
-fn combine_increments_after_sort() {
-    let instrs = parse(",+>+<+.").unwrap();
+fn should_not_extract_scan_with_other_instrs() {
+    let instrs = parse("[>+]").unwrap();
+    assert_eq!(extract_scan(instrs.clone()), instrs);
+
+    let instrs = parse("[]").unwrap();
+    assert_eq!(extract_scan(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_remove_dead_scan() {
+    // We know the current cell is zero at the start of the program
+    // and after a scan.
+    let instrs = parse(",[>][<]").unwrap();
     let expected = vec![
         Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
+        Scan {
+            stride: 1,
+            position: Some(Position { start: 1, end: 3 }),
+        },
+    ];
+    assert_eq!(optimize(instrs, &None).0, expected);
+}
+
+#[test]
+fn sort_by_offset_increment() {
+    let instrs = parse("+>+>").unwrap();
+    let expected = vec![
         Increment {
-            amount: Wrapping(2),
+            amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 5, end: 5 }),
+            position: Some(Position { start: 0, end: 0 }),
         },
         Increment {
             amount: Wrapping(1),
             offset: 1,
-            position: Some(Position { start: 3, end: 3 }),
+            position: Some(Position { start: 2, end: 2 }),
         },
-        Write {
-            position: Some(Position { start: 6, end: 6 }),
+        PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 3, end: 3 }),
         },
     ];
-    assert_eq!(optimize(instrs, &None).0, expected);
+    assert_eq!(sort_by_offset(instrs), expected);
 }
 
 #[test]
-fn prev_mutate_loop() {
-    // If we see a loop, we don't know when the current cell was last
-    // mutated.
-    let instrs = vec![
+fn sort_by_offset_increment_nested() {
+    let instrs = parse("[+>+>]").unwrap();
+    let expected = vec![Loop {
+        body: (vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            PointerIncrement {
+                amount: 2,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+        ]),
+        offset: 0,
+        position: Some(Position { start: 0, end: 5 }),
+    }];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn sort_by_offset_loop() {
+    let instrs = parse(">>[-<+>]<").unwrap();
+    let expected = vec![
         Loop {
-            body: vec![],
-            position: Some(Position { start: 0, end: 0 }),
+            body: vec![
+                Increment {
+                    amount: Wrapping(1),
+                    offset: 1,
+                    position: Some(Position { start: 5, end: 5 }),
+                },
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 2,
+                    position: Some(Position { start: 3, end: 3 }),
+                },
+            ],
+            offset: 2,
+            position: Some(Position { start: 2, end: 7 }),
         },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 8, end: 8 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 1), None);
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn sort_by_offset_nested_loop() {
+    let instrs = parse("[>[->+<]<-]").unwrap();
+    let expected = vec![Loop {
+        body: vec![
+            Loop {
+                body: vec![
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 1,
+                        position: Some(Position { start: 3, end: 3 }),
+                    },
+                    Increment {
+                        amount: Wrapping(1),
+                        offset: 2,
+                        position: Some(Position { start: 5, end: 5 }),
+                    },
+                ],
+                offset: 1,
+                position: Some(Position { start: 2, end: 7 }),
+            },
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 9, end: 9 }),
+            },
+        ],
+        offset: 0,
+        position: Some(Position { start: 0, end: 10 }),
+    }];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn sort_by_offset_loop_with_write() {
+    let instrs = parse(">[.-]").unwrap();
+    let expected = vec![
+        Loop {
+            body: vec![
+                Write {
+                    offset: 1,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 1,
+                    position: Some(Position { start: 3, end: 3 }),
+                },
+            ],
+            offset: 1,
+            position: Some(Position { start: 1, end: 4 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+/// Moving the pointer inside the loop body would be slower, so we
+/// only use loop offsets when the body can use offsets too.
+#[test]
+fn sort_by_offset_loop_with_movement() {
+    let instrs = parse(">[.>]").unwrap();
+    let expected = vec![
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![
+                Write {
+                    offset: 0,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+                PointerIncrement {
+                    amount: 1,
+                    position: Some(Position { start: 3, end: 3 }),
+                },
+            ],
+            offset: 0,
+            position: Some(Position { start: 1, end: 4 }),
+        },
+    ];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn should_remove_dead_loop_with_offset() {
+    let instrs = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 1,
+            position: None,
+        },
+        Loop {
+            body: vec![],
+            offset: 1,
+            position: None,
+        },
+    ];
+    assert_eq!(remove_dead_loops(instrs.clone()), vec![instrs[0].clone()]);
+}
+
+#[test]
+fn should_annotate_known_zero_after_loop_with_offset() {
+    let instrs = vec![Loop {
+        body: vec![],
+        offset: -1,
+        position: None,
+    }];
+    let expected = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: None,
+        },
+        Loop {
+            body: vec![],
+            offset: -1,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: -1,
+            position: None,
+        },
+    ];
+    assert_eq!(annotate_known_zero(instrs), expected);
+}
+
+#[test]
+fn sort_by_offset_remove_redundant() {
+    let initial = parse("><").unwrap();
+    assert_eq!(sort_by_offset(initial), vec![]);
+}
+
+// Reads use offsets, but we should only reorder instructions before
+// and after them.
+#[test]
+fn sort_by_offset_read() {
+    let instrs = parse(">+>,<+>>").unwrap();
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Read {
+            offset: 2,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 5, end: 5 }),
+        },
+        PointerIncrement {
+            amount: 3,
+            position: Some(Position { start: 7, end: 7 }),
+        },
+    ];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn quickcheck_sort_by_offset_set() {
+    fn sort_by_offset_set(amount1: i8, amount2: i8) -> bool {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(amount1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(amount2),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let expected = vec![
+            Set {
+                amount: Wrapping(amount2),
+                offset: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(amount1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        sort_by_offset(instrs) == expected
+    }
+    quickcheck(sort_by_offset_set as fn(i8, i8) -> bool);
+}
+
+#[test]
+fn quickcheck_sort_by_offset_pointer_increments() {
+    fn sort_by_offset_pointer_increments(amount1: isize, amount2: isize) -> TestResult {
+        // Although in principle our optimisations would work outside
+        // MAX_CELL_INDEX, we restrict the range to avoid overflow.
+        if !(-30000..=30000).contains(&amount1) || !(-30000..=30000).contains(&amount2) {
+            return TestResult::discard();
+        }
+        // We should discard the pointer increment if the two cancel out,
+        // but we don't test that here.
+        if amount1 + amount2 == 0 {
+            return TestResult::discard();
+        }
+
+        let instrs = vec![
+            PointerIncrement {
+                amount: amount1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: amount2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![PointerIncrement {
+            amount: amount1 + amount2,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        TestResult::from_bool(sort_by_offset(instrs) == expected)
+    }
+    quickcheck(sort_by_offset_pointer_increments as fn(isize, isize) -> TestResult);
+}
+
+// Don't combine instruction positions when they weren't originally
+// adjacent.
+#[test]
+fn combine_increments_non_adjacent_instrs() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let expected = vec![Increment {
+        amount: Wrapping(2),
+        offset: 0,
+        position: Some(Position { start: 2, end: 2 }),
+    }];
+    assert_eq!(combine_increments(instrs), expected);
+}
+
+// Don't combine instruction positions when they weren't originally
+// adjacent.
+#[test]
+fn combine_set_and_increment_non_adjacent_instrs() {
+    let instrs = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let expected = vec![Set {
+        amount: Wrapping(2),
+        offset: 0,
+        position: Some(Position { start: 2, end: 2 }),
+    }];
+    assert_eq!(combine_set_and_increments(instrs), expected);
+}
+
+/// Ensure that we combine after sorting, since sorting creates new
+/// combination opportunities.
+#[test]
+fn combine_increments_after_sort() {
+    let instrs = parse(",+>+<+.").unwrap();
+    let expected = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 5, end: 5 }),
+        },
+        // Cell 1 is never read, so we don't bother setting it.
+        Write {
+            offset: 0,
+            position: Some(Position { start: 6, end: 6 }),
+        },
+    ];
+    assert_eq!(optimize(instrs, &None).0, expected);
+}
+
+#[test]
+fn prev_mutate_loop() {
+    // If we see a loop, we don't know when the current cell was last
+    // mutated.
+    let instrs = vec![
+        Loop {
+            body: vec![],
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1, 0), None);
+}
+
+#[test]
+fn prev_mutate_increment() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1, 0), Some(0));
+}
+
+#[test]
+fn prev_mutate_ignores_offset_at_index() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        // The fact that this instruction is at offset 1 should be irrelevant.
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1, 0), Some(0));
+}
+
+#[test]
+fn prev_mutate_multiply_offset_matches() {
+    let mut changes = HashMap::new();
+    changes.insert(-1, Wrapping(-1));
+
+    let instrs = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2, 0), Some(0));
+}
+
+#[test]
+fn prev_mutate_multiply_offset_doesnt_match() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(2));
+
+    let instrs = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2, 0), None);
+}
+
+/// MultiplyMove zeroes the current cell, so it counts as a mutation
+/// of the current value.
+#[test]
+fn prev_mutate_multiply_ignore_offset() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(-1));
+
+    let instrs = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1, 0), Some(0));
+}
+
+#[test]
+fn prev_mutate_no_predecessors() {
+    let instrs = vec![Read {
+        offset: 0,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    assert_eq!(previous_cell_change(&instrs, 0, 0), None);
+}
+
+#[test]
+fn prev_mutate_increment_matching_offset() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(10),
+            offset: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2, 0), Some(0));
+}
+
+#[test]
+fn prev_mutate_ignore_write() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Write {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2, 0), Some(0));
+}
+
+#[test]
+fn prev_mutate_consider_pointer_increment() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2, 0), Some(0));
+}
+
+#[test]
+fn prev_mutate_set() {
+    let instrs = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1, 0), Some(0));
+}
+
+#[test]
+fn next_mutate_loop() {
+    // If we see a loop, we don't know when the current cell is next
+    // mutated.
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![],
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(next_cell_change(&instrs, 0, 0), None);
+}
+
+#[test]
+fn next_mutate_increment() {
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(next_cell_change(&instrs, 0, 0), Some(2));
+}
+
+#[test]
+fn next_mutate_consider_pointer_increment() {
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+
+    assert_eq!(next_cell_change(&instrs, 0, 0), Some(3));
+}
+
+#[test]
+fn combine_writes_of_known_cells() {
+    let initial = parse("+.+.").unwrap();
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        WriteConst {
+            bytes: vec![1, 2],
+            position: Some(Position { start: 1, end: 3 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    assert_eq!(combine_writes(initial), expected);
+}
+
+#[test]
+fn combine_writes_with_offset() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(65),
+            offset: 2,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Write {
+            offset: 2,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Write {
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let expected = vec![
+        Set {
+            amount: Wrapping(65),
+            offset: 2,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        WriteConst {
+            bytes: vec![65, 0],
+            position: Some(Position { start: 1, end: 2 }),
+        },
+    ];
+    assert_eq!(combine_writes(initial), expected);
+}
+
+#[test]
+fn combine_writes_not_across_pointer_increment() {
+    let initial = parse(".>.").unwrap();
+    let expected = vec![
+        WriteConst {
+            bytes: vec![0],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        WriteConst {
+            bytes: vec![0],
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    assert_eq!(combine_writes(initial), expected);
+}
+
+#[test]
+fn combine_writes_unknown_after_read() {
+    let initial = parse(",.").unwrap();
+    let expected = initial.clone();
+    assert_eq!(combine_writes(initial), expected);
+}
+
+#[test]
+fn combine_writes_in_loop() {
+    // We don't know the cell values inside the loop, but we know the
+    // loop's cell is zero afterwards.
+    let initial = parse(",[.-].").unwrap();
+    let expected = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![
+                Write {
+                    offset: 0,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 3, end: 3 }),
+                },
+            ],
+            offset: 0,
+            position: Some(Position { start: 1, end: 4 }),
+        },
+        WriteConst {
+            bytes: vec![0],
+            position: Some(Position { start: 5, end: 5 }),
+        },
+    ];
+    assert_eq!(combine_writes(initial), expected);
+}
+
+#[test]
+fn combine_writes_after_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(3));
+    let initial = vec![
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Write {
+            offset: 1,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let mut expected = initial.clone();
+    expected[2] = WriteConst {
+        bytes: vec![6],
+        position: Some(Position { start: 2, end: 2 }),
+    };
+    assert_eq!(combine_writes(initial), expected);
+}
+
+#[test]
+fn propagate_constants_increment_to_set() {
+    let initial = parse("+>+<+").unwrap();
+    let expected = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 4, end: 4 }),
+        },
+    ];
+    assert_eq!(propagate_constants(initial), expected);
+}
+
+#[test]
+fn propagate_constants_unknown_after_read() {
+    let initial = parse(",+").unwrap();
+    let expected = initial.clone();
+    assert_eq!(propagate_constants(initial), expected);
+}
+
+#[test]
+fn propagate_constants_removes_loop_on_zero() {
+    let initial = parse(">[.]").unwrap();
+    let expected = vec![PointerIncrement {
+        amount: 1,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    assert_eq!(propagate_constants(initial), expected);
+}
+
+#[test]
+fn propagate_constants_removes_loop_after_loop() {
+    let initial = parse(",[.][.]").unwrap();
+    let expected = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![Write {
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 1, end: 3 }),
+        },
+    ];
+    assert_eq!(propagate_constants(initial), expected);
+}
+
+/// Cells that a loop never modifies keep their value, both inside
+/// the loop and after it.
+#[test]
+fn propagate_constants_through_loop() {
+    let initial = parse(",[>[.]<,]>+").unwrap();
+    let expected = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![
+                PointerIncrement {
+                    amount: 1,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+                PointerIncrement {
+                    amount: -1,
+                    position: Some(Position { start: 6, end: 6 }),
+                },
+                Read {
+                    offset: 0,
+                    position: Some(Position { start: 7, end: 7 }),
+                },
+            ],
+            offset: 0,
+            position: Some(Position { start: 1, end: 8 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 9, end: 9 }),
+        },
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 10, end: 10 }),
+        },
+    ];
+    assert_eq!(propagate_constants(initial), expected);
+}
+
+#[test]
+fn propagate_constants_unknown_after_unbalanced_loop() {
+    let initial = parse(",[>,]>+").unwrap();
+    let expected = initial.clone();
+    assert_eq!(propagate_constants(initial), expected);
 }
 
 #[test]
-fn prev_mutate_increment() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
+fn propagate_constants_into_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(3));
+    changes.insert(2, Wrapping(1));
+    let initial = vec![
+        Set {
+            amount: Wrapping(2),
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Read {
-            position: Some(Position { start: 0, end: 0 }),
+            offset: 2,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 2, end: 2 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
-}
-
-#[test]
-fn prev_mutate_ignores_offset_at_index() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
+    let expected = vec![
+        Set {
+            amount: Wrapping(2),
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        // The fact that this instruction is at offset 1 should be irrelevant.
+        Read {
+            offset: 2,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Set {
+            amount: Wrapping(6),
+            offset: 1,
+            position: Some(Position { start: 2, end: 2 }),
+        },
         Increment {
             amount: Wrapping(2),
-            offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            offset: 2,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+    assert_eq!(propagate_constants(initial), expected);
 }
 
 #[test]
-fn prev_mutate_multiply_offset_matches() {
-    let mut changes = HashMap::new();
-    changes.insert(-1, Wrapping(-1));
+fn propagate_constants_removes_redundant_set() {
+    let initial = zeroing_loops(parse("[-]").unwrap());
+    assert_eq!(propagate_constants(initial), vec![]);
+}
 
-    let instrs = vec![
-        MultiplyMove {
-            changes: changes.clone(),
-            position: Some(Position { start: 0, end: 0 }),
+#[test]
+fn remove_dead_stores_overwritten() {
+    let initial = parse("+>+<,.").unwrap();
+    let expected = vec![
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
         },
         PointerIncrement {
             amount: -1,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 3, end: 3 }),
         },
         Read {
-            position: Some(Position { start: 0, end: 0 }),
+            offset: 0,
+            position: Some(Position { start: 4, end: 4 }),
+        },
+        Write {
+            offset: 0,
+            position: Some(Position { start: 5, end: 5 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+    assert_eq!(remove_dead_stores(initial), expected);
 }
 
 #[test]
-fn prev_mutate_multiply_offset_doesnt_match() {
-    let mut changes = HashMap::new();
-    changes.insert(1, Wrapping(2));
-
-    let instrs = vec![
-        MultiplyMove {
-            changes: changes.clone(),
+fn remove_dead_stores_across_pointer_movement() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 1,
             position: Some(Position { start: 0, end: 0 }),
         },
         PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 0, end: 0 }),
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
         },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        Write {
+            offset: 0,
+            position: Some(Position { start: 3, end: 3 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 2), None);
+    let expected = initial[1..].to_vec();
+    assert_eq!(remove_dead_stores(initial), expected);
 }
 
-/// MultiplyMove zeroes the current cell, so it counts as a mutation
-/// of the current value.
 #[test]
-fn prev_mutate_multiply_ignore_offset() {
-    let mut changes = HashMap::new();
-    changes.insert(1, Wrapping(-1));
+fn remove_dead_stores_keeps_written_cells() {
+    let initial = parse("+.+.").unwrap();
+    let expected = initial.clone();
+    assert_eq!(remove_dead_stores(initial), expected);
+}
 
-    let instrs = vec![
-        MultiplyMove {
-            changes: changes.clone(),
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+#[test]
+fn remove_dead_stores_keeps_loop_cell() {
+    let initial = parse(",>+<[->+<]>.").unwrap();
+    let expected = initial.clone();
+    assert_eq!(remove_dead_stores(initial), expected);
 }
 
+/// A cell modified at the end of a loop body may be read at the
+/// start of the next iteration.
 #[test]
-fn prev_mutate_no_predecessors() {
-    let instrs = vec![Read {
-        position: Some(Position { start: 0, end: 0 }),
-    }];
-    assert_eq!(previous_cell_change(&instrs, 0), None);
+fn remove_dead_stores_keeps_cell_read_next_iteration() {
+    let initial = parse(",[>.,<-]").unwrap();
+    let expected = initial.clone();
+    assert_eq!(remove_dead_stores(initial), expected);
 }
 
 #[test]
-fn prev_mutate_increment_matching_offset() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
+fn remove_dead_stores_in_loop() {
+    // The first increment of cell 1 is overwritten by the read.
+    let initial = parse(",[>+,.<-]").unwrap();
+    let mut expected = initial.clone();
+    if let Loop { ref mut body, .. } = expected[1] {
+        body.remove(1);
+    }
+    assert_eq!(remove_dead_stores(initial), expected);
+}
+
+#[test]
+fn remove_dead_stores_unbalanced_loop() {
+    // We can't tell which cells the loop reads.
+    let initial = parse("+>,[.>,]").unwrap();
+    let expected = initial.clone();
+    assert_eq!(remove_dead_stores(initial), expected);
+}
+
+#[test]
+fn remove_dead_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(2));
+    let initial = vec![
+        Read {
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        Increment {
-            amount: Wrapping(10),
-            offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 1, end: 1 }),
         },
         Read {
-            position: Some(Position { start: 0, end: 0 }),
+            offset: 1,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        Write {
+            offset: 1,
+            position: Some(Position { start: 3, end: 3 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+    let mut expected = initial.clone();
+    expected.remove(1);
+    assert_eq!(remove_dead_stores(initial), expected);
 }
 
+/// The body of an if must still leave its cell at zero.
 #[test]
-fn prev_mutate_ignore_write() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
+fn remove_dead_stores_keeps_if_set() {
+    let initial = vec![
+        Read {
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        Write {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+        If {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            }],
+            position: Some(Position { start: 1, end: 3 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+    let expected = initial.clone();
+    assert_eq!(remove_dead_stores(initial), expected);
 }
 
 #[test]
-fn prev_mutate_consider_pointer_increment() {
-    let instrs = vec![
+fn pass_manager_runs_selected_passes() {
+    let initial = parse("++>>").unwrap();
+    let expected = vec![
         Increment {
-            amount: Wrapping(1),
-            offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 1 }),
         },
         PointerIncrement {
             amount: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 2, end: 2 }),
         },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 3, end: 3 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+    let (result, report) =
+        PassManager::new(&Some("combine_inc".to_owned())).run_with_report(initial);
+    assert_eq!(result, expected);
+    assert_eq!(report.warnings, vec![]);
 }
 
 #[test]
-fn prev_mutate_set() {
-    let instrs = vec![
-        Set {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+fn pass_manager_warns_without_fixed_point() {
+    let initial = parse(",++.").unwrap();
+    let (_, report) = PassManager::new(&None)
+        .with_max_iterations(1)
+        .run_with_report(initial);
+    assert_eq!(
+        report.warnings,
+        vec![Warning {
+            message: "Ran peephole optimisations 1 times but did not reach a fixed point."
+                .to_owned(),
+            position: None,
+        }]
+    );
 }
 
 #[test]
-fn next_mutate_loop() {
-    // If we see a loop, we don't know when the current cell is next
-    // mutated.
-    let instrs = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Loop {
-            body: vec![],
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(next_cell_change(&instrs, 0), None);
+fn pass_manager_reaches_fixed_point() {
+    let initial = parse(",++.").unwrap();
+    let (_, report) = PassManager::new(&None)
+        .with_max_iterations(2)
+        .run_with_report(initial);
+    assert_eq!(report.warnings, vec![]);
 }
 
 #[test]
-fn next_mutate_increment() {
-    let instrs = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: -1,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(next_cell_change(&instrs, 0), Some(2));
+fn pass_manager_remarks() {
+    let initial = parse(",[>+<-][.]").unwrap();
+    let (_, report) = PassManager::new(&None)
+        .with_remarks()
+        .run_with_report(initial);
+    assert_eq!(
+        report.remarks,
+        vec![
+            Remark {
+                message: "Loop converted to a multiply-move (multiply).".to_owned(),
+                position: Some(Position { start: 1, end: 6 }),
+            },
+            Remark {
+                message: "Loop removed as dead (const_prop).".to_owned(),
+                position: Some(Position { start: 7, end: 9 }),
+            },
+        ]
+    );
 }
 
 #[test]
-fn next_mutate_consider_pointer_increment() {
-    let instrs = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        PointerIncrement {
-            amount: 1,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: -1,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
+fn pass_manager_remarks_nested_loop() {
+    let initial = parse(",[>,[-]<,]").unwrap();
+    let (_, report) = PassManager::new(&None)
+        .with_remarks()
+        .run_with_report(initial);
+    assert_eq!(
+        report.remarks,
+        vec![Remark {
+            message: "Loop converted to a set (zeroing_loop).".to_owned(),
+            position: Some(Position { start: 4, end: 6 }),
+        }]
+    );
+}
+
+#[test]
+fn pass_manager_remarks_nested_multiply() {
+    let initial = parse(",>,<[->[->+>+<<]>>[-<<+>>]<<<]").unwrap();
+    let (_, report) = PassManager::new(&None)
+        .with_remarks()
+        .run_with_report(initial);
+    let outer_loop: Vec<_> = report
+        .remarks
+        .iter()
+        .filter(|remark| remark.position == Some(Position { start: 4, end: 29 }))
+        .map(|remark| remark.message.as_str())
+        .collect();
+    assert_eq!(
+        outer_loop,
+        vec![
+            "Loop converted to a nested multiply (nested_multiply).",
+            "Loop converted to an if (if).",
+        ]
+    );
+}
 
-    assert_eq!(next_cell_change(&instrs, 0), Some(3));
+#[test]
+fn pass_manager_no_remarks_by_default() {
+    let initial = parse(",[-]").unwrap();
+    let (_, report) = PassManager::new(&None).run_with_report(initial);
+    assert_eq!(report.remarks, vec![]);
 }
@@ -0,0 +1,216 @@
+//! Printing the IR between passes, for `--print-after`.
+
+use crate::bfir::AstNode;
+
+/// The IR for `instrs`, one instruction per line.
+pub fn format_instrs(instrs: &[AstNode]) -> String {
+    let mut result = String::new();
+    for instr in instrs {
+        result.push_str(&format!("{}\n", instr));
+    }
+    result
+}
+
+/// A line-based diff from `old` to `new`. Removed lines start with
+/// `-` and added lines start with `+`. Unchanged lines are omitted.
+pub fn diff_lines(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // Skip the common prefix and suffix, so the table below is small
+    // when a pass only changes part of the program.
+    let prefix = old.iter().zip(&new).take_while(|(o, n)| o == n).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(o, n)| o == n)
+        .count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+
+    let mut result = String::new();
+    match shortest_edit(old, new) {
+        Some(edits) => {
+            for edit in edits {
+                match edit {
+                    Edit::Remove(i) => result.push_str(&format!("-{}\n", old[i])),
+                    Edit::Add(j) => result.push_str(&format!("+{}\n", new[j])),
+                }
+            }
+        }
+        // Too much changed to find a minimal diff cheaply, so show
+        // the whole region as replaced.
+        None => {
+            for line in old {
+                result.push_str(&format!("-{}\n", line));
+            }
+            for line in new {
+                result.push_str(&format!("+{}\n", line));
+            }
+        }
+    }
+    result
+}
+
+/// The most lines we add or remove in a diff before giving up on
+/// finding a minimal one. Searching takes memory quadratic in this.
+const MAX_EDITS: usize = 1000;
+
+/// A line removed from the old text, or added from the new text.
+#[derive(Debug, PartialEq, Eq)]
+enum Edit {
+    Remove(usize),
+    Add(usize),
+}
+
+/// The shortest sequence of edits from `old` to `new`, using Myers'
+/// O(ND) algorithm, or None if it needs more than `MAX_EDITS`.
+fn shortest_edit(old: &[&str], new: &[&str]) -> Option<Vec<Edit>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+
+    // After d edits, furthest[d][k + d] is the furthest index into
+    // `old` we can reach on diagonal k, where k = x - y.
+    let mut furthest: Vec<Vec<isize>> = vec![];
+    let mut found = false;
+    'search: for d in 0..=(n + m).min(MAX_EDITS as isize) {
+        let mut row = vec![0; 2 * d as usize + 1];
+        for k in (-d..=d).step_by(2) {
+            let mut x = match furthest.last() {
+                None => 0,
+                Some(prev) => {
+                    let prev_x = |k: isize| prev[(k + d - 1) as usize];
+                    if k == -d || (k != d && prev_x(k - 1) < prev_x(k + 1)) {
+                        // Add a line, moving down from diagonal k + 1.
+                        prev_x(k + 1)
+                    } else {
+                        // Remove a line, moving right from diagonal k - 1.
+                        prev_x(k - 1) + 1
+                    }
+                }
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            row[(k + d) as usize] = x;
+
+            if x >= n && y >= m {
+                furthest.push(row);
+                found = true;
+                break 'search;
+            }
+        }
+        furthest.push(row);
+    }
+
+    if !found {
+        return None;
+    }
+
+    // Walk back from the end to recover the edits.
+    let (mut x, mut y) = (n, m);
+    let mut edits = vec![];
+    for d in (1..furthest.len() as isize).rev() {
+        let prev = &furthest[d as usize - 1];
+        let prev_x = |k: isize| prev[(k + d - 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && prev_x(k - 1) < prev_x(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        x = prev_x(prev_k);
+        y = x - prev_k;
+        if prev_k == k + 1 {
+            edits.push(Edit::Add(y as usize));
+        } else {
+            edits.push(Edit::Remove(x as usize));
+        }
+    }
+    edits.reverse();
+    Some(edits)
+}
+
+/// The IR after a pass called `title`, and how it differs from
+/// `before`.
+pub fn ir_after(title: &str, before: &[AstNode], after: &[AstNode]) -> String {
+    if before == after {
+        return format!("*** IR after {}: no changes ***\n", title);
+    }
+
+    let after_text = format_instrs(after);
+    format!(
+        "*** IR after {} ***\n{}*** Diff after {} ***\n{}",
+        title,
+        after_text,
+        title,
+        diff_lines(&format_instrs(before), &after_text)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::peephole::combine_increments;
+
+    #[test]
+    fn diff_changed_line() {
+        assert_eq!(diff_lines("a\nb\nc\n", "a\nx\nc\n"), "-b\n+x\n");
+    }
+
+    #[test]
+    fn diff_added_and_removed_lines() {
+        assert_eq!(diff_lines("a\nb\n", "b\nc\n"), "-a\n+c\n");
+    }
+
+    #[test]
+    fn diff_is_minimal() {
+        assert_eq!(
+            diff_lines("a\nb\nc\na\nb\nb\na\n", "c\nb\na\nb\na\nc\n"),
+            "-a\n-b\n+b\n-b\n+c\n"
+        );
+    }
+
+    #[test]
+    fn diff_large_change() {
+        // Beyond MAX_EDITS, we show the whole region as replaced.
+        let old: String = (0..MAX_EDITS).map(|i| format!("a{}\n", i)).collect();
+        let new: String = (0..MAX_EDITS).map(|i| format!("b{}\n", i)).collect();
+        let diff = diff_lines(&format!("x\n{}", old), &format!("x\n{}", new));
+        assert_eq!(diff.lines().count(), 2 * MAX_EDITS);
+        assert!(diff.starts_with("-a0\n"));
+        assert!(diff.ends_with(&format!("+b{}\n", MAX_EDITS - 1)));
+    }
+
+    #[test]
+    fn diff_identical() {
+        assert_eq!(diff_lines("a\nb\n", "a\nb\n"), "");
+    }
+
+    #[test]
+    fn ir_after_unchanged() {
+        let instrs = parse("+").unwrap();
+        assert_eq!(
+            ir_after("combine_inc", &instrs, &instrs),
+            "*** IR after combine_inc: no changes ***\n"
+        );
+    }
+
+    #[test]
+    fn ir_after_combine() {
+        let before = parse("++").unwrap();
+        let after = combine_increments(before.clone());
+        assert_eq!(
+            ir_after("combine_inc", &before, &after),
+            "*** IR after combine_inc ***\n\
+             Increment { amount: 2, offset: 0, position: Some(0-1) }\n\
+             *** Diff after combine_inc ***\n\
+             -Increment { amount: 1, offset: 0, position: Some(0) }\n\
+             -Increment { amount: 1, offset: 0, position: Some(1) }\n\
+             +Increment { amount: 2, offset: 0, position: Some(0-1) }\n"
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! Execution profiles, showing where a program spends its time.
+//!
+//! We number instructions in the order they appear in the program
+//! (loops before their bodies), and count how many times each one
+//! executes. For loops, this is the number of times we tested the
+//! loop's cell, and we also count the number of iterations.
+//!
+//! Profiles come from the interpreter (`bfc run --profile`), or from
+//! compiled programs (`bfc --profile`), which write their counts to
+//! `PROFILE_FILENAME` when they exit.
+
+use std::collections::HashMap;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Position};
+use crate::diagnostics::{Info, Level};
+use crate::execution::ExecutionState;
+
+/// The file that instrumented programs write their counts to.
+pub const PROFILE_FILENAME: &str = "bfc.profile";
+
+/// The first line of a profile file.
+pub const PROFILE_HEADER: &str = "bfc profile";
+
+/// The number of loops we report.
+const MAX_HOT_SPOTS: usize = 10;
+
+pub struct Profile<'a> {
+    instrs: &'a [AstNode],
+    /// The number of each instruction.
+    ids: HashMap<*const AstNode, usize>,
+    /// The number of times each instruction executed.
+    pub counts: Vec<u64>,
+    /// The number of times each loop executed its body.
+    pub iterations: Vec<u64>,
+}
+
+/// A loop and how much time we spent in it.
+#[derive(Debug, PartialEq, Eq)]
+struct HotSpot {
+    position: Option<Position>,
+    iterations: u64,
+    /// Steps spent in this loop, excluding nested loops.
+    self_steps: u64,
+    /// Steps spent in this loop, including nested loops.
+    total_steps: u64,
+}
+
+/// Add `instrs` and everything inside them to `numbered`, in
+/// program order.
+fn number_instrs<'a>(instrs: &'a [AstNode], numbered: &mut Vec<&'a AstNode>) {
+    for instr in instrs {
+        numbered.push(instr);
+        match instr {
+            Loop { body, .. } | If { body, .. } => number_instrs(body, numbered),
+            _ => {}
+        }
+    }
+}
+
+/// The number of instructions in `instrs`, including loop bodies.
+pub fn num_instrs(instrs: &[AstNode]) -> usize {
+    let mut numbered = vec![];
+    number_instrs(instrs, &mut numbered);
+    numbered.len()
+}
+
+impl<'a> Profile<'a> {
+    pub fn new(instrs: &'a [AstNode]) -> Self {
+        let mut numbered = vec![];
+        number_instrs(instrs, &mut numbered);
+        let ids = numbered
+            .iter()
+            .enumerate()
+            .map(|(id, &instr)| (instr as *const AstNode, id))
+            .collect();
+
+        Profile {
+            instrs,
+            ids,
+            counts: vec![0; numbered.len()],
+            iterations: vec![0; numbered.len()],
+        }
+    }
+
+    /// Read the counts that an instrumented program wrote for
+    /// `instrs`.
+    pub fn from_file_contents(instrs: &'a [AstNode], contents: &str) -> Result<Self, String> {
+        let mut profile = Profile::new(instrs);
+
+        let mut lines = contents.lines();
+        let expected_header = format!("{} {}", PROFILE_HEADER, profile.counts.len());
+        match lines.next() {
+            Some(header) if header == expected_header => {}
+            Some(header) if header.starts_with(PROFILE_HEADER) => {
+                return Err(
+                    "This profile is for a different program. Generate the report with the \
+                     same options that you compiled with."
+                        .to_owned(),
+                );
+            }
+            _ => return Err("This is not a bfc profile.".to_owned()),
+        }
+
+        for (id, line) in lines.enumerate() {
+            let numbers: Vec<u64> = line
+                .split_whitespace()
+                .map(|number| number.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("Invalid counts on line {}.", id + 2))?;
+            match (numbers.as_slice(), id < profile.counts.len()) {
+                ([count, iterations], true) => {
+                    profile.counts[id] = *count;
+                    profile.iterations[id] = *iterations;
+                }
+                _ => return Err(format!("Invalid counts on line {}.", id + 2)),
+            }
+        }
+        Ok(profile)
+    }
+
+    /// Count `instr` executing, before it executes with `state`.
+    pub fn record(&mut self, instr: &AstNode, state: &ExecutionState) {
+        let id = self.ids[&(instr as *const AstNode)];
+        self.counts[id] += 1;
+
+        let tested_cell = match *instr {
+            Loop { offset, .. } => Some(state.cell_ptr + offset),
+            If { .. } => Some(state.cell_ptr),
+            _ => None,
+        };
+        if let Some(cell_index) = tested_cell {
+            if cell_index >= 0
+                && state
+                    .cells
+                    .get(cell_index as usize)
+                    .is_some_and(|cell| cell.0 != 0)
+            {
+                self.iterations[id] += 1;
+            }
+        }
+    }
+
+    pub fn total_steps(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Find the loops in `instrs` (starting with instruction number
+    /// `*next_id`). Returns the steps spent in `instrs`, without and
+    /// with nested loops.
+    fn find_hot_spots(
+        &self,
+        instrs: &[AstNode],
+        next_id: &mut usize,
+        hot_spots: &mut Vec<HotSpot>,
+    ) -> (u64, u64) {
+        let mut self_steps = 0;
+        let mut total_steps = 0;
+        for instr in instrs {
+            let id = *next_id;
+            *next_id += 1;
+            total_steps += self.counts[id];
+
+            if let Loop { body, .. } | If { body, .. } = instr {
+                let (body_self_steps, body_total_steps) =
+                    self.find_hot_spots(body, next_id, hot_spots);
+                hot_spots.push(HotSpot {
+                    position: get_position(instr),
+                    iterations: self.iterations[id],
+                    self_steps: self.counts[id] + body_self_steps,
+                    total_steps: self.counts[id] + body_total_steps,
+                });
+                total_steps += body_total_steps;
+            } else {
+                self_steps += self.counts[id];
+            }
+        }
+        (self_steps, total_steps)
+    }
+
+    /// The loops where we spent the most time, excluding time in
+    /// nested loops.
+    fn hot_spots(&self) -> Vec<HotSpot> {
+        let mut hot_spots = vec![];
+        self.find_hot_spots(self.instrs, &mut 0, &mut hot_spots);
+
+        hot_spots.retain(|hot_spot| hot_spot.self_steps > 0);
+        hot_spots.sort_by_key(|hot_spot| std::cmp::Reverse(hot_spot.self_steps));
+        hot_spots.truncate(MAX_HOT_SPOTS);
+        hot_spots
+    }
+
+    /// Describe the hottest loops, with snippets from `source`.
+    pub fn report(&self, filename: &str, source: &str) -> Vec<Info> {
+        let total_steps = self.total_steps().max(1) as f64;
+        self.hot_spots()
+            .into_iter()
+            .map(|hot_spot| Info {
+                level: Level::Note,
+                filename: filename.to_owned(),
+                message: format!(
+                    "This loop ran {} iterations and {} steps ({:.1}% of all steps, {:.1}% \
+                     including nested loops).",
+                    hot_spot.iterations,
+                    hot_spot.self_steps,
+                    100.0 * hot_spot.self_steps as f64 / total_steps,
+                    100.0 * hot_spot.total_steps as f64 / total_steps
+                ),
+                position: hot_spot.position,
+                source: Some(source.to_owned()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::execution::Input;
+    use crate::interpreter::Interpreter;
+    use pretty_assertions::assert_eq;
+
+    fn profile_run(instrs: &[AstNode]) -> Profile<'_> {
+        let mut profile = Profile::new(instrs);
+        let mut interpreter = Interpreter::new(instrs);
+        while let Some(instr) = interpreter.current_instr() {
+            profile.record(instr, &interpreter.state);
+            interpreter.step(&mut Input::Runtime).unwrap();
+        }
+        profile
+    }
+
+    #[test]
+    fn count_loop_iterations() {
+        let instrs = parse("+++[-]").unwrap();
+        let profile = profile_run(&instrs);
+
+        assert_eq!(profile.counts, vec![1, 1, 1, 4, 3]);
+        assert_eq!(profile.iterations, vec![0, 0, 0, 3, 0]);
+        assert_eq!(profile.total_steps(), 10);
+    }
+
+    #[test]
+    fn hot_spots_exclude_nested_loops() {
+        let instrs = parse("++[>+++[-]<-]").unwrap();
+        let profile = profile_run(&instrs);
+        let hot_spots = profile.hot_spots();
+
+        assert_eq!(
+            hot_spots,
+            vec![
+                HotSpot {
+                    position: Some(Position { start: 2, end: 12 }),
+                    iterations: 2,
+                    self_steps: 15,
+                    total_steps: 29,
+                },
+                HotSpot {
+                    position: Some(Position { start: 7, end: 9 }),
+                    iterations: 6,
+                    self_steps: 14,
+                    total_steps: 14,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn read_profile_file() {
+        let instrs = parse("[-]").unwrap();
+        let profile = Profile::from_file_contents(&instrs, "bfc profile 2\n3 2\n2 0\n").unwrap();
+
+        assert_eq!(profile.counts, vec![3, 2]);
+        assert_eq!(profile.iterations, vec![2, 0]);
+    }
+
+    #[test]
+    fn reject_profile_for_other_program() {
+        let instrs = parse("[-]").unwrap();
+        assert!(Profile::from_file_contents(&instrs, "bfc profile 3\n").is_err());
+        assert!(Profile::from_file_contents(&instrs, "hello").is_err());
+        assert!(Profile::from_file_contents(&instrs, "bfc profile 2\n1 x\n").is_err());
+    }
+}
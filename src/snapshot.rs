@@ -0,0 +1,219 @@
+//! Snapshots of interpreter state, so long runs can be saved to disk
+//! and resumed later.
+//!
+//! `ExecutionState` refers to the next instruction by reference, so
+//! snapshots store its path instead: the index of the instruction in
+//! each enclosing loop body. A snapshot is a text file:
+//!
+//! ```text
+//! bfc snapshot
+//! program 2f0b1c6a9e3d4f58
+//! steps 1234
+//! path 3 0 2
+//! pointer 1
+//! input 2
+//! cells 30000 5 0 72
+//! outputs 72 105
+//! ```
+//!
+//! `input` is how many bytes the program had read from stdin. We
+//! omit trailing zero cells.
+
+use std::num::Wrapping;
+
+use crate::bfir::Cell;
+
+const SNAPSHOT_HEADER: &str = "bfc snapshot";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Identifies the program, see `program_id`.
+    pub program_id: u64,
+    pub steps: u64,
+    pub path: Vec<usize>,
+    pub cell_ptr: isize,
+    /// How many bytes of input the program had read.
+    pub input_offset: u64,
+    pub cells: Vec<Cell>,
+    pub outputs: Vec<i8>,
+}
+
+/// A hash of the source and optimisation level, so we don't resume a
+/// snapshot with a different program. This is FNV-1a, which (unlike
+/// `DefaultHasher`) gives the same result in every build.
+pub fn program_id(source: &str, opt_level: u8) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in source.as_bytes().iter().chain(&[opt_level]) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Parse the numbers after the key on a line of a snapshot.
+fn parse_numbers<T: std::str::FromStr>(key: &str, values: &str) -> Result<Vec<T>, String> {
+    values
+        .split_whitespace()
+        .map(|value| value.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Invalid {} in snapshot.", key))
+}
+
+/// Parse a line of a snapshot that should contain a single number.
+fn parse_number<T: std::str::FromStr>(key: &str, values: &str) -> Result<T, String> {
+    values
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid {} in snapshot.", key))
+}
+
+impl Snapshot {
+    pub fn to_text(&self) -> String {
+        let num_initialised = self
+            .cells
+            .iter()
+            .rposition(|cell| cell.0 != 0)
+            .map_or(0, |index| index + 1);
+
+        let mut cells_line = format!("cells {}", self.cells.len());
+        for cell in &self.cells[..num_initialised] {
+            cells_line.push_str(&format!(" {}", cell.0));
+        }
+        let mut outputs_line = "outputs".to_owned();
+        for output in &self.outputs {
+            outputs_line.push_str(&format!(" {}", output));
+        }
+        let path: Vec<_> = self.path.iter().map(|index| index.to_string()).collect();
+
+        format!(
+            "{}\nprogram {:016x}\nsteps {}\npath {}\npointer {}\ninput {}\n{}\n{}\n",
+            SNAPSHOT_HEADER,
+            self.program_id,
+            self.steps,
+            path.join(" "),
+            self.cell_ptr,
+            self.input_offset,
+            cells_line,
+            outputs_line
+        )
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        if lines.next() != Some(SNAPSHOT_HEADER) {
+            return Err("This is not a bfc snapshot.".to_owned());
+        }
+
+        let mut program_id = None;
+        let mut steps = None;
+        let mut path = None;
+        let mut cell_ptr = None;
+        let mut input_offset = None;
+        let mut cells = None;
+        let mut outputs = None;
+        for line in lines {
+            let (key, values) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "program" => {
+                    program_id = Some(
+                        u64::from_str_radix(values, 16)
+                            .map_err(|_| "Invalid program in snapshot.".to_owned())?,
+                    )
+                }
+                "steps" => steps = Some(parse_number(key, values)?),
+                "path" => path = Some(parse_numbers(key, values)?),
+                "pointer" => cell_ptr = Some(parse_number(key, values)?),
+                "input" => input_offset = Some(parse_number(key, values)?),
+                "cells" => {
+                    let values: Vec<i64> = parse_numbers(key, values)?;
+                    let (&len, values) = values
+                        .split_first()
+                        .ok_or_else(|| "Invalid cells in snapshot.".to_owned())?;
+                    if len < 0 || values.len() as i64 > len {
+                        return Err("Invalid cells in snapshot.".to_owned());
+                    }
+                    let mut cell_values = vec![Wrapping(0); len as usize];
+                    for (cell, &value) in cell_values.iter_mut().zip(values) {
+                        *cell = Wrapping(value as i8);
+                    }
+                    cells = Some(cell_values);
+                }
+                "outputs" => outputs = Some(parse_numbers(key, values)?),
+                _ => return Err(format!("Unexpected line in snapshot: {}", line)),
+            }
+        }
+
+        match (
+            program_id,
+            steps,
+            path,
+            cell_ptr,
+            input_offset,
+            cells,
+            outputs,
+        ) {
+            (
+                Some(program_id),
+                Some(steps),
+                Some(path),
+                Some(cell_ptr),
+                Some(input_offset),
+                Some(cells),
+                Some(outputs),
+            ) => Ok(Snapshot {
+                program_id,
+                steps,
+                path,
+                cell_ptr,
+                input_offset,
+                cells,
+                outputs,
+            }),
+            _ => Err("This snapshot is incomplete.".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let snapshot = Snapshot {
+            program_id: 0x1234,
+            steps: 10,
+            path: vec![3, 0],
+            cell_ptr: 1,
+            input_offset: 2,
+            cells: vec![Wrapping(5), Wrapping(-1), Wrapping(0), Wrapping(0)],
+            outputs: vec![72, 105],
+        };
+        let text = snapshot.to_text();
+
+        assert_eq!(
+            text,
+            "bfc snapshot\nprogram 0000000000001234\nsteps 10\npath 3 0\npointer 1\n\
+             input 2\ncells 4 5 -1\noutputs 72 105\n"
+        );
+        assert_eq!(Snapshot::from_text(&text), Ok(snapshot));
+    }
+
+    #[test]
+    fn reject_invalid_snapshots() {
+        assert!(Snapshot::from_text("hello").is_err());
+        assert!(Snapshot::from_text("bfc snapshot\nsteps 1\n").is_err());
+        assert!(Snapshot::from_text(
+            "bfc snapshot\nprogram 1\nsteps 1\npath 0\npointer 0\ninput 0\ncells 1 1 2\noutputs\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn program_id_depends_on_opt_level() {
+        assert_eq!(program_id("+", 0), program_id("+", 0));
+        assert_ne!(program_id("+", 0), program_id("+", 1));
+        assert_ne!(program_id("+", 0), program_id("-", 0));
+    }
+}
@@ -1,6 +1,9 @@
 use quickcheck::{quickcheck, TestResult};
 
-use crate::bfir::AstNode;
+use std::num::Wrapping;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{parse, AstNode};
 use crate::execution::Outcome::*;
 use crate::execution::{execute_with_state, ExecutionState};
 use crate::peephole::*;
@@ -23,7 +26,7 @@ where
     // Optimisations may change malformed programs to well-formed
     // programs, so we ignore programs that don't terminate nicely.
     match result {
-        RuntimeError(_) | OutOfSteps => return TestResult::discard(),
+        RuntimeError(_) | OutOfSteps | InfiniteLoop(_) => return TestResult::discard(),
         _ => (),
     }
 
@@ -102,6 +105,49 @@ fn annotate_known_zero_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn extract_scan_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, extract_scan, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn extract_if_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, extract_if, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn extract_if_zeroed_body_is_sound() {
+    fn is_sound(initial: i8, mut body: Vec<AstNode>) -> TestResult {
+        // Arbitrary loops rarely finish on a zero cell, so
+        // construct one that does.
+        body.push(Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: None,
+        });
+        let instrs = vec![
+            Set {
+                amount: Wrapping(initial),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body,
+                offset: 0,
+                position: None,
+            },
+        ];
+        transform_is_sound(instrs, extract_if, true, None)
+    }
+    quickcheck(is_sound as fn(i8, Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn extract_multiply_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -110,6 +156,78 @@ fn extract_multiply_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn extract_multiply_any_step_is_sound() {
+    fn is_sound(initial: i8, step: i8, factor: i8) -> TestResult {
+        // [(step)>(factor)<] with cell #0 set to initial.
+        let instrs = vec![
+            Set {
+                amount: Wrapping(initial),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![
+                    Increment {
+                        amount: Wrapping(step),
+                        offset: 0,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(factor),
+                        offset: 1,
+                        position: None,
+                    },
+                ],
+                offset: 0,
+                position: None,
+            },
+        ];
+        transform_is_sound(instrs, extract_multiply, true, None)
+    }
+    quickcheck(is_sound as fn(i8, i8, i8) -> TestResult)
+}
+
+#[test]
+fn extract_nested_multiply_is_sound() {
+    fn is_sound(counter: u8, cells: Vec<i8>, step: i8) -> TestResult {
+        // Limit the iterations so we don't run out of steps.
+        let mut instrs = vec![Set {
+            amount: Wrapping((counter % 64) as i8),
+            offset: 0,
+            position: None,
+        }];
+        for (i, value) in cells.into_iter().take(4).enumerate() {
+            instrs.push(Set {
+                amount: Wrapping(value),
+                offset: i as isize + 1,
+                position: None,
+            });
+        }
+        // [(step)>[->+>+<<]>>[-<<+>>]<<+<]
+        let mut body = parse("->[->+>+<<]>>[-<<+>>]<<+<").unwrap();
+        body[0] = Increment {
+            amount: Wrapping(step),
+            offset: 0,
+            position: None,
+        };
+        instrs.push(Loop {
+            body,
+            offset: 0,
+            position: None,
+        });
+        let instrs = extract_multiply(instrs);
+
+        transform_is_sound(instrs, extract_nested_multiply, true, None)
+    }
+    quickcheck(is_sound as fn(u8, Vec<i8>, i8) -> TestResult);
+
+    fn optimize_is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, extract_nested_multiply, true, None)
+    }
+    quickcheck(optimize_is_sound as fn(Vec<AstNode>) -> TestResult);
+}
+
 #[test]
 fn simplify_loops_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -173,6 +291,32 @@ fn sort_by_offset_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn propagate_constants_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
+        transform_is_sound(instrs, propagate_constants, true, read_value)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
+}
+
+#[test]
+fn remove_dead_stores_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
+        // Cell values at termination aren't observable, so this
+        // pass may change them.
+        transform_is_sound(instrs, remove_dead_stores, false, read_value)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
+}
+
+#[test]
+fn combine_writes_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
+        transform_is_sound(instrs, combine_writes, true, read_value)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
+}
+
 #[test]
 fn test_overall_optimize_is_sound() {
     fn optimize_ignore_warnings(instrs: Vec<AstNode>) -> Vec<AstNode> {
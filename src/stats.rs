@@ -0,0 +1,265 @@
+//! Statistics about what the optimiser did, reported by `--stats`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::bfir::AstNode;
+use crate::bfir::AstNode::*;
+
+/// What a single peephole pass did, summed over every time it ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassStats {
+    pub name: &'static str,
+    /// The number of times the pass ran.
+    pub runs: u64,
+    /// The number of runs that changed the program.
+    pub changes: u64,
+    /// The number of instructions removed.
+    pub removed: usize,
+    /// The number of new instructions the pass produced.
+    pub rewritten: usize,
+    pub time: Duration,
+}
+
+impl PassStats {
+    pub fn new(name: &'static str) -> Self {
+        PassStats {
+            name,
+            runs: 0,
+            changes: 0,
+            removed: 0,
+            rewritten: 0,
+            time: Duration::default(),
+        }
+    }
+}
+
+/// How far compile time execution got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpeculationStats {
+    pub steps: u64,
+    /// The number of top-level instructions that we executed
+    /// completely.
+    pub executed_instrs: usize,
+    pub total_instrs: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Stats {
+    pub passes: Vec<PassStats>,
+    /// The number of times we ran the passes before reaching a
+    /// fixed point (or giving up).
+    pub iterations: u64,
+    pub speculation: Option<SpeculationStats>,
+}
+
+/// Does `instrs` contain `target` (the same instruction, not an equal
+/// one), possibly inside a loop?
+fn contains_instr(instrs: &[AstNode], target: &AstNode) -> bool {
+    instrs.iter().any(|instr| {
+        std::ptr::eq(instr, target)
+            || match instr {
+                Loop { body, .. } | If { body, .. } => contains_instr(body, target),
+                _ => false,
+            }
+    })
+}
+
+/// The number of top-level instructions that compile time execution
+/// finished, given the instruction where runtime execution starts.
+pub fn executed_instrs(instrs: &[AstNode], start_instr: Option<&AstNode>) -> usize {
+    match start_instr {
+        Some(start_instr) => instrs
+            .iter()
+            .position(|instr| contains_instr(std::slice::from_ref(instr), start_instr))
+            .unwrap_or(0),
+        None => instrs.len(),
+    }
+}
+
+/// A description of `instr` that ignores loop bodies, so we can
+/// compare instructions before and after a pass.
+fn shallow_description(instr: &AstNode) -> String {
+    match instr {
+        Loop {
+            offset, position, ..
+        } => format!("Loop {} {:?}", offset, position),
+        If { position, .. } => format!("If {:?}", position),
+        MultiplyMove { changes, position } => {
+            let mut changes: Vec<_> = changes.iter().collect();
+            changes.sort();
+            format!("MultiplyMove {:?} {:?}", changes, position)
+        }
+        MultiplyAdd { changes, position } => {
+            let mut changes: Vec<_> = changes.iter().collect();
+            changes.sort();
+            format!("MultiplyAdd {:?} {:?}", changes, position)
+        }
+        _ => format!("{:?}", instr),
+    }
+}
+
+fn count_descriptions(instrs: &[AstNode], counts: &mut HashMap<String, usize>) {
+    for instr in instrs {
+        *counts.entry(shallow_description(instr)).or_insert(0) += 1;
+        if let Loop { body, .. } | If { body, .. } = instr {
+            count_descriptions(body, counts);
+        }
+    }
+}
+
+/// Compare the instructions before and after a pass, returning the
+/// number of instructions removed and the number of new
+/// instructions.
+pub fn count_changes(before: &[AstNode], after: &[AstNode]) -> (usize, usize) {
+    let mut before_counts = HashMap::new();
+    count_descriptions(before, &mut before_counts);
+    let mut after_counts = HashMap::new();
+    count_descriptions(after, &mut after_counts);
+
+    let mut old = 0;
+    for (description, count) in &before_counts {
+        old += count.saturating_sub(*after_counts.get(description).unwrap_or(&0));
+    }
+    let mut new = 0;
+    for (description, count) in &after_counts {
+        new += count.saturating_sub(*before_counts.get(description).unwrap_or(&0));
+    }
+
+    (old.saturating_sub(new), new)
+}
+
+/// Escape `s` for use in a JSON string.
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+impl Stats {
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{:<16} {:>6} {:>8} {:>8} {:>10} {:>10}\n",
+            "pass", "runs", "changes", "removed", "rewritten", "time (ms)"
+        );
+        for pass in &self.passes {
+            table.push_str(&format!(
+                "{:<16} {:>6} {:>8} {:>8} {:>10} {:>10.3}\n",
+                pass.name,
+                pass.runs,
+                pass.changes,
+                pass.removed,
+                pass.rewritten,
+                pass.time.as_secs_f64() * 1000.0
+            ));
+        }
+        table.push_str(&format!("\nOptimisation iterations: {}\n", self.iterations));
+
+        if let Some(speculation) = &self.speculation {
+            table.push_str(&format!(
+                "Speculative execution: {} steps, executed {} of {} top-level instructions\n",
+                speculation.steps, speculation.executed_instrs, speculation.total_instrs
+            ));
+        }
+        table
+    }
+
+    pub fn to_json(&self) -> String {
+        let passes: Vec<_> = self
+            .passes
+            .iter()
+            .map(|pass| {
+                format!(
+                    "{{\"name\": {}, \"runs\": {}, \"changes\": {}, \"removed\": {}, \
+                     \"rewritten\": {}, \"time_ms\": {:.3}}}",
+                    json_string(pass.name),
+                    pass.runs,
+                    pass.changes,
+                    pass.removed,
+                    pass.rewritten,
+                    pass.time.as_secs_f64() * 1000.0
+                )
+            })
+            .collect();
+
+        let speculation = match &self.speculation {
+            Some(speculation) => format!(
+                "{{\"steps\": {}, \"executed_instrs\": {}, \"total_instrs\": {}}}",
+                speculation.steps, speculation.executed_instrs, speculation.total_instrs
+            ),
+            None => "null".to_owned(),
+        };
+
+        format!(
+            "{{\"passes\": [{}], \"iterations\": {}, \"speculation\": {}}}\n",
+            passes.join(", "),
+            self.iterations,
+            speculation
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+
+    #[test]
+    fn count_changes_combined() {
+        let before = parse("++>").unwrap();
+        let after = crate::peephole::combine_increments(before.clone());
+        assert_eq!(count_changes(&before, &after), (1, 1));
+    }
+
+    #[test]
+    fn count_changes_in_loop() {
+        let before = parse("[-]").unwrap();
+        let after = crate::peephole::zeroing_loops(before.clone());
+        assert_eq!(count_changes(&before, &after), (1, 1));
+    }
+
+    #[test]
+    fn count_changes_unchanged() {
+        let before = parse("+[>.]").unwrap();
+        assert_eq!(count_changes(&before, &before), (0, 0));
+    }
+
+    #[test]
+    fn executed_instrs_nested() {
+        let instrs = parse("+[,]+").unwrap();
+        let start_instr = match &instrs[1] {
+            Loop { body, .. } => &body[0],
+            _ => unreachable!(),
+        };
+        assert_eq!(executed_instrs(&instrs, Some(start_instr)), 1);
+        assert_eq!(executed_instrs(&instrs, None), 3);
+    }
+
+    #[test]
+    fn json_escapes_names() {
+        assert_eq!(json_string("a\"b\\"), "\"a\\\"b\\\\\"");
+    }
+
+    #[test]
+    fn stats_json() {
+        let stats = Stats {
+            passes: vec![PassStats::new("combine_inc")],
+            iterations: 2,
+            speculation: None,
+        };
+        assert_eq!(
+            stats.to_json(),
+            "{\"passes\": [{\"name\": \"combine_inc\", \"runs\": 0, \"changes\": 0, \
+             \"removed\": 0, \"rewritten\": 0, \"time_ms\": 0.000}], \"iterations\": 2, \
+             \"speculation\": null}\n"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Execution traces for `bfc run --trace`.
+//!
+//! A trace has one line for each instruction executed:
+//!
+//! ```text
+//! STEP POSITION POINTER CELL BEFORE AFTER
+//! ```
+//!
+//! POSITION is the instruction's offset (or range of offsets) in the
+//! source, or `-` if it has none. POINTER is the cell pointer before
+//! the instruction. CELL is the cell that the instruction reads or
+//! writes (the pointer plus the instruction's offset), and BEFORE and
+//! AFTER are that cell's values.
+
+use std::io;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Cell, Position};
+
+pub struct Tracer<W: io::Write> {
+    writer: W,
+    /// Only trace instructions that overlap this range of the source.
+    range: Option<Position>,
+    /// Only trace this many steps.
+    max_steps: Option<u64>,
+}
+
+/// The cell that `instr` reads or writes, when the pointer is at
+/// `cell_ptr`.
+pub fn traced_cell(instr: &AstNode, cell_ptr: isize) -> isize {
+    match *instr {
+        Increment { offset, .. }
+        | Set { offset, .. }
+        | Read { offset, .. }
+        | Write { offset, .. }
+        | Loop { offset, .. } => cell_ptr + offset,
+        _ => cell_ptr,
+    }
+}
+
+impl<W: io::Write> Tracer<W> {
+    pub fn new(mut writer: W, range: Option<Position>, max_steps: Option<u64>) -> io::Result<Self> {
+        writeln!(writer, "# step position pointer cell before after")?;
+        Ok(Tracer {
+            writer,
+            range,
+            max_steps,
+        })
+    }
+
+    /// Should we record executing `instr` as step number `step`?
+    pub fn should_trace(&self, step: u64, instr: &AstNode) -> bool {
+        if self.max_steps.is_some_and(|max_steps| step > max_steps) {
+            return false;
+        }
+        match (self.range, get_position(instr)) {
+            (Some(range), Some(position)) => {
+                position.start <= range.end && range.start <= position.end
+            }
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        step: u64,
+        instr: &AstNode,
+        cell_ptr: isize,
+        before: Cell,
+        after: Cell,
+    ) -> io::Result<()> {
+        let position = match get_position(instr) {
+            Some(position) => format!("{:?}", position),
+            None => "-".to_owned(),
+        };
+        writeln!(
+            self.writer,
+            "{} {} {} {} {} {}",
+            step,
+            position,
+            cell_ptr,
+            traced_cell(instr, cell_ptr),
+            before,
+            after
+        )
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use pretty_assertions::assert_eq;
+    use std::num::Wrapping;
+
+    #[test]
+    fn record_line() {
+        let instrs = parse(">+").unwrap();
+        let mut output = vec![];
+        let mut tracer = Tracer::new(&mut output, None, None).unwrap();
+        tracer
+            .record(2, &instrs[1], 1, Wrapping(0), Wrapping(1))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "# step position pointer cell before after\n2 1 1 1 0 1\n"
+        );
+    }
+
+    #[test]
+    fn trace_within_range() {
+        let instrs = parse("+[-]+").unwrap();
+        let tracer = Tracer::new(vec![], Some(Position { start: 2, end: 3 }), None).unwrap();
+
+        assert!(!tracer.should_trace(1, &instrs[0]));
+        assert!(tracer.should_trace(2, &instrs[1]));
+        assert!(!tracer.should_trace(3, &instrs[2]));
+    }
+
+    #[test]
+    fn trace_first_steps() {
+        let instrs = parse("+").unwrap();
+        let tracer = Tracer::new(vec![], None, Some(2)).unwrap();
+
+        assert!(tracer.should_trace(2, &instrs[0]));
+        assert!(!tracer.should_trace(3, &instrs[0]));
+    }
+}
@@ -0,0 +1,280 @@
+//! Translation validation for `--verify-opt`. We run the program
+//! before and after each pass on the same input, and check that the
+//! pass didn't change what the program does.
+
+use std::num::Wrapping;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Cell, Position};
+use crate::bounds::highest_cell_index;
+use crate::execution::{execute_with_input, ExecutionState, Input, Outcome};
+
+/// The maximum number of steps we execute each version of the
+/// program for.
+const MAX_STEPS: u64 = 10_000_000;
+
+/// The number of bytes of input we generate when the user doesn't
+/// give us an input file.
+pub const GENERATED_INPUT_LEN: usize = 1024;
+
+/// Passes that may change the final cell values, because they remove
+/// stores that are never read. We only compare outputs for these.
+const CELL_CHANGING_PASSES: &[&str] = &["read_clobber", "pure_removal", "dead_store"];
+
+/// The first point where a pass changed what the program does.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub pass_name: &'static str,
+    pub message: String,
+    /// The first instruction that the pass changed.
+    pub position: Option<Position>,
+}
+
+/// What happened when we executed one version of the program.
+#[derive(Debug, Clone)]
+struct Run {
+    completed: bool,
+    cells: Vec<Cell>,
+    outputs: Vec<i8>,
+}
+
+/// Checks each pass against the program it was given.
+pub struct Verifier {
+    input: Vec<u8>,
+    /// The number of cells in the unoptimised program. Passes may
+    /// remove instructions, so later programs can appear to use
+    /// fewer cells.
+    num_cells: usize,
+    /// The most recent program we executed, and what happened.
+    last_run: Option<(Vec<AstNode>, Run)>,
+}
+
+impl Verifier {
+    pub fn new(instrs: &[AstNode], input: Vec<u8>) -> Self {
+        Verifier {
+            input,
+            num_cells: highest_cell_index(instrs) + 1,
+            last_run: None,
+        }
+    }
+
+    fn run(&self, instrs: &[AstNode]) -> Run {
+        let mut state = ExecutionState::initial(instrs);
+        if state.cells.len() < self.num_cells {
+            state.cells.resize(self.num_cells, Wrapping(0));
+        }
+        let outcome = execute_with_input(
+            instrs,
+            &mut state,
+            MAX_STEPS,
+            &mut Input::bytes(&self.input),
+        );
+        Run {
+            completed: matches!(outcome, Outcome::Completed(_)),
+            cells: state.cells,
+            outputs: state.outputs,
+        }
+    }
+
+    /// Check that `after`, the result of running the pass
+    /// `pass_name` on `before`, does the same thing as `before`.
+    pub fn check(
+        &mut self,
+        pass_name: &'static str,
+        before: &[AstNode],
+        after: &[AstNode],
+    ) -> Option<Divergence> {
+        // The previous pass's output is usually this pass's input,
+        // so we rarely need to execute `before`.
+        let before_run = match self.last_run.take() {
+            Some((instrs, run)) if instrs == before => run,
+            _ => self.run(before),
+        };
+        let after_run = self.run(after);
+
+        let check_cells = !CELL_CHANGING_PASSES.contains(&pass_name);
+        let message = compare_runs(&before_run, &after_run, check_cells);
+        self.last_run = Some((after.to_vec(), after_run));
+
+        message.map(|message| Divergence {
+            pass_name,
+            message,
+            position: first_change(before, after),
+        })
+    }
+}
+
+/// Describe the first difference between the two runs, if any. If
+/// the original program didn't finish, we can only compare the
+/// output that both versions wrote.
+fn compare_runs(before: &Run, after: &Run, check_cells: bool) -> Option<String> {
+    if before.completed && !after.completed {
+        return Some("The original program finished but the optimised program did not.".to_owned());
+    }
+
+    let both_completed = before.completed && after.completed;
+    for (i, (old, new)) in before.outputs.iter().zip(&after.outputs).enumerate() {
+        if old != new {
+            return Some(format!(
+                "Output byte {} was {} but is now {}.",
+                i, *old as u8, *new as u8
+            ));
+        }
+    }
+    if both_completed && before.outputs.len() != after.outputs.len() {
+        return Some(format!(
+            "The program wrote {} bytes but now writes {}.",
+            before.outputs.len(),
+            after.outputs.len()
+        ));
+    }
+
+    if check_cells && both_completed {
+        let num_cells = before.cells.len().max(after.cells.len());
+        for i in 0..num_cells {
+            let old = before.cells.get(i).copied().unwrap_or(Wrapping(0));
+            let new = after.cells.get(i).copied().unwrap_or(Wrapping(0));
+            if old != new {
+                return Some(format!(
+                    "Cell {} ended as {} but now ends as {}.",
+                    i, old, new
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+/// The position of the first instruction that differs between
+/// `before` and `after`, looking inside loops that both share.
+fn first_change(before: &[AstNode], after: &[AstNode]) -> Option<Position> {
+    for (old, new) in before.iter().zip(after) {
+        if old == new {
+            continue;
+        }
+
+        return match (old, new) {
+            (
+                Loop {
+                    body: old_body,
+                    offset: old_offset,
+                    position: old_position,
+                },
+                Loop {
+                    body: new_body,
+                    offset: new_offset,
+                    position: new_position,
+                },
+            ) if old_offset == new_offset && old_position == new_position => {
+                first_change(old_body, new_body).or(*new_position)
+            }
+            (
+                If {
+                    body: old_body,
+                    position: old_position,
+                },
+                If {
+                    body: new_body,
+                    position: new_position,
+                },
+            ) if old_position == new_position => first_change(old_body, new_body).or(*new_position),
+            _ => get_position(new).or_else(|| get_position(old)),
+        };
+    }
+
+    // One program is a prefix of the other.
+    let rest = if before.len() > after.len() {
+        &before[after.len()..]
+    } else {
+        &after[before.len()..]
+    };
+    rest.first().and_then(get_position)
+}
+
+/// Arbitrary but reproducible input bytes, so `--verify-opt` can
+/// exercise programs that read input.
+pub fn generated_input(len: usize) -> Vec<u8> {
+    // A xorshift generator with a fixed seed.
+    let mut state: u32 = 2_463_534_242;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::peephole::combine_increments;
+
+    #[test]
+    fn sound_pass_has_no_divergence() {
+        let before = parse("++[>+<-]>.").unwrap();
+        let after = combine_increments(before.clone());
+
+        let mut verifier = Verifier::new(&before, vec![]);
+        assert_eq!(verifier.check("combine_inc", &before, &after), None);
+    }
+
+    #[test]
+    fn changed_output_is_divergence() {
+        let before = parse("+.").unwrap();
+        let after = parse("++.").unwrap();
+
+        let mut verifier = Verifier::new(&before, vec![]);
+        assert_eq!(
+            verifier.check("combine_inc", &before, &after),
+            Some(Divergence {
+                pass_name: "combine_inc",
+                message: "Output byte 0 was 1 but is now 2.".to_owned(),
+                position: Some(Position { start: 1, end: 1 }),
+            })
+        );
+    }
+
+    #[test]
+    fn divergence_uses_input() {
+        let before = parse(",.").unwrap();
+        let after = parse(",+.").unwrap();
+
+        let mut verifier = Verifier::new(&before, vec![5]);
+        let divergence = verifier.check("combine_inc", &before, &after).unwrap();
+        assert_eq!(divergence.message, "Output byte 0 was 5 but is now 6.");
+    }
+
+    #[test]
+    fn changed_cells_are_divergence() {
+        let before = parse("+>").unwrap();
+        let after = parse(">").unwrap();
+
+        let mut verifier = Verifier::new(&before, vec![]);
+        let divergence = verifier.check("combine_inc", &before, &after).unwrap();
+        assert_eq!(divergence.message, "Cell 0 ended as 1 but now ends as 0.");
+
+        // Dead store removal is allowed to change cells.
+        assert_eq!(verifier.check("dead_store", &before, &after), None);
+    }
+
+    #[test]
+    fn first_change_in_loop_body() {
+        let before = parse("+[-.]").unwrap();
+        let after = parse("+[+.]").unwrap();
+        assert_eq!(
+            first_change(&before, &after),
+            Some(Position { start: 2, end: 2 })
+        );
+    }
+
+    #[test]
+    fn generated_input_is_reproducible() {
+        let input = generated_input(GENERATED_INPUT_LEN);
+        assert_eq!(input.len(), GENERATED_INPUT_LEN);
+        assert_eq!(input, generated_input(GENERATED_INPUT_LEN));
+    }
+}